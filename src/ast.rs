@@ -0,0 +1,901 @@
+use crate::token::{Span, Token, TokenType};
+use crate::scanner::Scanner;
+use crate::precedence::Precedence;
+use crate::value::Value;
+use crate::compiler::{DiagnosticSink, MAX_ARGS};
+use crate::error::{CompileError, Diagnostic};
+
+// A syntax tree for the same language the single-pass compiler reads, for tools
+// that want to look at a whole program before (or instead of) running it. The
+// lower module turns one into bytecode. Every node's span covers all of its
+// source, from its first token to its last
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identifier<'a> {
+    pub name: &'a str,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr<'a> {
+    pub kind: ExprKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'a> {
+    Nil,
+    Bool(bool),
+    Number(Value),
+    // Without its quotation marks
+    String(&'a str),
+    Variable(Identifier<'a>),
+    Assign(Identifier<'a>, Box<Expr<'a>>),
+    This,
+    Grouping(Box<Expr<'a>>),
+    Unary(UnaryOp, Box<Expr<'a>>),
+    Binary(BinaryOp, Box<Expr<'a>>, Box<Expr<'a>>),
+    // Operators that may skip their right operand
+    Logical(LogicalOp, Box<Expr<'a>>, Box<Expr<'a>>),
+    // `a.b(c)` is a call of a Get, which the lowering turns into OP_INVOKE
+    Call(Box<Expr<'a>>, Vec<Expr<'a>>),
+    // `optional` for `a?.b`, which only ever appears inside an OptionalChain
+    Get { object: Box<Expr<'a>>, name: Identifier<'a>, optional: bool },
+    Set { object: Box<Expr<'a>>, name: Identifier<'a>, value: Box<Expr<'a>> },
+    Index(Box<Expr<'a>>, Box<Expr<'a>>),
+    IndexSet { object: Box<Expr<'a>>, index: Box<Expr<'a>>, value: Box<Expr<'a>> },
+    List(Vec<Expr<'a>>),
+    Lambda(Box<Function<'a>>),
+    Match(Box<Expr<'a>>, Vec<MatchArm<'a>>),
+    // Everything a nil receiver after `?.` skips: the access and the rest of
+    // the chain of calls, accesses and indexes after it
+    OptionalChain(Box<Expr<'a>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Range,
+    RangeInclusive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+    // `??`
+    Coalesce,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchArm<'a> {
+    // None for the `_` arm
+    pub pattern: Option<Expr<'a>>,
+    pub body: Expr<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'a> {
+    // "lambda" for anonymous functions
+    pub name: Identifier<'a>,
+    pub params: Vec<Identifier<'a>>,
+    // Whether the last parameter collects the rest of the arguments
+    pub variadic: bool,
+    pub body: Vec<Stmt<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Method<'a> {
+    pub function: Function<'a>,
+    // Written without a parameter list, and run when its property is read
+    pub getter: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt<'a> {
+    pub kind: StmtKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind<'a> {
+    Expression(Expr<'a>),
+    Print(Expr<'a>),
+    Return(Option<Expr<'a>>),
+    If(Expr<'a>, Box<Stmt<'a>>, Option<Box<Stmt<'a>>>),
+    While(Expr<'a>, Box<Stmt<'a>>),
+    For {
+        initializer: Option<Box<Stmt<'a>>>,
+        condition: Option<Expr<'a>>,
+        increment: Option<Expr<'a>>,
+        body: Box<Stmt<'a>>,
+    },
+    ForIn { name: Identifier<'a>, iterable: Expr<'a>, body: Box<Stmt<'a>> },
+    Continue,
+    Try { body: Vec<Stmt<'a>>, name: Identifier<'a>, handler: Vec<Stmt<'a>> },
+    Throw(Expr<'a>),
+    Block(Vec<Stmt<'a>>),
+    Var(Identifier<'a>, Option<Expr<'a>>),
+    // `var (a, b) = list;`
+    Destructure(Vec<Identifier<'a>>, Expr<'a>),
+    Const(Identifier<'a>, Expr<'a>),
+    Fun(Function<'a>),
+    Class(Identifier<'a>, Vec<Method<'a>>),
+    Import { path: &'a str, alias: Option<Identifier<'a>> },
+}
+
+// Parses the whole source, reporting syntax errors to `sink` as they're found
+// and recovering at statement boundaries like the single-pass compiler. Only
+// errors the grammar itself rules out are reported here; the rest (scoping,
+// constants, where `return` may appear) are left to the lowering
+pub fn parse<'a>(source: &'a str, sink: &mut dyn DiagnosticSink) -> Result<Vec<Stmt<'a>>, Vec<CompileError>> {
+    let mut p = Parser::new(source);
+
+    p.advance();
+    let mut program = Vec::new();
+    while !p.check(TokenType::EOF) {
+        program.push(p.declaration());
+    }
+    p.consume(TokenType::EOF, "Expect end of input.");
+
+    for diagnostic in p.diagnostics.drain(..) {
+        sink.report(diagnostic);
+    }
+
+    if p.errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(p.errors)
+    }
+}
+
+#[derive(Debug)]
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+
+    previous: Option<Token<'a>>,
+    current: Option<Token<'a>>,
+
+    panic_mode: bool,
+    errors: Vec<CompileError>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Parser {
+            scanner: Scanner::new(source),
+            previous: None,
+            current: None,
+            panic_mode: false,
+            errors: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn declaration(&mut self) -> Stmt<'a> {
+        let start = self.get_current().span;
+
+        let kind = if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            self.consume(TokenType::Identifier, "Expect function name.");
+            StmtKind::Fun(self.function(self.identifier(), false))
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration()
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration()
+        } else {
+            self.statement_kind()
+        };
+        let stmt = self.stmt(kind, start);
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        stmt
+    }
+
+    // The same boundaries as the single-pass compiler's synchronize
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while !self.check(TokenType::EOF) {
+            if self.previous.as_ref().is_some_and(|t| t.token_type == TokenType::Semicolon) {
+                return;
+            }
+            match self.get_current().token_type {
+                TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Const |
+                TokenType::Import | TokenType::For | TokenType::If | TokenType::While |
+                TokenType::Print | TokenType::Return | TokenType::Try | TokenType::Throw => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let name = self.identifier();
+
+        let mut methods = Vec::new();
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.consume(TokenType::Identifier, "Expect method name.");
+            let getter = self.check(TokenType::LeftBrace);
+            if getter && self.previous().literal == "init" {
+                self.error("An initializer can't be a getter.");
+            }
+            methods.push(Method { function: self.function(self.identifier(), getter), getter });
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+
+        StmtKind::Class(name, methods)
+    }
+
+    // Anything before the parameter list has already been consumed; getters
+    // have no parameter list
+    fn function(&mut self, name: Identifier<'a>, getter: bool) -> Function<'a> {
+        let (params, variadic) = if getter { (Vec::new(), false) } else { self.parameter_list() };
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        let body = self.block();
+
+        Function { name, params, variadic, body }
+    }
+
+    fn parameter_list(&mut self) -> (Vec<Identifier<'a>>, bool) {
+        let mut params = Vec::new();
+        let mut variadic = false;
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == MAX_ARGS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                variadic = self.match_token(TokenType::DotDotDot);
+                self.consume(TokenType::Identifier, "Expect parameter name.");
+                params.push(self.identifier());
+
+                if variadic && self.check(TokenType::Comma) {
+                    self.error_at_current("A rest parameter must come last.");
+                }
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+
+        (params, variadic)
+    }
+
+    fn var_declaration(&mut self) -> StmtKind<'a> {
+        if self.match_token(TokenType::LeftParen) {
+            return self.destructuring_declaration();
+        }
+
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let name = self.identifier();
+        let initializer = if self.match_token(TokenType::Equal) { Some(self.expression()) } else { None };
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        StmtKind::Var(name, initializer)
+    }
+
+    fn destructuring_declaration(&mut self) -> StmtKind<'a> {
+        let mut names = Vec::new();
+        loop {
+            if names.len() == MAX_ARGS {
+                self.error_at_current("Can't unpack more than 255 values.");
+            }
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            names.push(self.identifier());
+            if !self.match_token(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after variable names.");
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        StmtKind::Destructure(names, value)
+    }
+
+    fn const_declaration(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::Identifier, "Expect constant name.");
+        let name = self.identifier();
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+
+        StmtKind::Const(name, value)
+    }
+
+    fn import_declaration(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::String, "Expect module path.");
+        let literal = self.previous().literal;
+        let path = literal.get(1..literal.len().saturating_sub(1)).unwrap_or("");
+
+        // `as` is only special here, so it stays usable as a name elsewhere
+        let alias = if self.check(TokenType::Identifier) && self.get_current().literal == "as" {
+            self.advance();
+            self.consume(TokenType::Identifier, "Expect module name after 'as'.");
+            Some(self.identifier())
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+
+        StmtKind::Import { path, alias }
+    }
+
+    fn statement(&mut self) -> Stmt<'a> {
+        let start = self.get_current().span;
+        let kind = self.statement_kind();
+        self.stmt(kind, start)
+    }
+
+    fn statement_kind(&mut self) -> StmtKind<'a> {
+        if self.match_token(TokenType::Print) {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after value.");
+            StmtKind::Print(value)
+        } else if self.match_token(TokenType::Return) {
+            if self.match_token(TokenType::Semicolon) {
+                return StmtKind::Return(None);
+            }
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            StmtKind::Return(Some(value))
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::While) {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+            let condition = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after condition.");
+            StmtKind::While(condition, Box::new(self.statement()))
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::Continue) {
+            self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+            StmtKind::Continue
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_token(TokenType::Throw) {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+            StmtKind::Throw(value)
+        } else if self.match_token(TokenType::LeftBrace) {
+            StmtKind::Block(self.block())
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block(&mut self) -> Vec<Stmt<'a>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            statements.push(self.declaration());
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        statements
+    }
+
+    fn if_statement(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_token(TokenType::Else) { Some(Box::new(self.statement())) } else { None };
+        StmtKind::If(condition, then_branch, else_branch)
+    }
+
+    fn for_statement(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+
+        if self.check(TokenType::Identifier) && self.next_is_in() {
+            self.advance();
+            let name = self.identifier();
+            self.advance();
+
+            let iterable = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after loop collection.");
+            return StmtKind::ForIn { name, iterable, body: Box::new(self.statement()) };
+        }
+
+        let start = self.get_current().span;
+        let initializer = if self.match_token(TokenType::Semicolon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            let kind = self.var_declaration();
+            Some(Box::new(self.stmt(kind, start)))
+        } else {
+            let kind = self.expression_statement();
+            Some(Box::new(self.stmt(kind, start)))
+        };
+
+        let condition = if self.match_token(TokenType::Semicolon) {
+            None
+        } else {
+            let condition = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            Some(condition)
+        };
+
+        let increment = if self.match_token(TokenType::RightParen) {
+            None
+        } else {
+            let increment = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            Some(increment)
+        };
+
+        StmtKind::For { initializer, condition, increment, body: Box::new(self.statement()) }
+    }
+
+    // `in` is only a keyword here, so it has to be told apart from a for loop's
+    // initializer expression by looking past the loop variable
+    fn next_is_in(&self) -> bool {
+        self.scanner.clone()
+                    .scan_token()
+                    .is_ok_and(|t| t.token_type == TokenType::Identifier && t.literal == "in")
+    }
+
+    fn try_statement(&mut self) -> StmtKind<'a> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        let body = self.block();
+
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.consume(TokenType::Identifier, "Expect exception variable name.");
+        let name = self.identifier();
+        self.consume(TokenType::RightParen, "Expect ')' after exception variable.");
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+        let handler = self.block();
+
+        StmtKind::Try { body, name, handler }
+    }
+
+    fn expression_statement(&mut self) -> StmtKind<'a> {
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        StmtKind::Expression(value)
+    }
+
+    fn expression(&mut self) -> Expr<'a> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr<'a> {
+        self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
+
+        let Some(mut expr) = self.prefix(can_assign) else {
+            self.error("Expect expression.");
+            return self.expr(ExprKind::Nil, self.previous().span);
+        };
+
+        while precedence <= infix_precedence(self.get_current().token_type) {
+            self.advance();
+            expr = self.infix(expr, can_assign);
+        }
+
+        // Nothing consumed the '=', so what came before it can't be assigned to
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+        expr
+    }
+
+    // For the token just consumed, or None if no expression starts with it
+    fn prefix(&mut self, can_assign: bool) -> Option<Expr<'a>> {
+        let token = self.previous().clone();
+        let kind = match token.token_type {
+            TokenType::LeftParen => {
+                let inner = self.expression();
+                self.consume(TokenType::RightParen, "Expect ')' after expression.");
+                ExprKind::Grouping(Box::new(inner))
+            },
+            TokenType::LeftBracket => ExprKind::List(self.list()),
+            TokenType::Minus => ExprKind::Unary(UnaryOp::Negate, Box::new(self.parse_precedence(Precedence::Unary))),
+            TokenType::Bang => ExprKind::Unary(UnaryOp::Not, Box::new(self.parse_precedence(Precedence::Unary))),
+            TokenType::Identifier | TokenType::Underscore => {
+                let name = self.identifier();
+                if can_assign && self.match_token(TokenType::Equal) {
+                    ExprKind::Assign(name, Box::new(self.expression()))
+                } else {
+                    ExprKind::Variable(name)
+                }
+            },
+            TokenType::String => ExprKind::String(&token.literal[1..token.literal.len() - 1]),
+            TokenType::Number => match token.literal.parse() {
+                Ok(value) => ExprKind::Number(value),
+                Err(_) => {
+                    self.error("Invalid number literal.");
+                    ExprKind::Nil
+                },
+            },
+            TokenType::False => ExprKind::Bool(false),
+            TokenType::True => ExprKind::Bool(true),
+            TokenType::Nil => ExprKind::Nil,
+            TokenType::This => ExprKind::This,
+            TokenType::Fun => {
+                let name = Identifier { name: "lambda", span: token.span };
+                ExprKind::Lambda(Box::new(self.function(name, false)))
+            },
+            TokenType::Match => self.match_expression(),
+            _ => return None,
+        };
+        Some(self.expr(kind, token.span))
+    }
+
+    // For the operator just consumed, with `left` as its left operand
+    fn infix(&mut self, left: Expr<'a>, can_assign: bool) -> Expr<'a> {
+        let start = left.span;
+        let operator = self.previous().token_type;
+        let kind = match operator {
+            TokenType::LeftParen => ExprKind::Call(Box::new(left), self.argument_list()),
+            TokenType::LeftBracket => {
+                let index = self.expression();
+                self.consume(TokenType::RightBracket, "Expect ']' after index.");
+                if can_assign && self.match_token(TokenType::Equal) {
+                    ExprKind::IndexSet { object: Box::new(left), index: Box::new(index), value: Box::new(self.expression()) }
+                } else {
+                    ExprKind::Index(Box::new(left), Box::new(index))
+                }
+            },
+            TokenType::Dot => {
+                self.consume(TokenType::Identifier, "Expect property name after '.'.");
+                let name = self.identifier();
+                if can_assign && self.match_token(TokenType::Equal) {
+                    ExprKind::Set { object: Box::new(left), name, value: Box::new(self.expression()) }
+                } else {
+                    ExprKind::Get { object: Box::new(left), name, optional: false }
+                }
+            },
+            TokenType::QuestionDot => return self.optional_chain(left),
+            TokenType::And => self.logical(LogicalOp::And, left, Precedence::And),
+            TokenType::Or => self.logical(LogicalOp::Or, left, Precedence::Or),
+            TokenType::QuestionQuestion => self.logical(LogicalOp::Coalesce, left, Precedence::Coalesce + 1),
+            _ => {
+                // Exponentiation is right-associative, so its right operand takes in
+                // further operators of the same precedence
+                let precedence = infix_precedence(operator);
+                let right = if precedence == Precedence::Power {
+                    self.parse_precedence(precedence)
+                } else {
+                    self.parse_precedence(precedence + 1)
+                };
+                ExprKind::Binary(binary_op(operator), Box::new(left), Box::new(right))
+            },
+        };
+        self.expr(kind, start)
+    }
+
+    fn logical(&mut self, op: LogicalOp, left: Expr<'a>, precedence: Precedence) -> ExprKind<'a> {
+        ExprKind::Logical(op, Box::new(left), Box::new(self.parse_precedence(precedence)))
+    }
+
+    // The rest of the chain is parsed here, where none of it can be assigned to
+    fn optional_chain(&mut self, left: Expr<'a>) -> Expr<'a> {
+        let start = left.span;
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier();
+        let mut chain = self.expr(ExprKind::Get { object: Box::new(left), name, optional: true }, start);
+
+        while infix_precedence(self.get_current().token_type) >= Precedence::Call {
+            self.advance();
+            chain = self.infix(chain, false);
+        }
+        self.expr(ExprKind::OptionalChain(Box::new(chain)), start)
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr<'a>> {
+        let mut args = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                args.push(self.expression());
+                if args.len() == MAX_ARGS + 1 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        args
+    }
+
+    fn list(&mut self) -> Vec<Expr<'a>> {
+        let mut items = Vec::new();
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                items.push(self.expression());
+                if items.len() == MAX_ARGS + 1 {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        items
+    }
+
+    fn match_expression(&mut self) -> ExprKind<'a> {
+        let value = self.expression();
+        self.consume(TokenType::LeftBrace, "Expect '{' after match value.");
+
+        let mut arms: Vec<MatchArm> = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if arms.last().is_some_and(|arm| arm.pattern.is_none()) {
+                self.error_at_current("The '_' arm must come last.");
+            }
+
+            let pattern = if self.match_token(TokenType::Underscore) { None } else { Some(self.pattern()) };
+            self.consume(TokenType::EqualGreater, "Expect '=>' after match pattern.");
+            arms.push(MatchArm { pattern, body: self.expression() });
+
+            if !self.match_token(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        ExprKind::Match(Box::new(value), arms)
+    }
+
+    fn pattern(&mut self) -> Expr<'a> {
+        let start = self.get_current().span;
+        if self.match_token(TokenType::Minus) {
+            self.consume(TokenType::Number, "Expect a number after '-' in pattern.");
+            let number = self.prefix(false).unwrap_or_else(|| self.expr(ExprKind::Nil, start));
+            return self.expr(ExprKind::Unary(UnaryOp::Negate, Box::new(number)), start);
+        }
+
+        self.advance();
+        match self.previous().token_type {
+            TokenType::Number | TokenType::String | TokenType::True | TokenType::False | TokenType::Nil => {
+                self.prefix(false).expect("Expected a literal")
+            },
+            _ => {
+                self.error("Expect a literal pattern or '_'.");
+                self.expr(ExprKind::Nil, start)
+            },
+        }
+    }
+
+    fn identifier(&self) -> Identifier<'a> {
+        let token = self.previous();
+        Identifier { name: token.literal, span: token.span }
+    }
+
+    fn expr(&self, kind: ExprKind<'a>, start: Span) -> Expr<'a> {
+        Expr { kind, span: start.to(self.previous().span) }
+    }
+
+    fn stmt(&self, kind: StmtKind<'a>, start: Span) -> Stmt<'a> {
+        Stmt { kind, span: start.to(self.previous().span) }
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.as_ref().is_some_and(|t| t.token_type == token_type)
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) { return false; }
+        self.advance();
+        true
+    }
+
+    fn previous(&self) -> &Token<'a> {
+        self.previous.as_ref().expect("Expected previous token")
+    }
+
+    fn get_current(&self) -> &Token<'a> {
+        self.current.as_ref().expect("Expected current token")
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            match self.scanner.scan_token() {
+                Ok(token) =>  {
+                    self.current = Some(token);
+                    break;
+                },
+                Err(e) => {
+                    let span = self.scanner.span();
+                    self.report(span, String::new(), &e.to_string());
+                }
+            }
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = self.get_current().clone();
+        self.error_at(&token, message)
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = self.previous().clone();
+        self.error_at(&token, message)
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        let location = if token.token_type == TokenType::EOF {
+            " at end".to_string()
+        } else {
+            format!(" at '{}'", token.literal)
+        };
+
+        self.report(token.span, location, message);
+    }
+
+    fn report(&mut self, span: Span, location: String, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+
+        let error = CompileError { span, location, message: message.to_string() };
+        self.diagnostics.push(Diagnostic::from(&error));
+        self.errors.push(error);
+    }
+}
+
+// How tightly each operator binds, as in the single-pass compiler's rule table
+fn infix_precedence(token_type: TokenType) -> Precedence {
+    match token_type {
+        TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot | TokenType::QuestionDot => Precedence::Call,
+        TokenType::DotDot | TokenType::DotDotEqual => Precedence::Range,
+        TokenType::QuestionQuestion => Precedence::Coalesce,
+        TokenType::Minus | TokenType::Plus => Precedence::Term,
+        TokenType::Slash | TokenType::Star | TokenType::Percent => Precedence::Factor,
+        TokenType::StarStar | TokenType::Caret => Precedence::Power,
+        TokenType::BangEqual | TokenType::EqualEqual => Precedence::Equality,
+        TokenType::Greater | TokenType::Less | TokenType::GreaterEqual | TokenType::LessEqual => Precedence::Comparison,
+        TokenType::And => Precedence::And,
+        TokenType::Or => Precedence::Or,
+        _ => Precedence::None,
+    }
+}
+
+fn binary_op(token_type: TokenType) -> BinaryOp {
+    match token_type {
+        TokenType::Plus => BinaryOp::Add,
+        TokenType::Minus => BinaryOp::Subtract,
+        TokenType::Star => BinaryOp::Multiply,
+        TokenType::Slash => BinaryOp::Divide,
+        TokenType::Percent => BinaryOp::Modulo,
+        TokenType::StarStar | TokenType::Caret => BinaryOp::Power,
+        TokenType::EqualEqual => BinaryOp::Equal,
+        TokenType::BangEqual => BinaryOp::NotEqual,
+        TokenType::Greater => BinaryOp::Greater,
+        TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+        TokenType::Less => BinaryOp::Less,
+        TokenType::LessEqual => BinaryOp::LessEqual,
+        TokenType::DotDot => BinaryOp::Range,
+        TokenType::DotDotEqual => BinaryOp::RangeInclusive,
+        _ => unreachable!("{:?} is not a binary operator", token_type),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_ok(source: &str) -> Vec<Stmt<'_>> {
+        parse(source, &mut Vec::new()).unwrap()
+    }
+
+    fn expression(source: &str) -> Expr<'_> {
+        match parse_ok(source).remove(0).kind {
+            StmtKind::Expression(expr) => expr,
+            kind => panic!("Expected an expression statement, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expr = expression("1 + 2 * 3;");
+        assert_eq!(expr.span, Span { start: 0, end: 9, line: 1, column: 1 });
+        match expr.kind {
+            ExprKind::Binary(BinaryOp::Add, left, right) => {
+                assert_eq!(left.kind, ExprKind::Number(Value::Int(1)));
+                assert!(matches!(right.kind, ExprKind::Binary(BinaryOp::Multiply, _, _)));
+                assert_eq!(right.span, Span { start: 4, end: 9, line: 1, column: 5 });
+            },
+            kind => panic!("Expected a binary expression, got {:?}", kind),
+        }
+
+        // Assignment is right associative
+        match expression("a = b = 1;").kind {
+            ExprKind::Assign(a, value) => {
+                assert_eq!(a.name, "a");
+                assert!(matches!(value.kind, ExprKind::Assign(Identifier { name: "b", .. }, _)));
+            },
+            kind => panic!("Expected an assignment, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_parse_optional_chain() {
+        // The chain wraps everything after the `?.`, including the call
+        match expression("a?.b.c();").kind {
+            ExprKind::OptionalChain(chain) => match chain.kind {
+                ExprKind::Call(callee, args) => {
+                    assert!(args.is_empty());
+                    match callee.kind {
+                        ExprKind::Get { object, name, optional: false } => {
+                            assert_eq!(name.name, "c");
+                            assert!(matches!(object.kind, ExprKind::Get { optional: true, .. }));
+                        },
+                        kind => panic!("Expected a property access, got {:?}", kind),
+                    }
+                },
+                kind => panic!("Expected a call, got {:?}", kind),
+            },
+            kind => panic!("Expected an optional chain, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let program = parse_ok("var x = match (1) { 1 => \"one\", _ => \"other\" };\nclass A { size { return 1; } }");
+        assert_eq!(program.len(), 2);
+        match &program[0].kind {
+            StmtKind::Var(name, Some(Expr { kind: ExprKind::Match(_, arms), .. })) => {
+                assert_eq!(name.name, "x");
+                assert_eq!(arms.len(), 2);
+                assert_eq!(arms[0].body.kind, ExprKind::String("one"));
+                assert_eq!(arms[1].pattern, None);
+            },
+            kind => panic!("Expected a variable declaration, got {:?}", kind),
+        }
+        match &program[1].kind {
+            StmtKind::Class(name, methods) => {
+                assert_eq!(name.name, "A");
+                assert!(methods[0].getter);
+                assert_eq!(methods[0].function.name.name, "size");
+            },
+            kind => panic!("Expected a class, got {:?}", kind),
+        }
+        assert_eq!(program[1].span.line, 2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        let errors = parse("var = 1;\nprint (1;\nprint 2;", &mut diagnostics).unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["Expect variable name.", "Expect ')' after expression."]);
+        assert_eq!(errors[1].span.line, 2);
+        assert_eq!(diagnostics.len(), 2);
+
+        // Scoping errors are left for the lowering
+        assert!(parse("return 1;", &mut Vec::new()).is_ok());
+    }
+}
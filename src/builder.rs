@@ -0,0 +1,225 @@
+use crate::value::Value;
+use crate::chunk::{Chunk, OpCode};
+use crate::error::BuildError;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Each builder gets its own id so a label handed to the wrong one is caught
+static NEXT_BUILDER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label {
+    builder: usize,
+    index: usize,
+}
+
+#[derive(Debug)]
+pub struct ChunkBuilder {
+    id: usize,
+    chunk: Chunk,
+    line: u32,
+    labels: Vec<Option<usize>>,
+    patches: Vec<(usize, Label)>,
+    error: Option<BuildError>,
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        ChunkBuilder {
+            id: NEXT_BUILDER.fetch_add(1, Ordering::Relaxed),
+            chunk: Chunk::default(),
+            line: 1,
+            labels: Vec::new(),
+            patches: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+impl ChunkBuilder {
+    pub fn line(&mut self, line: u32) -> &mut Self {
+        self.line = line;
+        self
+    }
+
+    pub fn op(&mut self, op: OpCode) -> &mut Self {
+        self.chunk.write(op, self.line);
+        self
+    }
+
+    pub fn constant(&mut self, value: Value) -> &mut Self {
+        if self.chunk.write_constant(value, self.line).is_none() {
+            self.fail(BuildError::TooManyConstants);
+        }
+        self
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label { builder: self.id, index: self.labels.len() - 1 }
+    }
+
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        match self.target(label) {
+            Ok(Some(_)) => self.fail(BuildError::LabelRebound),
+            Ok(None) => self.labels[label.index] = Some(self.chunk.code.len()),
+            Err(e) => self.fail(e),
+        }
+        self
+    }
+
+    // Jumping to a label that has already been bound goes backwards, so it's emitted as a loop
+    pub fn jump(&mut self, label: Label) -> &mut Self {
+        match self.target(label) {
+            Ok(Some(target)) => {
+                self.op(OpCode::Loop);
+                let distance = self.chunk.code.len() + 2 - target;
+                self.write_short(distance);
+            },
+            Ok(None) => self.forward_jump(OpCode::Jump, label),
+            Err(e) => self.fail(e),
+        }
+        self
+    }
+
+    pub fn jump_if_false(&mut self, label: Label) -> &mut Self {
+        match self.target(label) {
+            Ok(Some(_)) => self.fail(BuildError::BackwardConditionalJump),
+            Ok(None) => self.forward_jump(OpCode::JumpIfFalse, label),
+            Err(e) => self.fail(e),
+        }
+        self
+    }
+
+    pub fn build(&mut self) -> Result<Chunk, BuildError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        for &(operand, label) in &self.patches {
+            let target = self.target(label)?.ok_or(BuildError::UnboundLabel)?;
+            let distance = u16::try_from(target - operand - 2).map_err(|_| BuildError::JumpTooLarge)?;
+            self.chunk.code[operand..operand + 2].copy_from_slice(&distance.to_be_bytes());
+        }
+        self.patches.clear();
+
+        let chunk = std::mem::take(&mut self.chunk);
+        chunk.verify()?;
+        Ok(chunk)
+    }
+
+    // Where the label is bound, if it is yet. Labels only mean anything to the
+    // builder that made them
+    fn target(&self, label: Label) -> Result<Option<usize>, BuildError> {
+        match self.labels.get(label.index) {
+            Some(&target) if label.builder == self.id => Ok(target),
+            _ => Err(BuildError::ForeignLabel),
+        }
+    }
+
+    fn forward_jump(&mut self, op: OpCode, label: Label) {
+        self.op(op);
+        self.patches.push((self.chunk.code.len(), label));
+        self.write_short(0);
+    }
+
+    fn write_short(&mut self, value: usize) {
+        match u16::try_from(value) {
+            Ok(v) => {
+                for b in v.to_be_bytes() {
+                    self.chunk.write(b, self.line);
+                }
+            },
+            Err(_) => self.fail(BuildError::JumpTooLarge),
+        }
+    }
+
+    fn fail(&mut self, error: BuildError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::VM;
+    use crate::error::ChunkError;
+
+    #[test]
+    fn test_forward_jump() {
+        let mut b = ChunkBuilder::default();
+        let end = b.label();
+        b.op(OpCode::False)
+         .jump_if_false(end)
+         .op(OpCode::Not)
+         .bind(end)
+         .op(OpCode::Return);
+
+        let chunk = b.build().unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::False.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x01,
+            OpCode::Not.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(VM::default().instruct(chunk).is_ok());
+    }
+
+    #[test]
+    fn test_backward_jump() {
+        let mut b = ChunkBuilder::default();
+        let top = b.label();
+        let end = b.label();
+        b.bind(top)
+         .op(OpCode::True)
+         .jump_if_false(end)
+         .jump(top)
+         .bind(end)
+         .op(OpCode::Return);
+
+        // The loop re-enters `top` with the condition still on the stack
+        assert!(matches!(b.build(), Err(BuildError::InvalidChunk(ChunkError::StackMismatchError(0)))));
+
+        let mut b = ChunkBuilder::default();
+        let top = b.label();
+        b.bind(top).jump(top);
+        assert_eq!(b.build().unwrap().code, vec![OpCode::Loop.into(), 0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut b = ChunkBuilder::default();
+        let l = b.label();
+        b.op(OpCode::Nil).jump(l).op(OpCode::Return);
+        assert!(matches!(b.build(), Err(BuildError::UnboundLabel)));
+
+        let mut b = ChunkBuilder::default();
+        let l = b.label();
+        b.bind(l).op(OpCode::Nil).jump_if_false(l);
+        assert!(matches!(b.build(), Err(BuildError::BackwardConditionalJump)));
+
+        let mut other = ChunkBuilder::default();
+        let foreign = other.label();
+        let mut b = ChunkBuilder::default();
+        b.op(OpCode::Nil).jump(foreign).op(OpCode::Return);
+        assert!(matches!(b.build(), Err(BuildError::ForeignLabel)));
+
+        let mut b = ChunkBuilder::default();
+        b.bind(foreign).op(OpCode::Return);
+        assert!(matches!(b.build(), Err(BuildError::ForeignLabel)));
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut b = ChunkBuilder::default();
+        for i in 0..=256 {
+            b.constant(Value::Number(i as f64)).op(OpCode::Pop);
+        }
+        b.op(OpCode::Nil).op(OpCode::Return);
+
+        let chunk = b.build().unwrap();
+        let tail = &chunk.code[chunk.code.len() - 7..];
+        assert_eq!(tail, [OpCode::ConstantLong.into(), 0x00, 0x01, 0x00, OpCode::Pop.into(), OpCode::Nil.into(), OpCode::Return.into()]);
+        assert!(VM::default().instruct(chunk).is_ok());
+    }
+}
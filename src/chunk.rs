@@ -1,6 +1,13 @@
-use crate::value::Value;
-use crate::error::ChunkError;
+use crate::value::{Value, ObjectType, ObjHandle, Function};
+use crate::heap::ObjHeap;
+use crate::error::{ChunkError, DecodeError};
+use crate::token::Span;
+use crate::codegen::MAX_LONG_CONSTANTS;
 
+use std::ops::Range;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     Constant,
     ConstantLong,
@@ -11,58 +18,251 @@ pub enum OpCode {
     Greater,
     Less,
     Add,
+    AddConstant,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Power,
     Not,
     Negate,
+    ConcatN,
+    Jump,
+    JumpIfFalse,
+    JumpIfNotNil,
+    JumpIfNil,
+    CompareJump,
+    Loop,
+    Print,
+    Pop,
+    Dup,
+    Swap,
+    DefineGlobal,
+    DefineGlobalLong,
+    GetGlobal,
+    GetGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetLocal,
+    GetLocalLong,
+    GetLocal0,
+    SetLocal,
+    SetLocalLong,
+    Call,
+    TailCall,
+    Class,
+    ClassLong,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    BuildList,
+    Unpack,
+    IndexGet,
+    IndexSet,
+    Range,
+    RangeInclusive,
+    IterNew,
+    IterNext,
+    Method,
+    MethodLong,
+    Getter,
+    GetterLong,
+    Invoke,
+    Import,
+    ImportLong,
+    ImportAll,
+    TryBegin,
+    TryEnd,
+    Throw,
     Return,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    Constant,
+    ConstantLong,
+    Jump,
+    // A number of values the instruction pops on top of its fixed pops
+    Count,
+    // A number of values the instruction pushes on top of its fixed pushes
+    Spread,
+    // A stack slot, counted from the bottom of the stack
+    Slot,
+    // A two byte stack slot, high byte first, for functions with more than 256 locals
+    SlotLong,
+    // A name constant followed by an argument count, which is popped like Count
+    Invoke,
+    // A comparison opcode followed by a jump offset
+    CompareJump,
+}
+
+impl Operand {
+    pub fn width(self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Constant => 1,
+            Operand::ConstantLong => 3,
+            Operand::Jump => 2,
+            Operand::Count => 1,
+            Operand::Spread => 1,
+            Operand::Slot => 1,
+            Operand::SlotLong => 2,
+            Operand::Invoke => 2,
+            Operand::CompareJump => 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpInfo {
+    pub op: OpCode,
+    pub name: &'static str,
+    pub operand: Operand,
+    pub pops: usize,
+    pub pushes: usize,
+}
+
+const fn op_info(op: OpCode, name: &'static str, operand: Operand, pops: usize, pushes: usize) -> OpInfo {
+    OpInfo { op, name, operand, pops, pushes }
+}
+
+// Indexed by the encoded byte of each opcode, so entries must stay in declaration order
+pub const OP_TABLE: [OpInfo; 67] = [
+    op_info(OpCode::Constant, "OP_CONSTANT", Operand::Constant, 0, 1),
+    op_info(OpCode::ConstantLong, "OP_CONSTANT_LONG", Operand::ConstantLong, 0, 1),
+    op_info(OpCode::Nil, "OP_NIL", Operand::None, 0, 1),
+    op_info(OpCode::True, "OP_TRUE", Operand::None, 0, 1),
+    op_info(OpCode::False, "OP_FALSE", Operand::None, 0, 1),
+    op_info(OpCode::Equal, "OP_EQUAL", Operand::None, 2, 1),
+    op_info(OpCode::Greater, "OP_GREATER", Operand::None, 2, 1),
+    op_info(OpCode::Less, "OP_LESS", Operand::None, 2, 1),
+    op_info(OpCode::Add, "OP_ADD", Operand::None, 2, 1),
+    // OP_CONSTANT followed by OP_ADD
+    op_info(OpCode::AddConstant, "OP_ADD_CONSTANT", Operand::Constant, 1, 1),
+    op_info(OpCode::Subtract, "OP_SUBTRACT", Operand::None, 2, 1),
+    op_info(OpCode::Multiply, "OP_MULTIPLY", Operand::None, 2, 1),
+    op_info(OpCode::Divide, "OP_DIVIDE", Operand::None, 2, 1),
+    op_info(OpCode::Modulo, "OP_MODULO", Operand::None, 2, 1),
+    op_info(OpCode::Power, "OP_POWER", Operand::None, 2, 1),
+    op_info(OpCode::Not, "OP_NOT", Operand::None, 1, 1),
+    op_info(OpCode::Negate, "OP_NEGATE", Operand::None, 1, 1),
+    op_info(OpCode::ConcatN, "OP_CONCAT_N", Operand::Count, 0, 1),
+    op_info(OpCode::Jump, "OP_JUMP", Operand::Jump, 0, 0),
+    op_info(OpCode::JumpIfFalse, "OP_JUMP_IF_FALSE", Operand::Jump, 1, 1),
+    op_info(OpCode::JumpIfNotNil, "OP_JUMP_IF_NOT_NIL", Operand::Jump, 1, 1),
+    op_info(OpCode::JumpIfNil, "OP_JUMP_IF_NIL", Operand::Jump, 1, 1),
+    // OP_EQUAL, OP_GREATER or OP_LESS followed by OP_JUMP_IF_FALSE, which leaves the result
+    op_info(OpCode::CompareJump, "OP_COMPARE_JUMP", Operand::CompareJump, 2, 1),
+    op_info(OpCode::Loop, "OP_LOOP", Operand::Jump, 0, 0),
+    op_info(OpCode::Print, "OP_PRINT", Operand::None, 1, 0),
+    op_info(OpCode::Pop, "OP_POP", Operand::None, 1, 0),
+    op_info(OpCode::Dup, "OP_DUP", Operand::None, 1, 2),
+    op_info(OpCode::Swap, "OP_SWAP", Operand::None, 2, 2),
+    op_info(OpCode::DefineGlobal, "OP_DEFINE_GLOBAL", Operand::Constant, 1, 0),
+    op_info(OpCode::DefineGlobalLong, "OP_DEFINE_GLOBAL_LONG", Operand::ConstantLong, 1, 0),
+    op_info(OpCode::GetGlobal, "OP_GET_GLOBAL", Operand::Constant, 0, 1),
+    op_info(OpCode::GetGlobalLong, "OP_GET_GLOBAL_LONG", Operand::ConstantLong, 0, 1),
+    op_info(OpCode::SetGlobal, "OP_SET_GLOBAL", Operand::Constant, 1, 1),
+    op_info(OpCode::SetGlobalLong, "OP_SET_GLOBAL_LONG", Operand::ConstantLong, 1, 1),
+    op_info(OpCode::GetLocal, "OP_GET_LOCAL", Operand::Slot, 0, 1),
+    op_info(OpCode::GetLocalLong, "OP_GET_LOCAL_LONG", Operand::SlotLong, 0, 1),
+    // The callee, or the receiver in a method
+    op_info(OpCode::GetLocal0, "OP_GET_LOCAL_0", Operand::None, 0, 1),
+    op_info(OpCode::SetLocal, "OP_SET_LOCAL", Operand::Slot, 1, 1),
+    op_info(OpCode::SetLocalLong, "OP_SET_LOCAL_LONG", Operand::SlotLong, 1, 1),
+    // Pops the arguments as well as the callee
+    op_info(OpCode::Call, "OP_CALL", Operand::Count, 1, 1),
+    // A call in return position, made in place of the current frame
+    op_info(OpCode::TailCall, "OP_TAIL_CALL", Operand::Count, 1, 1),
+    op_info(OpCode::Class, "OP_CLASS", Operand::Constant, 0, 1),
+    op_info(OpCode::ClassLong, "OP_CLASS_LONG", Operand::ConstantLong, 0, 1),
+    op_info(OpCode::GetProperty, "OP_GET_PROPERTY", Operand::Constant, 1, 1),
+    op_info(OpCode::GetPropertyLong, "OP_GET_PROPERTY_LONG", Operand::ConstantLong, 1, 1),
+    op_info(OpCode::SetProperty, "OP_SET_PROPERTY", Operand::Constant, 2, 1),
+    op_info(OpCode::SetPropertyLong, "OP_SET_PROPERTY_LONG", Operand::ConstantLong, 2, 1),
+    // Collects the top `count` values into a new list
+    op_info(OpCode::BuildList, "OP_BUILD_LIST", Operand::Count, 0, 1),
+    // Replaces a list with its `count` items, first item lowest
+    op_info(OpCode::Unpack, "OP_UNPACK", Operand::Spread, 1, 0),
+    op_info(OpCode::IndexGet, "OP_INDEX_GET", Operand::None, 2, 1),
+    op_info(OpCode::IndexSet, "OP_INDEX_SET", Operand::None, 3, 1),
+    op_info(OpCode::Range, "OP_RANGE", Operand::None, 2, 1),
+    op_info(OpCode::RangeInclusive, "OP_RANGE_INCLUSIVE", Operand::None, 2, 1),
+    // Leaves the collection with a cursor above it, for OP_ITER_NEXT to advance
+    op_info(OpCode::IterNew, "OP_ITER_NEW", Operand::None, 1, 2),
+    // Pushes the next item, or jumps without pushing once the collection is exhausted
+    op_info(OpCode::IterNext, "OP_ITER_NEXT", Operand::Jump, 0, 1),
+    // Pops the method, leaving the class it was added to
+    op_info(OpCode::Method, "OP_METHOD", Operand::Constant, 2, 1),
+    op_info(OpCode::MethodLong, "OP_METHOD_LONG", Operand::ConstantLong, 2, 1),
+    // Like OP_METHOD, but the function runs whenever the property is read
+    op_info(OpCode::Getter, "OP_GETTER", Operand::Constant, 2, 1),
+    op_info(OpCode::GetterLong, "OP_GETTER_LONG", Operand::ConstantLong, 2, 1),
+    // A method call on the receiver below the arguments, without a bound method in between
+    op_info(OpCode::Invoke, "OP_INVOKE", Operand::Invoke, 1, 1),
+    // Pushes the module, then the result of running it: a call the first time
+    // it's imported, and nil once it's cached
+    op_info(OpCode::Import, "OP_IMPORT", Operand::Constant, 0, 2),
+    op_info(OpCode::ImportLong, "OP_IMPORT_LONG", Operand::ConstantLong, 0, 2),
+    // Copies every global of the module on the stack into the current globals
+    op_info(OpCode::ImportAll, "OP_IMPORT_ALL", Operand::None, 1, 0),
+    // Registers a handler at the jump target, which starts with the caught value pushed
+    op_info(OpCode::TryBegin, "OP_TRY_BEGIN", Operand::Jump, 0, 0),
+    op_info(OpCode::TryEnd, "OP_TRY_END", Operand::None, 0, 0),
+    op_info(OpCode::Throw, "OP_THROW", Operand::None, 1, 0),
+    op_info(OpCode::Return, "OP_RETURN", Operand::None, 1, 0),
+];
+
+impl OpCode {
+    pub fn info(self) -> &'static OpInfo {
+        &OP_TABLE[self as usize]
+    }
+
+    // The comparisons OP_COMPARE_JUMP can carry
+    pub fn is_comparison(self) -> bool {
+        matches!(self, OpCode::Equal | OpCode::Greater | OpCode::Less)
+    }
+
+    // The form of an instruction taking a constant that takes a three byte index
+    // instead, for chunks with more than 256 constants
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::Getter => Some(OpCode::GetterLong),
+            OpCode::Import => Some(OpCode::ImportLong),
+            OpCode::GetLocal => Some(OpCode::GetLocalLong),
+            OpCode::SetLocal => Some(OpCode::SetLocalLong),
+            _ => None,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(self.info().operand, Operand::ConstantLong | Operand::SlotLong)
+    }
+}
+
 impl TryFrom<u8> for OpCode {
     type Error = ChunkError;
 
     fn try_from(value: u8) -> Result<OpCode, ChunkError> {
-        match value {
-            0x00 => Ok(OpCode::Constant),
-            0x01 => Ok(OpCode::ConstantLong),
-            0x02 => Ok(OpCode::Nil),
-            0x03 => Ok(OpCode::True),
-            0x04 => Ok(OpCode::False),
-            0x05 => Ok(OpCode::Equal),
-            0x06 => Ok(OpCode::Greater),
-            0x07 => Ok(OpCode::Less),
-            0x08 => Ok(OpCode::Add),
-            0x09 => Ok(OpCode::Subtract),
-            0x0A => Ok(OpCode::Multiply),
-            0x0B => Ok(OpCode::Divide),
-            0x0C => Ok(OpCode::Not),
-            0x0D => Ok(OpCode::Negate),
-            0x0E => Ok(OpCode::Return),
-            _ => Err(ChunkError::BadOPCodeError(value)),
-        }
+        OP_TABLE.get(value as usize)
+                .map(|info| info.op)
+                .ok_or(ChunkError::BadOPCodeError(value))
     }
 }
 
 impl From<OpCode> for u8 {
     fn from(op: OpCode) -> u8 {
-        match op {
-            OpCode::Constant => 0x00,
-            OpCode::ConstantLong => 0x01,
-            OpCode::Nil => 0x02,
-            OpCode::True => 0x03,
-            OpCode::False => 0x04,
-            OpCode::Equal => 0x05,
-            OpCode::Greater => 0x06,
-            OpCode::Less => 0x07,
-            OpCode::Add => 0x08,
-            OpCode::Subtract => 0x09,
-            OpCode::Multiply => 0x0A,
-            OpCode::Divide => 0x0B,
-            OpCode::Not => 0x0C,
-            OpCode::Negate => 0x0D,
-            OpCode::Return => 0x0E,
-        }
+        op as u8
     }
 }
 
@@ -70,65 +270,265 @@ impl From<OpCode> for u8 {
 pub struct Chunk {
     pub code: Vec<u8>,
     constants: Vec<Value>,
-    lines: Vec<(u32, u32)>,
+    // Where each constant added so far lives, so repeats can share an index
+    constant_indices: HashMap<ConstantKey, usize>,
+    // Runs of (line, exclusive end offset), kept sorted so lookups can binary search
+    lines: Vec<(u32, usize)>,
+    // Runs of (source span, exclusive end offset) in the same form. Only code
+    // written with write_spanned has any
+    spans: Vec<(Span, usize)>,
+    // Path of the file this was compiled from, when known, for runtime errors
+    pub source: Option<String>,
+    // Per instruction offset, the table index a name lookup there last found its
+    // name at. Seeded by the compiler for globals it saw defined, filled in by
+    // the VM as it runs, and never serialized
+    caches: Vec<Option<usize>>,
 }
 
 impl Chunk {
     pub fn read(&self, ip: usize) -> Result<u8, ChunkError> {
-        self.code.get(ip).ok_or(ChunkError::IPOutOfBoundsError).map(|&op| op)
+        self.code.get(ip).ok_or(ChunkError::IPOutOfBoundsError).copied()
     }
 
     pub fn read_op(&self, ip: usize) -> Result<OpCode, ChunkError> {
         self.read(ip)?.try_into()
     }
 
-    pub fn read_constant(&self, ip: usize) -> Result<&Value, ChunkError> {
-        self.constants.get(ip).ok_or(ChunkError::IPOutOfBoundsError)
+    pub fn cached(&self, offset: usize) -> Option<usize> {
+        self.caches.get(offset).copied().flatten()
+    }
+
+    pub fn cache(&mut self, offset: usize, index: usize) {
+        if self.caches.len() < self.code.len() {
+            self.caches.resize(self.code.len(), None);
+        }
+        if let Some(slot) = self.caches.get_mut(offset) {
+            *slot = Some(index);
+        }
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn constant_ref(&self, idx: usize) -> Result<&Value, ChunkError> {
+        self.constants.get(idx).ok_or(ChunkError::IPOutOfBoundsError)
+    }
+
+    pub fn read_short(&self, ip: usize) -> Result<u16, ChunkError> {
+        Ok(u16::from_be_bytes([self.read(ip)?, self.read(ip + 1)?]))
+    }
+
+    // The 24-bit operand of OP_CONSTANT_LONG, high byte first
+    pub fn read_long(&self, ip: usize) -> Result<usize, ChunkError> {
+        Ok(u32::from_be_bytes([0, self.read(ip)?, self.read(ip + 1)?, self.read(ip + 2)?]) as usize)
     }
 
     pub fn write<U: Into<u8>>(&mut self, op: U, line: u32) {
         self.code.push(op.into());
 
-        match self.lines.pop() {
-            Some((top_line, count)) => {
-                if line == top_line {
-                    self.lines.push((line, count + 1));
-                } else {
-                    self.lines.push((top_line, count));
-                    self.lines.push((line, 1));
-                }
-            },
-            None => self.lines.push((line, 1)),
+        match self.lines.last_mut() {
+            Some((top_line, end)) if *top_line == line => *end += 1,
+            _ => self.lines.push((line, self.code.len())),
         }
     }
 
-    pub fn get_line(&self, idx: usize) -> Option<u32>{
-        let mut offset: i32 = idx as i32;
-        for &(line, count) in &self.lines {
-            offset -= count as i32;
-            if offset < 0 {
-                return Some(line)
-            }
+    // Like write, but also records exactly which source the byte came from
+    pub fn write_spanned<U: Into<u8>>(&mut self, op: U, span: Span) {
+        self.write(op, span.line);
+
+        match self.spans.last_mut() {
+            Some((top_span, end)) if *top_span == span => *end += 1,
+            _ => self.spans.push((span, self.code.len())),
+        }
+    }
+
+    pub fn get_span(&self, idx: usize) -> Option<Span> {
+        let run = self.spans.partition_point(|&(_, end)| end <= idx);
+        self.spans.get(run).map(|&(span, _)| span)
+    }
+
+    pub fn get_line(&self, idx: usize) -> Option<u32> {
+        let run = self.lines.partition_point(|&(_, end)| end <= idx);
+        self.lines.get(run).map(|&(line, _)| line)
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn line_runs(&self) -> impl Iterator<Item = (u32, Range<usize>)> + '_ {
+        let starts = std::iter::once(0).chain(self.lines.iter().map(|&(_, end)| end));
+        self.lines.iter().zip(starts).map(|(&(line, end), start)| (line, start..end))
+    }
+
+    // Drops a single byte of code, shifting everything after it back by one
+    pub fn remove_byte(&mut self, offset: usize) {
+        self.code.remove(offset);
+        if offset < self.caches.len() {
+            self.caches.remove(offset);
+        }
+        remove_from_runs(&mut self.lines, offset);
+        remove_from_runs(&mut self.spans, offset);
+    }
+
+    // Rolls the chunk back to `len` bytes of code and `constants` constants, as
+    // when the compiler folds what it just emitted into a single constant
+    pub fn truncate(&mut self, len: usize, constants: usize) {
+        self.code.truncate(len);
+        self.caches.truncate(len);
+        truncate_runs(&mut self.lines, len);
+        truncate_runs(&mut self.spans, len);
+
+        for value in self.constants.drain(constants.min(self.constants.len())..) {
+            self.constant_indices.remove(&ConstantKey::from(value));
         }
-        None
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::from(value);
+        if let Some(&idx) = self.constant_indices.get(&key) {
+            return idx;
+        }
+
         self.constants.push(value);
+        self.constant_indices.insert(key, self.constants.len() - 1);
         self.constants.len() - 1
     }
 
+    // Adds a constant and emits the instruction that loads it, switching to
+    // OP_CONSTANT_LONG past the first 256. None if the index won't fit in 24 bits
+    pub fn write_constant(&mut self, value: Value, line: u32) -> Option<usize> {
+        let idx = self.add_constant(value);
+        match u8::try_from(idx) {
+            Ok(short) => {
+                self.write(OpCode::Constant, line);
+                self.write(short, line);
+            },
+            Err(_) if idx < MAX_LONG_CONSTANTS => {
+                self.write(OpCode::ConstantLong, line);
+                for b in &(idx as u32).to_be_bytes()[1..] {
+                    self.write(*b, line);
+                }
+            },
+            Err(_) => return None,
+        }
+        Some(idx)
+    }
+
+    pub fn verify(&self) -> Result<(), ChunkError> {
+        self.verify_with_depth(0)
+    }
+
+    // `depth` is how many values are already in the frame when the chunk starts
+    // (the callee and its arguments, for a function)
+    pub fn verify_with_depth(&self, depth: usize) -> Result<(), ChunkError> {
+        // Walk every reachable path, making sure each offset is always entered
+        // with the same stack depth
+        let mut depths: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut pending = vec![(0, depth)];
+
+        while let Some((offset, depth)) = pending.pop() {
+            match depths.get(offset).ok_or(ChunkError::IPOutOfBoundsError)? {
+                Some(d) if *d == depth => continue,
+                Some(_) => return Err(ChunkError::StackMismatchError(offset)),
+                None => depths[offset] = Some(depth),
+            }
+
+            let info = self.read_op(offset)?.info();
+            if offset + info.operand.width() >= self.code.len() {
+                return Err(ChunkError::TruncatedOperandError(offset));
+            }
+
+            if info.operand == Operand::CompareJump && !OpCode::try_from(self.code[offset + 1]).is_ok_and(OpCode::is_comparison) {
+                return Err(ChunkError::BadOPCodeError(self.code[offset + 1]));
+            }
+
+            let constant = match info.operand {
+                Operand::Constant | Operand::Invoke => Some(self.code[offset + 1] as usize),
+                Operand::ConstantLong => Some(self.read_long(offset + 1)?),
+                _ => None,
+            };
+            if constant.is_some_and(|c| c >= self.constants.len()) {
+                return Err(ChunkError::BadConstantError(offset));
+            }
+
+            // Only slots below the value being stored (for OP_SET_LOCAL) can be named
+            let slot = match info.operand {
+                Operand::Slot => Some(self.code[offset + 1] as usize),
+                Operand::SlotLong => Some(u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]) as usize),
+                _ => None,
+            };
+            if slot.is_some_and(|s| s + info.pops >= depth) {
+                return Err(ChunkError::BadSlotError(offset));
+            }
+
+            let pops = match info.operand {
+                Operand::Count => info.pops + self.code[offset + 1] as usize,
+                Operand::Invoke => info.pops + self.code[offset + 2] as usize,
+                _ => info.pops,
+            };
+            if depth < pops {
+                return Err(ChunkError::StackUnderflowError(offset));
+            }
+            let pushes = match info.operand {
+                Operand::Spread => info.pushes + self.code[offset + 1] as usize,
+                _ => info.pushes,
+            };
+            let depth = depth - pops + pushes;
+            let next = offset + 1 + info.operand.width();
+
+            match info.op {
+                OpCode::Return | OpCode::Throw => {},
+                OpCode::TryBegin => {
+                    let target = next + self.read_short(offset + 1)? as usize;
+                    if target >= self.code.len() {
+                        return Err(ChunkError::BadJumpError(offset));
+                    }
+                    pending.push((target, depth + 1));
+                    pending.push((next, depth));
+                },
+                OpCode::IterNext => {
+                    let target = next + self.read_short(offset + 1)? as usize;
+                    if target >= self.code.len() {
+                        return Err(ChunkError::BadJumpError(offset));
+                    }
+                    pending.push((target, depth - 1));
+                    pending.push((next, depth));
+                },
+                // The offset is always the last two bytes of the operand
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNotNil | OpCode::JumpIfNil | OpCode::CompareJump => {
+                    let target = next + self.read_short(next - 2)? as usize;
+                    if target >= self.code.len() {
+                        return Err(ChunkError::BadJumpError(offset));
+                    }
+                    pending.push((target, depth));
+                    if info.op != OpCode::Jump {
+                        pending.push((next, depth));
+                    }
+                },
+                OpCode::Loop => {
+                    let target = next.checked_sub(self.read_short(offset + 1)? as usize)
+                                     .ok_or(ChunkError::BadJumpError(offset))?;
+                    pending.push((target, depth));
+                },
+                _ => pending.push((next, depth)),
+            }
+        }
+        Ok(())
+    }
+
     // Debug functions
 
-    pub fn disassemble_chunk(&self, name: &str) {
+    pub fn disassemble_chunk(&self, name: &str, heap: &ObjHeap) {
         println!("== {} ==", name);
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, heap);
         }
     }
 
-    fn disassemble_instruction(&self, offset: usize) -> usize {
+    pub fn disassemble_instruction(&self, offset: usize, heap: &ObjHeap) -> usize {
         print!("{:0>4} ", offset);
 
         let current_line = self.get_line(offset).expect("Could not find line number");
@@ -139,22 +539,23 @@ impl Chunk {
         }
 
         let op = self.code[offset];
-        match op.try_into() {
-            Ok(OpCode::Constant) => self.constant_instruction("OP_CONSTANT", offset),
-            Ok(OpCode::ConstantLong) => self.constant_long_instruction("OP_CONSTANT_LONG", offset),
-            Ok(OpCode::Nil) => Self::simple_instruction("OP_NIL", offset),
-            Ok(OpCode::True) => Self::simple_instruction("OP_TRUE", offset),
-            Ok(OpCode::False) => Self::simple_instruction("OP_FALSE", offset),
-            Ok(OpCode::Equal) => Self::simple_instruction("OP_EQUAL", offset),
-            Ok(OpCode::Greater) => Self::simple_instruction("OP_GREATER", offset),
-            Ok(OpCode::Less) => Self::simple_instruction("OP_LESS", offset),
-            Ok(OpCode::Add) => Self::simple_instruction("OP_ADD", offset),
-            Ok(OpCode::Subtract) => Self::simple_instruction("OP_SUBTRACT", offset),
-            Ok(OpCode::Multiply) => Self::simple_instruction("OP_MULTIPLY", offset),
-            Ok(OpCode::Divide) => Self::simple_instruction("OP_DIVIDE", offset),
-            Ok(OpCode::Not) => Self::simple_instruction("OP_NOT", offset),
-            Ok(OpCode::Negate) => Self::simple_instruction("OP_NEGATE", offset),
-            Ok(OpCode::Return) => Self::simple_instruction("OP_RETURN", offset),
+        match OpCode::try_from(op) {
+            Ok(op) => {
+                let info = op.info();
+                match info.operand {
+                    Operand::None => Self::simple_instruction(info.name, offset),
+                    Operand::Constant => self.constant_instruction(info.name, offset, heap),
+                    Operand::ConstantLong => self.constant_long_instruction(info.name, offset, heap),
+                    Operand::Count | Operand::Spread | Operand::Slot => self.byte_instruction(info.name, offset),
+                    Operand::SlotLong => self.short_instruction(info.name, offset),
+                    Operand::Invoke => self.invoke_instruction(info.name, offset, heap),
+                    Operand::Jump => {
+                        let sign = if op == OpCode::Loop { -1 } else { 1 };
+                        self.jump_instruction(info.name, sign, offset)
+                    },
+                    Operand::CompareJump => self.compare_jump_instruction(info.name, offset),
+                }
+            },
             Err(_) => {
                 println!("Unknown opcode: {}", op);
                 offset + 1
@@ -162,36 +563,469 @@ impl Chunk {
         }
     }
 
-    fn constant_long_instruction(&self, name: &str, offset: usize) -> usize {
-        let mut constant = 0;
-        for o in 1..=3 {
-            constant += (constant << 2) + self.code[offset + o];
-        }
+    fn constant_long_instruction(&self, name: &str, offset: usize, heap: &ObjHeap) -> usize {
+        let constant = self.read_long(offset + 1).unwrap_or_default();
         println!(
-            "{} {:0<4} {}",
-            name, constant, self.constants[constant as usize]
+            "{} {:0>4} {}",
+            name, constant, heap.display(&self.constants[constant])
         );
         offset + 4
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn constant_instruction(&self, name: &str, offset: usize, heap: &ObjHeap) -> usize {
+        let constant = self.code[offset + 1];
+        println!(
+            "{} {:0>4} {}",
+            name, constant, heap.display(&self.constants[constant as usize])
+        );
+        offset + 2
+    }
+
+    fn invoke_instruction(&self, name: &str, offset: usize, heap: &ObjHeap) -> usize {
         let constant = self.code[offset + 1];
+        let arg_count = self.code[offset + 2];
         println!(
-            "{} {:0<4} {}",
-            name, constant, self.constants[constant as usize]
+            "{} ({} args) {:0>4} {}",
+            name, arg_count, constant, heap.display(&self.constants[constant as usize])
         );
+        offset + 3
+    }
+
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        println!("{} {:>4}", name, self.code[offset + 1]);
         offset + 2
     }
 
+    fn short_instruction(&self, name: &str, offset: usize) -> usize {
+        println!("{} {:>4}", name, u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]));
+        offset + 3
+    }
+
+    fn jump_instruction(&self, name: &str, sign: i32, offset: usize) -> usize {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        println!("{} {:0>4} -> {}", name, offset, offset as i32 + 3 + sign * jump as i32);
+        offset + 3
+    }
+
+    fn compare_jump_instruction(&self, name: &str, offset: usize) -> usize {
+        let compare = OpCode::try_from(self.code[offset + 1]).map_or("?", |op| op.info().name);
+        let jump = u16::from_be_bytes([self.code[offset + 2], self.code[offset + 3]]);
+        println!("{} {} {:0>4} -> {}", name, compare, offset, offset + 4 + jump as usize);
+        offset + 4
+    }
+
     fn simple_instruction(name: &str, offset: usize) -> usize {
         println!("{}", name);
         offset + 1
     }
+
+    // Bytecode file layout (integers little-endian):
+    //   magic, format version
+    //   flags byte, then the u32 length and bytes of the source path if flagged
+    //   the script's chunk body:
+    //     u32 code length, code
+    //     u32 constant count, then per constant a tag byte and its payload
+    //     u32 line run count, then (u32 line, u32 end) per run
+    //     u32 span run count, then per run the span's u32 start, end, line and
+    //     column followed by the run's u32 end
+    // String constants carry their contents and function constants carry their
+    // name, arity, rest flag and chunk body, so the chunk can be loaded into any
+    // heap. Other objects, such as classes put in by a ChunkBuilder, can't be written
+    pub fn serialize(&self, heap: &ObjHeap) -> Result<Vec<u8>, ChunkError> {
+        let mut out = Vec::new();
+        out.extend_from_slice(BYTECODE_MAGIC);
+        out.push(BYTECODE_VERSION);
+
+        match &self.source {
+            Some(path) => {
+                out.push(FLAG_SOURCE_PATH);
+                write_str(&mut out, path);
+            },
+            None => out.push(0),
+        }
+
+        self.write_body(&mut out, heap)?;
+        Ok(out)
+    }
+
+    fn write_body(&self, out: &mut Vec<u8>, heap: &ObjHeap) -> Result<(), ChunkError> {
+        write_u32(out, self.code.len());
+        out.extend_from_slice(&self.code);
+
+        write_u32(out, self.constants.len());
+        for (index, constant) in self.constants.iter().enumerate() {
+            match constant {
+                Value::Nil => out.push(0),
+                Value::Bool(false) => out.push(1),
+                Value::Bool(true) => out.push(2),
+                Value::Number(n) => {
+                    out.push(3);
+                    out.extend_from_slice(&n.to_le_bytes());
+                },
+                Value::Int(i) => {
+                    out.push(6);
+                    out.extend_from_slice(&i.to_le_bytes());
+                },
+                Value::Object(_) => {
+                    if let Some(s) = heap.as_str(constant) {
+                        out.push(4);
+                        write_str(out, s);
+                    } else if let Some(function) = heap.as_function(constant) {
+                        out.push(5);
+                        match &function.name {
+                            Some(name) => {
+                                out.push(1);
+                                write_str(out, name);
+                            },
+                            None => out.push(0),
+                        }
+                        write_u32(out, function.arity);
+                        out.push(function.variadic as u8);
+                        function.chunk.write_body(out, heap)?;
+                    } else {
+                        return Err(ChunkError::UnserializableConstantError(index));
+                    }
+                },
+            }
+        }
+
+        write_u32(out, self.lines.len());
+        for &(line, end) in &self.lines {
+            write_u32(out, line as usize);
+            write_u32(out, end);
+        }
+
+        write_u32(out, self.spans.len());
+        for &(span, end) in &self.spans {
+            for n in [span.start, span.end, span.line as usize, span.column as usize, end] {
+                write_u32(out, n);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(bytes: &[u8], heap: &mut ObjHeap) -> Result<Chunk, DecodeError> {
+        let mut reader = ByteReader { bytes, pos: 0 };
+
+        if reader.take(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+            return Err(DecodeError::BadMagicError);
+        }
+
+        let version = reader.take(1)?[0];
+        if version != BYTECODE_VERSION {
+            return Err(DecodeError::UnsupportedVersionError(version));
+        }
+
+        let source = match reader.take(1)?[0] & FLAG_SOURCE_PATH {
+            0 => None,
+            _ => Some(reader.string()?),
+        };
+
+        // The script's own slot is the only thing on the stack when it starts
+        let mut chunk = Chunk::read_body(&mut reader, heap, 1)?;
+        chunk.source = source;
+        Ok(chunk)
+    }
+
+    fn read_body(reader: &mut ByteReader, heap: &mut ObjHeap, depth: usize) -> Result<Chunk, DecodeError> {
+        let mut chunk = Chunk::default();
+
+        let code_len = reader.u32()?;
+        chunk.code = reader.take(code_len)?.to_vec();
+
+        for _ in 0..reader.u32()? {
+            let constant = match reader.take(1)?[0] {
+                0 => Value::Nil,
+                1 => Value::Bool(false),
+                2 => Value::Bool(true),
+                3 => Value::Number(f64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+                4 => heap.alloc_str(reader.string()?),
+                6 => Value::Int(i64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+                5 => {
+                    let name = match reader.take(1)?[0] {
+                        0 => None,
+                        _ => Some(reader.string()?),
+                    };
+                    let arity = reader.u32()?;
+                    let variadic = reader.take(1)?[0] != 0;
+                    // A function's frame starts with the callee and its arguments
+                    let chunk = Chunk::read_body(reader, heap, arity + 1)?;
+                    Value::Object(heap.alloc(ObjectType::Function(Function { arity, variadic, chunk, name, module: None })))
+                },
+                tag => return Err(DecodeError::BadConstantTagError(tag)),
+            };
+            chunk.constants.push(constant);
+        }
+
+        for _ in 0..reader.u32()? {
+            let line = reader.u32()? as u32;
+            let end = reader.u32()?;
+            chunk.lines.push((line, end));
+        }
+
+        for _ in 0..reader.u32()? {
+            let (start, end) = (reader.u32()?, reader.u32()?);
+            let (line, column) = (reader.u32()? as u32, reader.u32()? as u32);
+            chunk.spans.push((Span { start, end, line, column }, reader.u32()?));
+        }
+
+        chunk.verify_with_depth(depth)?;
+        Ok(chunk)
+    }
+}
+
+// Shifts the runs after a removed byte back by one
+fn remove_from_runs<T>(runs: &mut Vec<(T, usize)>, offset: usize) {
+    for (_, end) in runs.iter_mut().filter(|(_, end)| *end > offset) {
+        *end -= 1;
+    }
+    drop_empty_runs(runs);
+}
+
+fn truncate_runs<T>(runs: &mut Vec<(T, usize)>, len: usize) {
+    for (_, end) in runs.iter_mut() {
+        *end = (*end).min(len);
+    }
+    drop_empty_runs(runs);
+}
+
+fn drop_empty_runs<T>(runs: &mut Vec<(T, usize)>) {
+    let mut previous_end = 0;
+    runs.retain(|&(_, end)| {
+        let keep = end > previous_end;
+        previous_end = end;
+        keep
+    });
+}
+
+const BYTECODE_MAGIC: &[u8] = b"ROXC";
+// Bump whenever the layout or the opcode numbering changes
+pub const BYTECODE_VERSION: u8 = 29;
+const FLAG_SOURCE_PATH: u8 = 0x01;
+
+// Stricter than Value's own equality: a constant may only be shared with one
+// of the same type and bit pattern, so 3 and 3.0 (or 0 and -0) stay apart.
+// Strings are interned, so equal strings already share a handle
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+    Int(i64),
+    Object(ObjHandle),
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> ConstantKey {
+        match value {
+            Value::Bool(b) => ConstantKey::Bool(b),
+            Value::Nil => ConstantKey::Nil,
+            Value::Number(n) => ConstantKey::Number(n.to_bits()),
+            Value::Int(i) => ConstantKey::Int(i),
+            Value::Object(h) => ConstantKey::Object(h),
+        }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let slice = self.bytes.get(self.pos..self.pos + len).ok_or(DecodeError::TruncatedError)?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u32()?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|_| DecodeError::BadStringError)?;
+        Ok(s.to_string())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::value::Class;
+
+    #[test]
+    fn test_op_table_order() {
+        for (byte, info) in OP_TABLE.iter().enumerate() {
+            assert_eq!(u8::from(info.op) as usize, byte);
+            assert_eq!(OpCode::try_from(byte as u8).unwrap(), info.op);
+        }
+        assert!(OpCode::try_from(OP_TABLE.len() as u8).is_err());
+
+        for info in OP_TABLE.iter() {
+            if let Some(long) = info.op.long_form() {
+                assert_eq!(long.info().name, format!("{}_LONG", info.name));
+                assert!(long.is_long());
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut chunk = Chunk::default();
+        let constant = chunk.add_constant(Value::Number(1.0)) as u8;
+        chunk.write(OpCode::Constant, 1);
+        chunk.write(constant, 1);
+        chunk.write(OpCode::Negate, 1);
+        chunk.write(OpCode::Return, 1);
+        assert!(chunk.verify().is_ok());
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Constant, 1);
+        assert!(matches!(chunk.verify(), Err(ChunkError::TruncatedOperandError(0))));
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Constant, 1);
+        chunk.write(0x05, 1);
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadConstantError(0))));
+
+        let mut chunk = Chunk::default();
+        chunk.add_constant(Value::Nil);
+        for byte in [OpCode::ConstantLong.into(), 0x00, 0x01, 0x00, OpCode::Return.into()] {
+            chunk.write(byte, 1);
+        }
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadConstantError(0))));
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::Add, 1);
+        assert!(matches!(chunk.verify(), Err(ChunkError::StackUnderflowError(1))));
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::GetLocal, 1);
+        chunk.write(0x01, 1);
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadSlotError(1))));
+
+        let mut chunk = Chunk::default();
+        for byte in [OpCode::Nil.into(), OpCode::GetLocalLong.into(), 0x01, 0x00, OpCode::Return.into()] {
+            chunk.write(byte, 1);
+        }
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadSlotError(1))));
+
+        let mut chunk = Chunk::default();
+        for byte in [OpCode::Nil, OpCode::Nil, OpCode::CompareJump, OpCode::Add] {
+            chunk.write(byte, 1);
+        }
+        for byte in [0x00, 0x00, OpCode::Return.into()] {
+            chunk.write(byte, 1);
+        }
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadOPCodeError(b)) if b == OpCode::Add as u8));
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::Jump, 1);
+        chunk.write(0x00, 1);
+        chunk.write(0x10, 1);
+        chunk.write(OpCode::Return, 1);
+        assert!(matches!(chunk.verify(), Err(ChunkError::BadJumpError(1))));
+    }
+
+    #[test]
+    fn test_constant_dedup() {
+        let mut heap = ObjHeap::default();
+        let mut chunk = Chunk::default();
+        let a = heap.alloc_str("a".to_string());
+
+        assert_eq!(chunk.add_constant(Value::Number(1.5)), 0);
+        assert_eq!(chunk.add_constant(a), 1);
+        assert_eq!(chunk.add_constant(Value::Number(1.5)), 0);
+        assert_eq!(chunk.add_constant(heap.alloc_str("a".to_string())), 1);
+
+        // Equal as values, but not interchangeable as constants
+        assert_eq!(chunk.add_constant(Value::Int(1)), 2);
+        assert_eq!(chunk.add_constant(Value::Number(1.0)), 3);
+        assert_eq!(chunk.add_constant(Value::Number(0.0)), 4);
+        assert_eq!(chunk.add_constant(Value::Number(-0.0)), 5);
+        assert_eq!(chunk.constants().len(), 6);
+
+        // Dropped constants are forgotten, so adding one again gives a new index
+        chunk.truncate(0, 3);
+        assert_eq!(chunk.constants().len(), 3);
+        assert_eq!(chunk.add_constant(Value::Number(0.0)), 3);
+        assert_eq!(chunk.add_constant(a), 1);
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut heap = ObjHeap::default();
+        let mut chunk = Chunk::default();
+        let number = chunk.add_constant(Value::Number(1.5)) as u8;
+        let string = chunk.add_constant(heap.alloc_str("hi".to_string())) as u8;
+        chunk.add_constant(Value::Int(-3));
+        chunk.write(OpCode::Constant, 1);
+        chunk.write(number, 1);
+        chunk.write(OpCode::Constant, 2);
+        chunk.write(string, 2);
+        chunk.write(OpCode::Return, 3);
+
+        let bytes = chunk.serialize(&heap).unwrap();
+
+        let mut other_heap = ObjHeap::default();
+        other_heap.alloc_str("unrelated".to_string());
+        let loaded = Chunk::deserialize(&bytes, &mut other_heap).unwrap();
+
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.line_runs().collect::<Vec<_>>(), chunk.line_runs().collect::<Vec<_>>());
+        assert_eq!(loaded.constant_ref(0).unwrap(), &Value::Number(1.5));
+        assert!(matches!(loaded.constant_ref(2).unwrap(), Value::Int(-3)));
+        assert_eq!(other_heap.as_str(loaded.constant_ref(1).unwrap()), Some("hi"));
+        assert_eq!(loaded.source, None);
+
+        chunk.source = Some("scripts/hi.lox".to_string());
+        let loaded = Chunk::deserialize(&chunk.serialize(&heap).unwrap(), &mut other_heap).unwrap();
+        assert_eq!(loaded.source.as_deref(), Some("scripts/hi.lox"));
+        assert_eq!(loaded.get_line(4), Some(3));
+
+        let mut body = Chunk::default();
+        body.write(OpCode::GetLocal, 1);
+        body.write(1, 1);
+        body.write(OpCode::Return, 1);
+        let function = Function { arity: 1, variadic: true, chunk: body, name: Some("id".to_string()), module: None };
+        let mut chunk = Chunk::default();
+        let constant = chunk.add_constant(Value::Object(heap.alloc(ObjectType::Function(function)))) as u8;
+        chunk.write(OpCode::Constant, 1);
+        chunk.write(constant, 1);
+        chunk.write(OpCode::Return, 1);
+
+        let loaded = Chunk::deserialize(&chunk.serialize(&heap).unwrap(), &mut other_heap).unwrap();
+        let function = other_heap.as_function(loaded.constant_ref(0).unwrap()).unwrap();
+        assert_eq!((function.arity, function.variadic, function.name.as_deref()), (1, true, Some("id")));
+        assert_eq!(function.chunk.code, vec![OpCode::GetLocal.into(), 1, OpCode::Return.into()]);
+
+        // Only strings and functions have an encoding among the objects
+        let class = heap.alloc(ObjectType::Class(Class { name: "A".to_string(), methods: HashMap::new(), getters: HashMap::new() }));
+        let mut chunk = Chunk::default();
+        chunk.add_constant(Value::Int(1));
+        chunk.add_constant(Value::Object(class));
+        assert!(matches!(chunk.serialize(&heap), Err(ChunkError::UnserializableConstantError(1))));
+
+        assert!(matches!(Chunk::deserialize(b"nope", &mut other_heap), Err(DecodeError::BadMagicError)));
+        assert!(matches!(
+            Chunk::deserialize(&bytes[..bytes.len() - 1], &mut other_heap),
+            Err(DecodeError::TruncatedError)
+        ));
+    }
 
     #[test]
     fn test_line_rle() {
@@ -239,5 +1073,55 @@ mod test {
         for offset in 12..=13 {
             assert_eq!(chunk.get_line(offset), Some(100));
         }
+
+        assert_eq!(chunk.line_count(), 4);
+
+        assert_eq!(
+            chunk.line_runs().collect::<Vec<_>>(),
+            vec![(1, 0..3), (2, 3..7), (3, 7..12), (100, 12..14)]
+        );
+
+        chunk.remove_byte(12);
+        chunk.remove_byte(4);
+        assert_eq!(
+            chunk.line_runs().collect::<Vec<_>>(),
+            vec![(1, 0..3), (2, 3..6), (3, 6..11), (100, 11..12)]
+        );
+        chunk.remove_byte(11);
+        assert_eq!(chunk.line_count(), 3);
+
+        chunk.truncate(5, 0);
+        assert_eq!(chunk.line_runs().collect::<Vec<_>>(), vec![(1, 0..3), (2, 3..5)]);
+        chunk.truncate(3, 0);
+        assert_eq!(chunk.line_count(), 1);
+    }
+
+    #[test]
+    fn test_spans() {
+        let heap = ObjHeap::default();
+        let mut chunk = Chunk::default();
+        let nil = Span { start: 0, end: 3, line: 1, column: 1 };
+        let ret = Span { start: 5, end: 11, line: 2, column: 1 };
+        chunk.write_spanned(OpCode::Nil, nil);
+        chunk.write_spanned(OpCode::Nil, nil);
+        chunk.write_spanned(OpCode::Return, ret);
+
+        assert_eq!(chunk.get_span(1), Some(nil));
+        assert_eq!(chunk.get_span(2), Some(ret));
+        assert_eq!(chunk.get_span(3), None);
+        assert_eq!(chunk.get_line(2), Some(2));
+
+        let loaded = Chunk::deserialize(&chunk.serialize(&heap).unwrap(), &mut ObjHeap::default()).unwrap();
+        assert_eq!(loaded.get_span(2), Some(ret));
+
+        chunk.remove_byte(0);
+        assert_eq!(chunk.get_span(1), Some(ret));
+        chunk.truncate(1, 0);
+        assert_eq!(chunk.get_span(1), None);
+
+        // Code written without a span has none
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Return, 1);
+        assert_eq!(chunk.get_span(0), None);
     }
 }
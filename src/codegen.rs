@@ -0,0 +1,474 @@
+use crate::ast::Identifier;
+use crate::chunk::{Chunk, OpCode, Operand};
+use crate::error::{Diagnostic, Severity};
+use crate::heap::ObjHeap;
+use crate::token::Span;
+use crate::value::{Function, Value};
+
+use std::collections::{HashMap, HashSet};
+
+pub(crate) const MAX_LOCALS: usize = u16::MAX as usize + 1;
+pub(crate) const MAX_LONG_CONSTANTS: usize = 1 << 24;
+
+// One per function being compiled, innermost first. Locals live on the VM
+// stack in declaration order, so a local's index here is also its frame slot
+#[derive(Debug)]
+pub(crate) struct FunctionState<'a> {
+    pub(crate) enclosing: Option<Box<FunctionState<'a>>>,
+    pub(crate) function_type: FunctionType,
+    pub(crate) name: Option<&'a str>,
+    pub(crate) arity: usize,
+    pub(crate) variadic: bool,
+    pub(crate) chunk: Chunk,
+    pub(crate) locals: Vec<Local<'a>>,
+    pub(crate) scope_depth: usize,
+    // How many try blocks enclose the code being compiled
+    pub(crate) try_depth: usize,
+    // Innermost last; a function body starts with none, so it can't continue an outer loop
+    pub(crate) loops: Vec<Loop>,
+}
+
+impl<'a> FunctionState<'a> {
+    pub(crate) fn new(function_type: FunctionType, name: Option<&'a str>) -> Self {
+        FunctionState {
+            enclosing: None,
+            function_type,
+            name,
+            arity: 0,
+            variadic: false,
+            chunk: Chunk::default(),
+            // Slot zero holds the function being called, or the receiver for methods
+            locals: vec![Local { name: if function_type.is_method() { "this" } else { "" }, ..Local::used(0) }],
+            scope_depth: 0,
+            try_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    pub(crate) fn into_function(self) -> Function {
+        Function {
+            arity: self.arity,
+            variadic: self.variadic,
+            chunk: self.chunk,
+            name: self.name.map(str::to_string),
+            module: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    // A method without a parameter list, run when its property is read
+    Getter,
+    Script,
+}
+
+impl FunctionType {
+    pub(crate) fn is_method(self) -> bool {
+        matches!(self, FunctionType::Method | FunctionType::Initializer | FunctionType::Getter)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Local<'a> {
+    pub(crate) name: &'a str,
+    // None until the initializer has been compiled
+    pub(crate) depth: Option<usize>,
+    pub(crate) is_const: bool,
+    pub(crate) is_function: bool,
+    pub(crate) span: Span,
+    // Whether the local has been read, or never needs to be (like a parameter)
+    pub(crate) used: bool,
+}
+
+impl<'a> Local<'a> {
+    pub(crate) fn new(name: &'a str, span: Span) -> Self {
+        Local { name, depth: None, is_const: false, is_function: false, span, used: false }
+    }
+
+    // Slot zero, hidden locals and the like, which are never warned about
+    pub(crate) fn used(depth: usize) -> Self {
+        Local { name: "", depth: Some(depth), is_const: false, is_function: false, span: Span::default(), used: true }
+    }
+
+    // Hidden locals have no name and can't be read anyway
+    pub(crate) fn unused_warning(&self) -> Option<Diagnostic> {
+        if self.used || self.name.is_empty() {
+            return None;
+        }
+        let kind = if self.is_function { "function" } else if self.is_const { "constant" } else { "variable" };
+        let message = format!("Unused {} '{}'.", kind, self.name);
+        Some(Diagnostic { span: self.span, severity: Severity::Warning, message, location: String::new() })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Loop {
+    // Where `continue` jumps back to: the condition, or a for loop's increment clause
+    pub(crate) start: usize,
+    // Locals deeper than this belong to the body and are popped by `continue`
+    pub(crate) scope_depth: usize,
+    // Try blocks deeper than this are inside the body, and `continue` leaves them
+    pub(crate) try_depth: usize,
+}
+
+// The code generation shared by the single-pass compiler and the ast lowering.
+// Implementors say where the current function's state lives, what emitted code
+// is attributed to and where errors go; scopes, locals, constants and jumps are
+// then handled the same way for both
+pub(crate) trait CodeGen<'a> {
+    fn state(&mut self) -> &mut FunctionState<'a>;
+    fn heap(&mut self) -> &mut ObjHeap;
+    // Globals declared with `const` anywhere in the compilation unit
+    fn const_globals(&mut self) -> &mut HashSet<&'a str>;
+    // The index each global defined so far gets in a fresh globals table
+    fn global_slots(&mut self) -> &mut HashMap<String, usize>;
+    // What the code being emitted is attributed to
+    fn span(&self) -> Span;
+
+    // An error about the code being emitted, like a jump that's too long
+    fn error(&mut self, message: &str);
+    // An error about a name; `text` is what the location quotes
+    fn error_at(&mut self, span: Span, text: &str, message: &str);
+    fn warn(&mut self, diagnostic: Diagnostic);
+
+    // Hooks for the single-pass compiler's peephole optimizations: a one-byte
+    // OP_CONSTANT was emitted at `start`, a forward jump was patched to land
+    // at `offset`, and the opcode of a jump about to be emitted
+    fn constant_emitted(&mut self, _start: usize) {}
+    fn jump_landed(&mut self, _offset: usize) {}
+    fn emit_jump_op(&mut self, op: OpCode) {
+        self.emit_byte(op);
+    }
+
+    fn begin_function(&mut self, function_type: FunctionType, name: Option<&'a str>) {
+        let enclosing = std::mem::replace(self.state(), FunctionState::new(function_type, name));
+        self.state().enclosing = Some(Box::new(enclosing));
+        self.begin_scope();
+    }
+
+    // Hands back the finished function's state and goes back to the enclosing
+    // one. The function's own scope is never ended, since returning discards the
+    // whole frame, so its locals are checked here
+    fn end_function(&mut self) -> FunctionState<'a> {
+        for local in std::mem::take(&mut self.state().locals) {
+            self.warn_if_unused(&local);
+        }
+        let enclosing = self.state().enclosing.take().expect("Expected an enclosing function");
+        std::mem::replace(self.state(), *enclosing)
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state().scope_depth -= 1;
+
+        let depth = self.state().scope_depth;
+        while self.state().locals.last().is_some_and(|l| l.depth.is_none_or(|d| d > depth)) {
+            self.emit_byte(OpCode::Pop);
+            if let Some(local) = self.state().locals.pop() {
+                self.warn_if_unused(&local);
+            }
+        }
+    }
+
+    fn warn_if_unused(&mut self, local: &Local) {
+        if let Some(warning) = local.unused_warning() {
+            self.warn(warning);
+        }
+    }
+
+    // Returns the name constant for globals; locals don't need one
+    fn declare_variable(&mut self, name: Identifier<'a>) -> usize {
+        if self.state().scope_depth == 0 {
+            // Globals can normally be redefined, but that would get around const
+            if self.const_globals().contains(name.name) {
+                self.error_at(name.span, name.name, "Already a constant with this name.");
+            }
+            return self.identifier_constant(name.name);
+        }
+
+        let depth = self.state().scope_depth;
+        let duplicate = self.state().locals.iter()
+                                           .rev()
+                                           .take_while(|l| l.depth.is_none_or(|d| d >= depth))
+                                           .any(|l| l.name == name.name);
+        if duplicate {
+            self.error_at(name.span, name.name, "Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+        0
+    }
+
+    fn add_local(&mut self, name: Identifier<'a>) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error_at(name.span, name.name, "Too many local variables in function.");
+            return;
+        }
+        self.state().locals.push(Local::new(name.name, name.span));
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_define_global(global);
+    }
+
+    // `var (a, b) = list;` unpacks the list onto the stack, one value per name
+    // in declaration order, so globals are defined from the last name back
+    fn define_unpacked(&mut self, globals: Vec<usize>) {
+        self.emit_bytes(OpCode::Unpack.into(), globals.len() as u8);
+
+        let state = self.state();
+        if state.scope_depth > 0 {
+            let depth = state.scope_depth;
+            for local in state.locals.iter_mut().rev().take(globals.len()) {
+                local.depth = Some(depth);
+            }
+        } else {
+            for global in globals.into_iter().rev() {
+                self.emit_define_global(global);
+            }
+        }
+    }
+
+    // A table only ever appends, so the globals a script defines, in the order
+    // it defines them, sit at known indices once it runs in a fresh table
+    fn emit_define_global(&mut self, global: usize) {
+        let name = self.state().chunk.constants().get(global).copied();
+        if let Some(name) = name.and_then(|name| self.heap().as_str(&name).map(str::to_string)) {
+            let next = self.global_slots().len();
+            self.global_slots().entry(name).or_insert(next);
+        }
+        self.emit_operand(OpCode::DefineGlobal, global);
+    }
+
+    // The slot the global is expected at seeds the site's inline cache, so even
+    // the first lookup is an index. When the table isn't fresh, as in a REPL, the
+    // name check on the cache sends a wrong guess back to the name lookup
+    fn emit_get_global(&mut self, name: &str, constant: usize) {
+        let site = self.state().chunk.code.len();
+        self.emit_operand(OpCode::GetGlobal, constant);
+        if let Some(&slot) = self.global_slots().get(name) {
+            self.state().chunk.cache(site, slot);
+        }
+    }
+
+    // For locals that are fine to leave unread
+    fn mark_used(&mut self) {
+        if let Some(local) = self.state().locals.last_mut() {
+            local.used = true;
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let depth = self.state().scope_depth;
+        if depth == 0 { return; }
+        if let Some(local) = self.state().locals.last_mut().filter(|l| l.depth.is_none()) {
+            local.depth = Some(depth);
+        }
+    }
+
+    // A local still inside its own initializer already shadows any enclosing
+    // variable of the same name, so `var a = a;` can't quietly read the outer one
+    fn resolve_local(&mut self, name: Identifier<'a>) -> Option<usize> {
+        let Some(slot) = self.state().locals.iter().rposition(|l| l.name == name.name) else {
+            return self.resolve_enclosing(name);
+        };
+        if self.state().locals[slot].depth.is_none() {
+            self.error_at(name.span, name.name, "Can't read local variable in its own initializer.");
+        }
+        Some(slot)
+    }
+
+    // Functions don't close over anything, so a local of an enclosing function
+    // isn't in scope here and the name would otherwise quietly fall through to a
+    // global. The one exception is a local function naming itself, which finds
+    // itself in slot zero. Either way the outer local counts as used
+    fn resolve_enclosing(&mut self, name: Identifier<'a>) -> Option<usize> {
+        let (function_type, own_name) = (self.state().function_type, self.state().name);
+
+        let mut found = None;
+        let mut enclosing = self.state().enclosing.as_deref_mut();
+        let mut depth = 0;
+        while let Some(state) = enclosing {
+            if let Some(local) = state.locals.iter_mut().rev().find(|l| l.name == name.name) {
+                local.used = true;
+                found = Some((depth, local.is_function));
+                break;
+            }
+            enclosing = state.enclosing.as_deref_mut();
+            depth += 1;
+        }
+
+        match found {
+            Some((0, true)) if function_type == FunctionType::Function && own_name == Some(name.name) => Some(0),
+            Some(_) => {
+                let message = format!("Can't capture local variable '{}' from an enclosing function.", name.name);
+                self.error_at(name.span, name.name, &message);
+                None
+            },
+            None => None,
+        }
+    }
+
+    // Only reads count as a use, assigning to a local nothing reads is still dead
+    fn emit_get_local(&mut self, slot: usize) {
+        self.state().locals[slot].used = true;
+        if slot == 0 {
+            self.emit_byte(OpCode::GetLocal0);
+        } else {
+            self.emit_operand(OpCode::GetLocal, slot);
+        }
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let state = self.state();
+        let (scope_depth, try_depth) = (state.scope_depth, state.try_depth);
+        state.loops.push(Loop { start, scope_depth, try_depth });
+    }
+
+    // The collection and a cursor into it live in two hidden locals for the
+    // whole loop, while the item is a fresh local for each pass through the
+    // body. Expects the collection on the stack, and leaves the item's scope
+    // open for it to be declared in. Returns the loop's start and exit jump
+    fn begin_for_in(&mut self) -> (usize, usize) {
+        self.emit_byte(OpCode::IterNew);
+        for _ in 0..2 {
+            self.add_local(Identifier { name: "", span: self.span() });
+            self.mark_initialized();
+        }
+
+        let loop_start = self.state().chunk.code.len();
+        let exit_jump = self.emit_jump(OpCode::IterNext);
+
+        // `continue` has to pop the item too, so the loop is recorded outside its scope
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        (loop_start, exit_jump)
+    }
+
+    fn end_for_in(&mut self, (loop_start, exit_jump): (usize, usize)) {
+        self.end_scope();
+        self.state().loops.pop();
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+    }
+
+    // Returns false outside of a loop, leaving the error to the caller. The
+    // locals stay declared for the rest of the body; only the stack is unwound
+    fn emit_continue(&mut self) -> bool {
+        let Some(&Loop { start, scope_depth, try_depth }) = self.state().loops.last() else {
+            return false;
+        };
+
+        let body_locals = self.state().locals.iter()
+                                             .rev()
+                                             .take_while(|l| l.depth.is_none_or(|d| d > scope_depth))
+                                             .count();
+        for _ in 0..body_locals {
+            self.emit_byte(OpCode::Pop);
+        }
+        for _ in try_depth..self.state().try_depth {
+            self.emit_byte(OpCode::TryEnd);
+        }
+        self.emit_loop(start);
+        true
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let value = self.heap().alloc_str(name.to_string());
+        self.state().chunk.add_constant(value)
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.state().chunk.add_constant(value);
+        let start = self.state().chunk.code.len();
+        self.emit_operand(OpCode::Constant, constant);
+        if constant <= u8::MAX as usize {
+            self.constant_emitted(start);
+        }
+    }
+
+    // Past the first 256 constants the index is written as three bytes, high
+    // byte first, after the long form of the opcode. Slots past 256 take two
+    fn emit_operand(&mut self, op: OpCode, constant: usize) {
+        match (u8::try_from(constant), op.long_form()) {
+            (Ok(c), _) => self.emit_bytes(op.into(), c),
+            (Err(_), Some(long)) if long.info().operand == Operand::SlotLong => {
+                let [hi, lo] = (constant as u16).to_be_bytes();
+                self.emit_byte(long);
+                self.emit_bytes(hi, lo);
+            },
+            (Err(_), Some(long)) if constant < MAX_LONG_CONSTANTS => {
+                self.emit_byte(long);
+                self.emit_bytes((constant >> 16) as u8, (constant >> 8) as u8);
+                self.emit_byte(constant as u8);
+            },
+            _ => self.error("Too many constants in one chunk."),
+        }
+    }
+
+    // Emits a jump with a placeholder offset, returning where the offset goes
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_jump_op(op);
+        self.emit_bytes(0xff, 0xff);
+        self.state().chunk.code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let len = self.state().chunk.code.len();
+        self.jump_landed(len);
+
+        // Jumps are relative to the end of their operand
+        match u16::try_from(len - offset - 2) {
+            Ok(jump) => self.state().chunk.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes()),
+            Err(_) => self.error("Too much code to jump over."),
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop);
+
+        let offset = self.state().chunk.code.len() - loop_start + 2;
+        match u16::try_from(offset) {
+            Ok(offset) => {
+                let [hi, lo] = offset.to_be_bytes();
+                self.emit_bytes(hi, lo);
+            },
+            Err(_) => {
+                self.error("Loop body too large.");
+                self.emit_bytes(0, 0);
+            },
+        }
+    }
+
+    // Falling off the end of a function (or the script) returns nil, while
+    // initializers always return the instance
+    fn emit_return(&mut self) {
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_byte(OpCode::GetLocal0);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.emit_byte(OpCode::Return);
+    }
+
+    fn emit_byte<U: Into<u8>>(&mut self, byte: U) {
+        let span = self.span();
+        self.state().chunk.write_spanned(byte, span);
+    }
+
+    fn emit_bytes<U: Into<u8>>(&mut self, byte1: U, byte2: U) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+}
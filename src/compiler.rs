@@ -1,34 +1,117 @@
 use crate::value::{Value, ObjectType};
-use crate::token::{Token, TokenType};
+use crate::heap::ObjHeap;
+use crate::token::{Span, Token, TokenType};
 use crate::scanner::{ScanError, Scanner};
 use crate::chunk::{Chunk, OpCode};
 use crate::precedence::Precedence;
+use crate::error::{CompileError, Diagnostic, Severity};
+use crate::ast::Identifier;
+use crate::codegen::{CodeGen, FunctionState, FunctionType};
 
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::str;
 
-pub fn compile(source: &str, chunk: &mut Chunk) -> Result<(), ScanError> {
-    let mut p = Parser::new(source, chunk);
+// Where compiling sends its errors and warnings, so a host can show them
+// however it likes instead of having them printed
+pub trait DiagnosticSink {
+    fn report(&mut self, diagnostic: Diagnostic);
+}
+
+// What compile uses: each diagnostic printed on its own line
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stderr;
+
+impl DiagnosticSink for Stderr {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        eprintln!("{}", diagnostic);
+    }
+}
+
+impl DiagnosticSink for Vec<Diagnostic> {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.push(diagnostic);
+    }
+}
+
+pub fn compile(source: &str, chunk: &mut Chunk, heap: &mut ObjHeap) -> Result<(), Vec<CompileError>> {
+    compile_with_sink(source, chunk, heap, &mut Stderr)
+}
+
+// Diagnostics are passed on in the order they were raised, once the whole
+// source has been compiled. Errors are still returned as well
+pub fn compile_with_sink(source: &str, chunk: &mut Chunk, heap: &mut ObjHeap, sink: &mut dyn DiagnosticSink) -> Result<(), Vec<CompileError>> {
+    let mut p = Parser::new(source, heap);
 
     p.advance();
-    p.expression();
-    p.consume(TokenType::EOF, "Expect end of expression.");
+    p.declarations(TokenType::EOF);
+    p.consume(TokenType::EOF, "Expect end of input.");
     p.emit_return();
+    *chunk = std::mem::take(&mut p.compiler.chunk);
+    for diagnostic in p.diagnostics.drain(..) {
+        sink.report(diagnostic);
+    }
 
-    Ok(())
+    if p.had_error {
+        Err(p.errors)
+    } else {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct Parser<'a> {
     scanner: Scanner<'a>,
-    chunk: &'a mut Chunk,
+    heap: &'a mut ObjHeap,
 
     previous: Option<Token<'a>>,
     current: Option<Token<'a>>,
 
     had_error: bool,
     panic_mode: bool,
+    errors: Vec<CompileError>,
+    diagnostics: Vec<Diagnostic>,
+
+    // Where the most recently compiled string literal's constant instruction ends
+    last_string_constant: Option<usize>,
+    // Where the latest one-byte OP_CONSTANT and lone comparison start, and the
+    // offset the latest forward jump lands on, for fusing superinstructions.
+    // Like the rest of these, they only ever point into the current function
+    last_constant: Option<usize>,
+    last_comparison: Option<usize>,
+    last_landing: Option<usize>,
+    // Where the latest OP_CALL starts, so `return f(...)` can become a tail call
+    last_call: Option<usize>,
+    // The latest literal operand, for folding constant expressions
+    last_literal: Option<Literal>,
+    // Whether the statement just compiled always returns, throws or continues,
+    // so nothing after it in the same block can run
+    always_exits: bool,
+
+    compiler: FunctionState<'a>,
+    // How many class bodies enclose the code being compiled
+    class_depth: usize,
+    // Globals declared with `const` anywhere in this compilation unit
+    const_globals: HashSet<&'a str>,
+    global_slots: HashMap<String, usize>,
+}
+
+// A literal's instruction, spanning start..end, and how many constants the chunk
+// had before it, so folding can drop its constant again
+#[derive(Debug, Clone, Copy)]
+struct Literal {
+    start: usize,
+    end: usize,
+    constants: usize,
+    value: Value,
 }
 
+// Bump whenever the same source compiles to different code, so chunks cached
+// by an older compiler aren't loaded in place of the new code
+pub const COMPILER_REVISION: u32 = 1;
+
+pub(crate) const MAX_ARGS: usize = 255;
+
 #[derive(Debug)]
 pub enum ParseError {
     ScanError(ScanError)
@@ -40,61 +123,80 @@ impl From<ScanError> for ParseError {
     }
 }
 
-type ParserFn<'a, 'b> = fn(&'b mut Parser<'a>);
-struct Rule<'a, 'b> {
-    prefix: Option<Box<ParserFn<'a, 'b>>>,
-    infix: Option<Box<ParserFn<'a, 'b>>>,
+// The flag says whether the expression may be an assignment target, which is
+// only the case when parsing at assignment precedence or lower
+type ParserFn<'a> = fn(&mut Parser<'a>, bool);
+
+#[derive(Clone, Copy)]
+struct Rule<'a> {
+    prefix: Option<ParserFn<'a>>,
+    infix: Option<ParserFn<'a>>,
     precedence: Precedence
 }
 
-impl<'a, 'b> Rule<'a, 'b> {
-    fn new(
-        prefix: Option<Box<ParserFn<'a, 'b>>>,
-        infix: Option<Box<ParserFn<'a, 'b>>>,
-        precedence: Precedence
-    ) -> Self {
+impl<'a> Rule<'a> {
+    const fn new(prefix: Option<ParserFn<'a>>, infix: Option<ParserFn<'a>>, precedence: Precedence) -> Self {
         Rule { prefix, infix, precedence }
     }
 }
 
-fn get_rule<'a, 'b>(token_type: TokenType) -> Rule<'a, 'b> {
+fn get_rule<'a>(token_type: TokenType) -> Rule<'a> {
     match token_type {
-        TokenType::LeftParen => Rule::new(Some(Box::new(Parser::<'a>::grouping)), None, Precedence::None),
+        TokenType::LeftParen => Rule::new(Some(Parser::grouping), Some(Parser::call), Precedence::Call),
         TokenType::RightParen => Rule::new(None, None, Precedence::None),
         TokenType::LeftBrace => Rule::new(None, None, Precedence::None),
         TokenType::RightBrace => Rule::new(None, None, Precedence::None),
+        TokenType::LeftBracket => Rule::new(Some(Parser::list), Some(Parser::index), Precedence::Call),
+        TokenType::RightBracket => Rule::new(None, None, Precedence::None),
         TokenType::Comma => Rule::new(None, None, Precedence::None),
-        TokenType::Dot => Rule::new(None, None, Precedence::None),
-        TokenType::Minus => Rule::new(Some(Box::new(Parser::<'a>::unary)), Some(Box::new(Parser::<'a>::binary)), Precedence::Term),
-        TokenType::Plus => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Term),
+        TokenType::Dot => Rule::new(None, Some(Parser::dot), Precedence::Call),
+        TokenType::DotDot => Rule::new(None, Some(Parser::binary), Precedence::Range),
+        TokenType::DotDotEqual => Rule::new(None, Some(Parser::binary), Precedence::Range),
+        TokenType::DotDotDot => Rule::new(None, None, Precedence::None),
+        TokenType::QuestionQuestion => Rule::new(None, Some(Parser::coalesce), Precedence::Coalesce),
+        TokenType::QuestionDot => Rule::new(None, Some(Parser::optional_dot), Precedence::Call),
+        TokenType::Minus => Rule::new(Some(Parser::unary), Some(Parser::binary), Precedence::Term),
+        TokenType::Plus => Rule::new(None, Some(Parser::binary), Precedence::Term),
         TokenType::Semicolon => Rule::new(None, None, Precedence::None),
-        TokenType::Slash => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Factor),
-        TokenType::Star => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Factor),
-        TokenType::Bang => Rule::new(Some(Box::new(Parser::<'a>::unary)), None, Precedence::None),
-        TokenType::BangEqual => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Equality),
+        TokenType::Underscore => Rule::new(Some(Parser::variable), None, Precedence::None),
+        TokenType::Slash => Rule::new(None, Some(Parser::binary), Precedence::Factor),
+        TokenType::Star => Rule::new(None, Some(Parser::binary), Precedence::Factor),
+        TokenType::Percent => Rule::new(None, Some(Parser::binary), Precedence::Factor),
+        TokenType::StarStar => Rule::new(None, Some(Parser::binary), Precedence::Power),
+        TokenType::Caret => Rule::new(None, Some(Parser::binary), Precedence::Power),
+        TokenType::Bang => Rule::new(Some(Parser::unary), None, Precedence::None),
+        TokenType::BangEqual => Rule::new(None, Some(Parser::binary), Precedence::Equality),
         TokenType::Equal => Rule::new(None, None, Precedence::None),
-        TokenType::EqualEqual => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Equality),
-        TokenType::Greater => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Comparison),
-        TokenType::Less => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Comparison),
-        TokenType::GreaterEqual => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Comparison),
-        TokenType::LessEqual => Rule::new(None, Some(Box::new(Parser::<'a>::binary)), Precedence::Comparison),
-        TokenType::Identifier => Rule::new(None, None, Precedence::None),
-        TokenType::String => Rule::new(Some(Box::new(Parser::<'a>::string)), None, Precedence::None),
-        TokenType::Number => Rule::new(Some(Box::new(Parser::<'a>::number)), None, Precedence::None),
-        TokenType::And => Rule::new(None, None, Precedence::None),
+        TokenType::EqualEqual => Rule::new(None, Some(Parser::binary), Precedence::Equality),
+        TokenType::EqualGreater => Rule::new(None, None, Precedence::None),
+        TokenType::Greater => Rule::new(None, Some(Parser::binary), Precedence::Comparison),
+        TokenType::Less => Rule::new(None, Some(Parser::binary), Precedence::Comparison),
+        TokenType::GreaterEqual => Rule::new(None, Some(Parser::binary), Precedence::Comparison),
+        TokenType::LessEqual => Rule::new(None, Some(Parser::binary), Precedence::Comparison),
+        TokenType::Identifier => Rule::new(Some(Parser::variable), None, Precedence::None),
+        TokenType::String => Rule::new(Some(Parser::string), None, Precedence::None),
+        TokenType::Number => Rule::new(Some(Parser::number), None, Precedence::None),
+        TokenType::And => Rule::new(None, Some(Parser::and), Precedence::And),
+        TokenType::Catch => Rule::new(None, None, Precedence::None),
         TokenType::Class => Rule::new(None, None, Precedence::None),
+        TokenType::Const => Rule::new(None, None, Precedence::None),
+        TokenType::Continue => Rule::new(None, None, Precedence::None),
         TokenType::Else => Rule::new(None, None, Precedence::None),
-        TokenType::False => Rule::new(Some(Box::new(Parser::<'a>::literal)), None, Precedence::None),
+        TokenType::False => Rule::new(Some(Parser::literal), None, Precedence::None),
         TokenType::For => Rule::new(None, None, Precedence::None),
-        TokenType::Fun => Rule::new(None, None, Precedence::None),
+        TokenType::Fun => Rule::new(Some(Parser::lambda), None, Precedence::None),
         TokenType::If => Rule::new(None, None, Precedence::None),
-        TokenType::Nil => Rule::new(Some(Box::new(Parser::<'a>::literal)), None, Precedence::None),
-        TokenType::Or => Rule::new(None, None, Precedence::None),
+        TokenType::Import => Rule::new(None, None, Precedence::None),
+        TokenType::Match => Rule::new(Some(Parser::match_expression), None, Precedence::None),
+        TokenType::Nil => Rule::new(Some(Parser::literal), None, Precedence::None),
+        TokenType::Or => Rule::new(None, Some(Parser::or), Precedence::Or),
         TokenType::Print => Rule::new(None, None, Precedence::None),
         TokenType::Return => Rule::new(None, None, Precedence::None),
         TokenType::Super => Rule::new(None, None, Precedence::None),
-        TokenType::This => Rule::new(None, None, Precedence::None),
-        TokenType::True => Rule::new(Some(Box::new(Parser::<'a>::literal)), None, Precedence::None),
+        TokenType::This => Rule::new(Some(Parser::this), None, Precedence::None),
+        TokenType::Throw => Rule::new(None, None, Precedence::None),
+        TokenType::True => Rule::new(Some(Parser::literal), None, Precedence::None),
+        TokenType::Try => Rule::new(None, None, Precedence::None),
         TokenType::Var => Rule::new(None, None, Precedence::None),
         TokenType::While => Rule::new(None, None, Precedence::None),
         TokenType::EOF => Rule::new(None, None, Precedence::None),
@@ -102,257 +204,2245 @@ fn get_rule<'a, 'b>(token_type: TokenType) -> Rule<'a, 'b> {
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source: &'a str, chunk: &'a mut Chunk) -> Self {
+    pub fn new(source: &'a str, heap: &'a mut ObjHeap) -> Self {
         Parser {
             scanner: Scanner::new(source),
-            chunk,
+            heap,
             previous: None,
             current: None,
             had_error: false,
             panic_mode: false,
+            errors: Vec::new(),
+            diagnostics: Vec::new(),
+            last_string_constant: None,
+            last_constant: None,
+            last_comparison: None,
+            last_landing: None,
+            last_call: None,
+            last_literal: None,
+            always_exits: false,
+            compiler: FunctionState::new(FunctionType::Script, None),
+            class_depth: 0,
+            const_globals: HashSet::new(),
+            global_slots: HashMap::new(),
         }
     }
 
-    pub fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment)
+    // Compiles declarations up to `end`. Once one of them always exits, the
+    // rest can't run, so they're only compiled for their errors and then dropped
+    fn declarations(&mut self, end: TokenType) {
+        let mut exit = None;
+        let mut warned = false;
+        while !self.check(end) && !self.check(TokenType::EOF) {
+            // Only the first statement past the exit is pointed out
+            if exit.is_some() && !warned {
+                let span = self.get_current().span;
+                self.warn(Diagnostic { span, severity: Severity::Warning, message: "Unreachable code.".to_string(), location: String::new() });
+                warned = true;
+            }
+            self.declaration();
+            match exit {
+                Some(len) => self.truncate(len),
+                None if self.always_exits => exit = Some(self.chunk_len()),
+                None => {},
+            }
+        }
+        self.always_exits = exit.is_some();
     }
 
-    pub fn grouping(&mut self) {
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    // Compiles with `compile`, keeping the code only if `live`
+    fn compile_unless_dead(&mut self, live: bool, compile: fn(&mut Self)) {
+        let len = self.chunk_len();
+        compile(self);
+        if !live {
+            self.truncate(len);
+            self.always_exits = false;
+        }
     }
 
-    pub fn binary(&mut self) {
-        let operator_type = self.previous().token_type;
+    // How much code and how many constants the chunk has, to truncate back to
+    fn chunk_len(&self) -> (usize, usize) {
+        (self.compiler.chunk.code.len(), self.compiler.chunk.constants().len())
+    }
 
-        let Rule { precedence, .. } = get_rule(operator_type);
-        self.parse_precedence(precedence + 1);
+    // Drops the code and constants past `len`
+    fn truncate(&mut self, (len, constants): (usize, usize)) {
+        self.compiler.chunk.truncate(len, constants);
+        self.forget_emitted();
+    }
 
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            _ => {}
-        }
+    // Whatever the peephole trackers pointed at may be gone, or be in another
+    // function's chunk, and code emitted next could land on the same offsets
+    fn forget_emitted(&mut self) {
+        self.last_constant = None;
+        self.last_comparison = None;
+        self.last_landing = None;
+        self.last_call = None;
+        self.last_literal = None;
+        self.last_string_constant = None;
     }
 
-    pub fn unary(&mut self) {
-        let operator_type = self.previous().token_type;
+    pub fn declaration(&mut self) {
+        self.always_exits = false;
 
-        self.parse_precedence(Precedence::Unary);
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration();
+        } else {
+            self.statement();
+        }
 
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not),
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            _ => {}
+        if self.panic_mode {
+            self.synchronize();
         }
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
-        match get_rule(self.previous().token_type) {
-            Rule { prefix: Some(prefix_rule), .. } => {
-                prefix_rule(self);
+    // Skips to the next likely statement boundary after an error, so the rest of
+    // the source still gets compiled and its errors reported
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
 
-                while precedence <= get_rule(self.get_current().token_type).precedence {
-                    self.advance();
-                    if let Rule { infix: Some(infix_rule), .. } = get_rule(self.previous().token_type) {
-                        infix_rule(self);
-                    }
-                }
-            },
-            _ => self.error("Expect expression."),
+        while !self.check(TokenType::EOF) {
+            if self.previous.as_ref().is_some_and(|t| t.token_type == TokenType::Semicolon) {
+                return;
+            }
+            match self.get_current().token_type {
+                TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Const |
+                TokenType::Import | TokenType::For | TokenType::If | TokenType::While |
+                TokenType::Print | TokenType::Return | TokenType::Try | TokenType::Throw => return,
+                _ => self.advance(),
+            }
         }
     }
 
-    pub fn string(&mut self) {
-        let p = self.previous().literal;
-        self.emit_constant(
-            Value::Object(
-                // Truncate the quotation marks
-                ObjectType::Str(p[1..p.len()-1].to_string())
-            )
-        );
+    fn class_declaration(&mut self) {
+        let named = self.consume_name("Expect class name.");
+        let name = self.previous_name();
+        let name_constant = self.identifier_constant(name.name);
+        if named {
+            self.declare_variable(name);
+        }
+
+        self.emit_operand(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        // Methods are added to the class while it sits on the stack
+        self.class_depth += 1;
+        self.named_variable(name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop);
+        self.class_depth -= 1;
     }
 
-    pub fn number(&mut self) {
-        self.emit_constant(
-            self.previous()
-                .literal
-                .parse()
-                .expect("Expected number")
-        )
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous().literal;
+        let constant = self.identifier_constant(name);
+
+        if self.check(TokenType::LeftBrace) {
+            if name == "init" {
+                self.error("An initializer can't be a getter.");
+            }
+            self.function(FunctionType::Getter, name);
+            self.emit_operand(OpCode::Getter, constant);
+            return;
+        }
+
+        let function_type = if name == "init" { FunctionType::Initializer } else { FunctionType::Method };
+        self.function(function_type, name);
+        self.emit_operand(OpCode::Method, constant);
     }
 
-    pub fn literal(&mut self) {
-        match self.previous().token_type {
-            TokenType::Nil => self.emit_byte(OpCode::Nil),
-            TokenType::True => self.emit_byte(OpCode::True),
-            TokenType::False => self.emit_byte(OpCode::False),
-            _ => {},
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // Initialized straight away, so the body can call itself
+        self.mark_initialized();
+        if self.compiler.scope_depth > 0 {
+            if let Some(local) = self.compiler.locals.last_mut() {
+                local.is_function = true;
+            }
         }
+        self.function(FunctionType::Function, self.previous().literal);
+        self.define_variable(global);
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant.into(), constant);
+    // Compiles the parameters and body into a new function object, which is
+    // left on the stack as a constant
+    fn function(&mut self, function_type: FunctionType, name: &'a str) {
+        self.begin_function(function_type, Some(name));
+        let always_exits = std::mem::take(&mut self.always_exits);
+        self.forget_emitted();
+
+        if function_type != FunctionType::Getter {
+            self.parameter_list();
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        if !self.always_exits {
+            self.emit_return();
+        }
+        let function = self.end_function().into_function();
+        self.always_exits = always_exits;
+        self.forget_emitted();
+
+        let value = Value::Object(self.heap.alloc(ObjectType::Function(function)));
+        self.emit_constant(value);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        match self.chunk.add_constant(value).try_into() {
-            Ok(c) => c,
-            Err(_) => {
-                self.error("Too many constants in one chunk.");
-                0
+    fn parameter_list(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler.arity += 1;
+                if self.compiler.arity > MAX_ARGS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                self.compiler.variadic = self.match_token(TokenType::DotDotDot);
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                self.mark_used();
+
+                if self.compiler.variadic && self.check(TokenType::Comma) {
+                    self.error_at_current("A rest parameter must come last.");
+                }
+                if !self.match_token(TokenType::Comma) { break; }
             }
         }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
     }
 
-    fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return);
+    fn var_declaration(&mut self) {
+        if self.match_token(TokenType::LeftParen) {
+            return self.destructuring_declaration();
+        }
+
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
     }
 
-    fn emit_byte<U: Into<u8>>(&mut self, byte: U) {
-        let line = self.previous.as_ref().unwrap().line;
-        self.chunk.write(byte, line);
+    fn destructuring_declaration(&mut self) {
+        let mut globals = Vec::new();
+        loop {
+            if globals.len() == MAX_ARGS {
+                self.error_at_current("Can't unpack more than 255 values.");
+            }
+            globals.push(self.parse_variable("Expect variable name."));
+            if !self.match_token(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after variable names.");
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+        self.define_unpacked(globals);
     }
 
-    fn emit_bytes<U: Into<u8>>(&mut self, byte1: U, byte2: U) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.previous().literal;
+        if self.compiler.scope_depth > 0 {
+            if let Some(local) = self.compiler.locals.last_mut() {
+                local.is_const = true;
+            }
+        } else {
+            self.const_globals.insert(name);
+        }
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+
+        self.define_variable(global);
     }
 
-    pub fn consume(&mut self, token_type: TokenType, message: &'a str) {
-        if self.current.as_ref().map_or(false, |t| t.token_type == token_type) {
+    // `import "path";` copies all of the module's globals in, while
+    // `import "path" as name;` binds the module itself to name
+    fn import_declaration(&mut self) {
+        self.consume(TokenType::String, "Expect module path.");
+        let literal = self.previous().literal;
+        let path = self.identifier_constant(&literal[1..literal.len() - 1]);
+
+        // `as` is only special here, so it stays usable as a name elsewhere
+        let alias = self.check(TokenType::Identifier) && self.get_current().literal == "as";
+        let global = if alias {
             self.advance();
-            return;
+            Some(self.parse_variable("Expect module name after 'as'."))
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+
+        self.emit_operand(OpCode::Import, path);
+        self.emit_byte(OpCode::Pop);
+        match global {
+            Some(global) => self.define_variable(global),
+            None => self.emit_byte(OpCode::ImportAll),
         }
+    }
 
-        self.error_at_current(message);
+    // Returns the name constant for globals; locals don't need one
+    fn parse_variable(&mut self, message: &str) -> usize {
+        if !self.consume_name(message) { return 0; }
+        self.declare_variable(self.previous_name())
     }
 
-    pub fn previous(&self) -> &Token {
-        self.previous.as_ref().expect("Expected previous token")
+    // Whether a name was consumed. Without one there's nothing to declare, and
+    // the token before it mustn't be taken for one
+    fn consume_name(&mut self, message: &str) -> bool {
+        let named = self.check(TokenType::Identifier);
+        self.consume(TokenType::Identifier, message);
+        named
     }
 
-    pub fn get_current(&self) -> &Token {
-        self.current.as_ref().expect("Expected previous token")
+    fn previous_name(&self) -> Identifier<'a> {
+        Identifier { name: self.previous().literal, span: self.previous().span }
     }
 
-    pub fn advance(&mut self) {
-        self.previous = self.current.clone();
+    pub fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
 
-        loop {
-            match self.scanner.scan_token() {
-                Ok(token) =>  {
-                    self.current = Some(token);
-                    break;
-                },
-                Err(_e) => {
-                    let message = self.current.as_ref().map_or("", |t| t.literal);
-                    self.error_at_current(message);
+    fn block(&mut self) {
+        self.declarations(TokenType::RightBrace);
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        self.always_exits = true;
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+
+            // A try block's handler belongs to this frame, so the call has to return here
+            if self.compiler.try_depth == 0 && self.compiler.function_type != FunctionType::Script {
+                if let Some(start) = self.fusable(self.last_call, 2) {
+                    self.compiler.chunk.code[start] = OpCode::TailCall.into();
                 }
             }
+            self.emit_byte(OpCode::Return);
         }
     }
 
-    fn error_at_current(&mut self, message: &'a str) {
-        // TODO Need to handle 'None'
-        self.error_at(&self.current.clone().unwrap(), message)
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        // A literal condition picks the branch at compile time
+        if let Some(condition) = self.literal_at_end() {
+            let taken = !condition.value.is_falsey();
+            self.truncate((condition.start, condition.constants));
+
+            self.compile_unless_dead(taken, Self::statement);
+            let then_exits = std::mem::take(&mut self.always_exits);
+            if self.match_token(TokenType::Else) {
+                self.compile_unless_dead(!taken, Self::statement);
+            }
+            self.always_exits = if taken { then_exits } else { self.always_exits };
+            return;
+        }
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+        let then_exits = std::mem::take(&mut self.always_exits);
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.always_exits &= then_exits;
+        self.patch_jump(else_jump);
     }
 
-    fn error(&mut self, message: &'a str) {
-        // TODO Need to handle 'None'
-        self.error_at(&self.previous.clone().unwrap(), message)
+    fn while_statement(&mut self) {
+        let start = self.chunk_len();
+        let loop_start = start.0;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let never_runs = self.literal_at_end().is_some_and(|c| c.value.is_falsey());
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.loop_body(loop_start);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+
+        if never_runs {
+            self.truncate(start);
+        }
     }
 
-    fn error_at(&mut self, token: &Token, message: &'a str) {
-        if self.panic_mode { return; }
-        self.panic_mode = true;
+    // Desugared into the same jumps as a while loop. The increment clause comes
+    // before the body in the bytecode, so the body jumps back to it and it then
+    // loops back to the condition
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
-        eprint!("[line {}] Error", token.line);
+        if self.check(TokenType::Identifier) && self.next_is_in() {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
 
-        if token.token_type == TokenType::EOF {
-            eprint!(" at end");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
         } else {
-            eprint!(" at '{}'", token.literal);
+            self.expression_statement();
         }
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+        let mut loop_start = self.compiler.chunk.code.len();
+
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop);
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.compiler.chunk.code.len();
+
+            self.expression();
+            self.emit_byte(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(loop_start);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+
+        self.end_scope();
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    fn for_in_statement(&mut self) {
+        self.advance();
+        let name = self.previous_name();
+        self.advance();
 
-    #[test]
-    fn test_basic_arithmetic() {
-        assert_expr("1 + 1", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Add.into()
-        ]);
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after loop collection.");
+        let for_in = self.begin_for_in();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_for_in(for_in);
+        self.always_exits = false;
+    }
 
-        assert_expr("2 * 2", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Multiply.into()
-        ]);
+    // `in` is only a keyword here, so it has to be told apart from a for loop's
+    // initializer expression by looking past the loop variable
+    fn next_is_in(&self) -> bool {
+        self.scanner.clone()
+                    .scan_token()
+                    .is_ok_and(|t| t.token_type == TokenType::Identifier && t.literal == "in")
+    }
 
-        assert_expr("3 / 3", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Divide.into()
-        ]);
+    // A loop's body may not run at all, so the loop never counts as exiting
+    fn loop_body(&mut self, start: usize) {
+        self.begin_loop(start);
+        self.statement();
+        self.compiler.loops.pop();
+        self.always_exits = false;
+    }
 
-        assert_expr("4 - 4", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Subtract.into()
-        ]);
+    fn continue_statement(&mut self) {
+        let keyword = self.previous().span;
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        if !self.emit_continue() {
+            self.error_at(keyword, "continue", "Can't use 'continue' outside of a loop.");
+            return;
+        }
+        self.always_exits = true;
     }
 
-    #[test]
-    fn test_grouping() {
-        assert_expr("(1 + 1) * 2", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Add.into(),
-            OpCode::Constant.into(), 0x02,
-            OpCode::Multiply.into(),
-        ]);
+    // The handler is registered for the try block only. When something is thrown
+    // the VM unwinds to the depth the stack had at OP_TRY_BEGIN, pushes the
+    // thrown value and jumps to the catch clause, which binds it as a local
+    fn try_statement(&mut self) {
+        let handler_jump = self.emit_jump(OpCode::TryBegin);
+        self.compiler.try_depth += 1;
 
-        assert_expr("(1 + 1) * (2 - 1) / 4", vec![
-            OpCode::Constant.into(), 0x00,
-            OpCode::Constant.into(), 0x01,
-            OpCode::Add.into(),
-            OpCode::Constant.into(), 0x02,
-            OpCode::Constant.into(), 0x03,
-            OpCode::Subtract.into(),
-            OpCode::Multiply.into(),
-            OpCode::Constant.into(), 0x04,
-            OpCode::Divide.into(),
-        ]);
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        self.compiler.try_depth -= 1;
+        self.emit_byte(OpCode::TryEnd);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(handler_jump);
+
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.begin_scope();
+        if self.consume_name("Expect exception variable name.") {
+            self.declare_variable(self.previous_name());
+            self.mark_initialized();
+            self.mark_used();
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after exception variable.");
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(end_jump);
+        self.always_exits = false;
     }
 
-    fn assert_expr(source: &str, code: Vec<u8>) {
-        let mut chunk = Chunk::default();
-        let mut p = Parser::new(source, &mut chunk);
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(OpCode::Throw);
+        self.always_exits = true;
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop);
+    }
+
+    pub fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    pub fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    pub fn binary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous().token_type;
+        if operator_type == TokenType::Plus {
+            return self.sum();
+        }
+
+        // Exponentiation is right-associative, so its right operand takes in
+        // further operators of the same precedence
+        let left = self.literal_at_end();
+        let Rule { precedence, .. } = get_rule(operator_type);
+        if precedence == Precedence::Power {
+            self.parse_precedence(precedence);
+        } else {
+            self.parse_precedence(precedence + 1);
+        }
+
+        if self.fold_binary(operator_type, left) {
+            return;
+        }
+        match operator_type {
+            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
+            TokenType::EqualEqual => self.emit_comparison(OpCode::Equal),
+            TokenType::Greater => self.emit_comparison(OpCode::Greater),
+            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
+            TokenType::Less => self.emit_comparison(OpCode::Less),
+            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
+            TokenType::Minus => self.emit_byte(OpCode::Subtract),
+            TokenType::Star => self.emit_byte(OpCode::Multiply),
+            TokenType::Slash => self.emit_byte(OpCode::Divide),
+            TokenType::Percent => self.emit_byte(OpCode::Modulo),
+            TokenType::StarStar | TokenType::Caret => self.emit_byte(OpCode::Power),
+            TokenType::DotDot => self.emit_byte(OpCode::Range),
+            TokenType::DotDotEqual => self.emit_byte(OpCode::RangeInclusive),
+            _ => {}
+        }
+    }
+
+    // A chain like `a + "b" + c` can only succeed if every operand is a string, so
+    // when one of them is a literal the chain is built with a single OP_CONCAT_N
+    fn sum(&mut self) {
+        let mut has_string = self.ends_with_string_literal();
+        let mut adds = Vec::new();
+        let mut operands = 1;
+
+        loop {
+            let left = self.literal_at_end();
+            self.parse_precedence(Precedence::Term + 1);
+            operands += 1;
+
+            // Two literals fold into one. Otherwise a literal on the right folds
+            // into OP_ADD_CONSTANT, unless the chain has a string literal so far
+            // and may still become OP_CONCAT_N
+            has_string |= self.ends_with_string_literal();
+            if self.fold_binary(TokenType::Plus, left) {
+                operands -= 1;
+            } else if let Some(start) = self.fusable(self.last_constant, 2).filter(|_| !has_string) {
+                self.compiler.chunk.code[start] = OpCode::AddConstant.into();
+                self.last_constant = None;
+                self.last_literal = None;
+            } else {
+                adds.push(self.compiler.chunk.code.len());
+                self.emit_byte(OpCode::Add);
+            }
+
+            if self.get_current().token_type != TokenType::Plus { break; }
+            self.advance();
+        }
+
+        if has_string && operands > 2 && adds.len() + 1 == operands {
+            if let Ok(count) = u8::try_from(operands) {
+                for &offset in adds.iter().rev() {
+                    self.compiler.chunk.remove_byte(offset);
+                }
+                self.emit_bytes(OpCode::ConcatN.into(), count);
+                self.last_literal = None;
+            }
+        }
+    }
+
+    fn ends_with_string_literal(&self) -> bool {
+        self.last_string_constant == Some(self.compiler.chunk.code.len())
+    }
+
+    pub fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.last_call = Some(self.compiler.chunk.code.len());
+        self.emit_bytes(OpCode::Call.into(), arg_count);
+    }
+
+    pub fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous().literal;
+        let name_constant = self.identifier_constant(name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_operand(OpCode::SetProperty, name_constant);
+        } else if self.match_token(TokenType::LeftParen) {
+            // OP_INVOKE only has room for a one byte name, so past that the
+            // method is fetched and then called, as a parenthesized one is
+            match u8::try_from(name_constant) {
+                Ok(constant) => {
+                    let arg_count = self.argument_list();
+                    self.emit_bytes(OpCode::Invoke.into(), constant);
+                    self.emit_byte(arg_count);
+                },
+                Err(_) => {
+                    self.emit_operand(OpCode::GetProperty, name_constant);
+                    self.call(false);
+                },
+            }
+        } else {
+            self.emit_operand(OpCode::GetProperty, name_constant);
+        }
+    }
+
+    pub fn list(&mut self, _can_assign: bool) {
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if count == MAX_ARGS {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                count += 1;
+
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(OpCode::BuildList.into(), count.min(MAX_ARGS) as u8);
+    }
+
+    pub fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::IndexSet);
+        } else {
+            self.emit_byte(OpCode::IndexGet);
+        }
+    }
+
+    // A nil receiver skips the access and the rest of the chain after it, so
+    // `a?.b.c()` is nil when `a` is. The result can't be assigned to
+    pub fn optional_dot(&mut self, _can_assign: bool) {
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil);
+        self.dot(false);
+
+        while get_rule(self.get_current().token_type).precedence >= Precedence::Call {
+            self.advance();
+            if let Rule { infix: Some(infix_rule), .. } = get_rule(self.previous().token_type) {
+                infix_rule(self, false);
+            }
+        }
+        self.patch_jump(nil_jump);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if count == MAX_ARGS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                count += 1;
+
+                if !self.match_token(TokenType::Comma) { break; }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        count.min(MAX_ARGS) as u8
+    }
+
+    // If the left operand is falsey it's the result, and the right is skipped
+    pub fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    pub fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::Or);
+
+        self.patch_jump(end_jump);
+    }
+
+    // The left operand is the result unless it's nil, in which case the right is
+    pub fn coalesce(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfNotNil);
+
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::Coalesce + 1);
+
+        self.patch_jump(end_jump);
+    }
+
+    // Each arm compares a copy of the value, so the value itself is only popped
+    // once an arm has been chosen. When no arm matches the result is nil
+    pub fn match_expression(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::LeftBrace, "Expect '{' after match value.");
+
+        let mut end_jumps = Vec::new();
+        let mut has_wildcard = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if has_wildcard {
+                self.error_at_current("The '_' arm must come last.");
+            }
+
+            let next_arm = if self.match_token(TokenType::Underscore) {
+                has_wildcard = true;
+                None
+            } else {
+                self.emit_byte(OpCode::Dup);
+                self.pattern();
+                self.emit_comparison(OpCode::Equal);
+                let jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                Some(jump)
+            };
+            self.consume(TokenType::EqualGreater, "Expect '=>' after match pattern.");
+
+            self.emit_byte(OpCode::Pop);
+            self.expression();
+
+            if let Some(next_arm) = next_arm {
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_arm);
+                self.emit_byte(OpCode::Pop);
+            }
+
+            if !self.match_token(TokenType::Comma) { break; }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        if !has_wildcard {
+            self.emit_bytes(OpCode::Pop, OpCode::Nil);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn pattern(&mut self) {
+        if self.match_token(TokenType::Minus) {
+            self.consume(TokenType::Number, "Expect a number after '-' in pattern.");
+            self.number(false);
+            self.emit_byte(OpCode::Negate);
+            return;
+        }
+
+        self.advance();
+        match self.previous().token_type {
+            TokenType::Number => self.number(false),
+            TokenType::String => self.string(false),
+            TokenType::True | TokenType::False | TokenType::Nil => self.literal(false),
+            _ => self.error("Expect a literal pattern or '_'."),
+        }
+    }
+
+    pub fn unary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous().token_type;
+
+        self.parse_precedence(Precedence::Unary);
+
+        // Only what the VM would compute without an error is folded
+        if let Some(operand) = self.literal_at_end() {
+            let folded = match (operator_type, operand.value) {
+                (TokenType::Bang, Value::Bool(b)) => Some(Value::Bool(!b)),
+                (TokenType::Bang, Value::Nil) => Some(Value::Bool(true)),
+                (TokenType::Minus, value) => -value,
+                _ => None,
+            };
+            if let Some(value) = folded {
+                return self.replace_literals(operand, value);
+            }
+        }
+
+        match operator_type {
+            TokenType::Bang => self.emit_byte(OpCode::Not),
+            TokenType::Minus => self.emit_byte(OpCode::Negate),
+            _ => {}
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        match get_rule(self.previous().token_type) {
+            Rule { prefix: Some(prefix_rule), .. } => {
+                let can_assign = precedence <= Precedence::Assignment;
+                prefix_rule(self, can_assign);
+
+                while precedence <= get_rule(self.get_current().token_type).precedence {
+                    self.advance();
+                    if let Rule { infix: Some(infix_rule), .. } = get_rule(self.previous().token_type) {
+                        infix_rule(self, can_assign);
+                    }
+                }
+
+                // Nothing consumed the '=', so what came before it can't be assigned to
+                if can_assign && self.match_token(TokenType::Equal) {
+                    self.error("Invalid assignment target.");
+                }
+            },
+            _ => self.error("Expect expression."),
+        }
+    }
+
+    pub fn string(&mut self, _can_assign: bool) {
+        let p = self.previous().literal;
+        // Truncate the quotation marks
+        let value = self.heap.alloc_str(p[1..p.len()-1].to_string());
+        self.emit_literal(value);
+    }
+
+    // An anonymous function. At the start of a statement `fun` always begins a
+    // declaration, so a lambda called on the spot needs wrapping in parentheses
+    pub fn lambda(&mut self, _can_assign: bool) {
+        self.function(FunctionType::Function, "lambda");
+    }
+
+    pub fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous_name(), can_assign);
+    }
+
+    pub fn this(&mut self, _can_assign: bool) {
+        if self.class_depth == 0 {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+
+    fn named_variable(&mut self, name: Identifier<'a>, can_assign: bool) {
+        let local = self.resolve_local(name);
+        let (set_op, arg, is_const) = match local {
+            Some(slot) => (OpCode::SetLocal, slot, self.compiler.locals[slot].is_const),
+            None => (OpCode::SetGlobal, self.identifier_constant(name.name), self.const_globals.contains(name.name)),
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            if is_const {
+                self.error_at(name.span, name.name, &format!("Can't assign to constant '{}'.", name.name));
+            }
+            self.expression();
+            self.emit_operand(set_op, arg);
+            return;
+        }
+
+        match local {
+            Some(slot) => self.emit_get_local(slot),
+            None => self.emit_get_global(name.name, arg),
+        }
+    }
+
+    pub fn number(&mut self, _can_assign: bool) {
+        match self.previous().literal.parse() {
+            Ok(value) => self.emit_literal(value),
+            Err(_) => self.error("Invalid number literal."),
+        }
+    }
+
+    pub fn literal(&mut self, _can_assign: bool) {
+        match self.previous().token_type {
+            TokenType::Nil => self.emit_literal(Value::Nil),
+            TokenType::True => self.emit_literal(Value::Bool(true)),
+            TokenType::False => self.emit_literal(Value::Bool(false)),
+            _ => {},
+        }
+    }
+
+    fn emit_literal(&mut self, value: Value) {
+        let start = self.compiler.chunk.code.len();
+        let constants = self.compiler.chunk.constants().len();
+        match value {
+            Value::Nil => self.emit_byte(OpCode::Nil),
+            Value::Bool(true) => self.emit_byte(OpCode::True),
+            Value::Bool(false) => self.emit_byte(OpCode::False),
+            _ => self.emit_constant(value),
+        }
+
+        let end = self.compiler.chunk.code.len();
+        if self.heap.as_str(&value).is_some() {
+            self.last_string_constant = Some(end);
+        }
+        self.last_literal = Some(Literal { start, end, constants, value });
+    }
+
+    // The literal that the code emitted so far ends with, if nothing can jump
+    // past it to the end
+    fn literal_at_end(&self) -> Option<Literal> {
+        let end = self.compiler.chunk.code.len();
+        self.last_literal.filter(|l| l.end == end && self.last_landing != Some(end))
+    }
+
+    // Folds `left <operator> right` when both operands are literals and the VM
+    // would compute the result without an error, returning whether it did
+    fn fold_binary(&mut self, operator: TokenType, left: Option<Literal>) -> bool {
+        let Some((left, right)) = left.zip(self.literal_at_end()).filter(|(l, r)| l.end == r.start) else {
+            return false;
+        };
+        let (a, b) = (left.value, right.value);
+        let compare = |ordering| self.heap.compare(&a, &b) == Some(ordering);
+
+        let folded = match operator {
+            TokenType::Plus => match (self.heap.as_str(&a), self.heap.as_str(&b)) {
+                (Some(s1), Some(s2)) => {
+                    let result = [s1, s2].concat();
+                    Some(self.heap.alloc_str(result))
+                },
+                (None, None) => a + b,
+                _ => None,
+            },
+            TokenType::Minus => a - b,
+            TokenType::Star => a * b,
+            // Division by zero depends on the VM's options, so it's left to run
+            TokenType::Slash if b != Value::Number(0.0) => a / b,
+            TokenType::Percent if b != Value::Number(0.0) => a % b,
+            TokenType::StarStar | TokenType::Caret => a.pow(b),
+            TokenType::EqualEqual => Some(Value::Bool(self.heap.equal(&a, &b))),
+            TokenType::BangEqual => Some(Value::Bool(!self.heap.equal(&a, &b))),
+            TokenType::Greater => Some(Value::Bool(compare(Ordering::Greater))),
+            TokenType::GreaterEqual => Some(Value::Bool(!compare(Ordering::Less))),
+            TokenType::Less => Some(Value::Bool(compare(Ordering::Less))),
+            TokenType::LessEqual => Some(Value::Bool(!compare(Ordering::Greater))),
+            _ => None,
+        };
+
+        match folded {
+            Some(value) => {
+                self.replace_literals(left, value);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Swaps everything from `first` on for a single literal
+    fn replace_literals(&mut self, first: Literal, value: Value) {
+        self.truncate((first.start, first.constants));
+        self.emit_literal(value);
+    }
+
+    fn emit_comparison(&mut self, op: OpCode) {
+        self.last_comparison = Some(self.compiler.chunk.code.len());
+        self.emit_byte(op);
+    }
+
+    // The start of an instruction `width` bytes long if it's the last one
+    // emitted and can be rewritten in place. A jump landing just after it would
+    // otherwise skip the fused half
+    fn fusable(&self, start: Option<usize>, width: usize) -> Option<usize> {
+        let end = self.compiler.chunk.code.len();
+        start.filter(|&start| start + width == end && self.last_landing != Some(end))
+    }
+
+    pub fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.as_ref().is_some_and(|t| t.token_type == token_type)
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) { return false; }
+        self.advance();
+        true
+    }
+
+    pub fn previous(&self) -> &Token<'a> {
+        self.previous.as_ref().expect("Expected previous token")
+    }
+
+    pub fn get_current(&self) -> &Token<'a> {
+        self.current.as_ref().expect("Expected previous token")
+    }
+
+    pub fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            match self.scanner.scan_token() {
+                Ok(token) =>  {
+                    self.current = Some(token);
+                    break;
+                },
+                Err(e) => {
+                    // The offending input never becomes a token, so there's nothing to point at
+                    let span = self.scanner.span();
+                    self.report(span, String::new(), &e.to_string());
+                }
+            }
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        // TODO Need to handle 'None'
+        self.error_at_token(&self.current.clone().unwrap(), message)
+    }
+
+    fn error_at_token(&mut self, token: &Token, message: &str) {
+        let location = if token.token_type == TokenType::EOF {
+            " at end".to_string()
+        } else {
+            format!(" at '{}'", token.literal)
+        };
+
+        self.report(token.span, location, message);
+    }
+
+    fn report(&mut self, span: Span, location: String, message: &str) {
+        if self.panic_mode { return; }
+        self.panic_mode = true;
+
+        let error = CompileError { span, location, message: message.to_string() };
+        self.diagnostics.push(Diagnostic::from(&error));
+        self.errors.push(error);
+        self.had_error = true;
+    }
+}
+
+impl<'a> CodeGen<'a> for Parser<'a> {
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        &mut self.compiler
+    }
+
+    fn heap(&mut self) -> &mut ObjHeap {
+        self.heap
+    }
+
+    fn const_globals(&mut self) -> &mut HashSet<&'a str> {
+        &mut self.const_globals
+    }
+
+    fn global_slots(&mut self) -> &mut HashMap<String, usize> {
+        &mut self.global_slots
+    }
+
+    fn span(&self) -> Span {
+        self.previous().span
+    }
+
+    fn error(&mut self, message: &str) {
+        // TODO Need to handle 'None'
+        self.error_at_token(&self.previous.clone().unwrap(), message)
+    }
+
+    fn error_at(&mut self, span: Span, text: &str, message: &str) {
+        self.report(span, format!(" at '{}'", text), message);
+    }
+
+    fn warn(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn constant_emitted(&mut self, start: usize) {
+        self.last_constant = Some(start);
+    }
+
+    fn jump_landed(&mut self, offset: usize) {
+        self.last_landing = Some(offset);
+    }
+
+    // A conditional jump straight after a comparison becomes OP_COMPARE_JUMP,
+    // with the comparison moved into its operand
+    fn emit_jump_op(&mut self, op: OpCode) {
+        match self.fusable(self.last_comparison, 1) {
+            Some(start) if op == OpCode::JumpIfFalse => {
+                let compare = std::mem::replace(&mut self.compiler.chunk.code[start], OpCode::CompareJump.into());
+                self.last_comparison = None;
+                self.emit_byte(compare);
+            },
+            _ => self.emit_byte(op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Severity;
+
+    #[test]
+    fn test_basic_arithmetic() {
+        assert_expr("a + 1", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+        ]);
+
+        // Only a literal right operand is folded into the add
+        assert_expr("a + -b", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::Negate.into(),
+            OpCode::Add.into(),
+        ]);
+
+        assert_expr("a * b", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::Multiply.into()
+        ]);
+
+        assert_expr("a / b", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::Divide.into()
+        ]);
+
+        assert_expr("a - b", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::Subtract.into()
+        ]);
+
+        assert_expr("a + b % c", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Modulo.into(),
+            OpCode::Add.into(),
+        ]);
+
+        // Right-associative, and tighter than unary minus
+        assert_expr("-a ** b ^ c", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Power.into(),
+            OpCode::Power.into(),
+            OpCode::Negate.into(),
+        ]);
+
+        assert_expr("a * b ** c", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Power.into(),
+            OpCode::Multiply.into(),
+        ]);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let folded = |source: &str| {
+            let mut heap = ObjHeap::default();
+            let mut p = Parser::new(source, &mut heap);
+            p.advance();
+            p.expression();
+            assert!(!p.had_error);
+            let chunk = std::mem::take(&mut p.compiler.chunk);
+            match chunk.code[..] {
+                [op, 0x00] if op == OpCode::Constant.into() => {
+                    assert_eq!(chunk.constants().len(), 1, "{}", source);
+                    Some(heap.display(&chunk.constants()[0]).to_string())
+                },
+                [op] if op == OpCode::True.into() => Some("true".to_string()),
+                [op] if op == OpCode::False.into() => Some("false".to_string()),
+                _ => None,
+            }
+        };
+
+        assert_eq!(folded("2 * 3 + 1").as_deref(), Some("7"));
+        assert_eq!(folded("-(5)").as_deref(), Some("-5"));
+        assert_eq!(folded("!false").as_deref(), Some("true"));
+        assert_eq!(folded("!!nil").as_deref(), Some("false"));
+        assert_eq!(folded("\"a\" + \"b\" + \"c\"").as_deref(), Some("\"abc\""));
+        assert_eq!(folded("2 ** 3 ^ 2").as_deref(), Some("512"));
+        assert_eq!(folded("7 / 2 - 1").as_deref(), Some("2.5"));
+        assert_eq!(folded("1 + 2 >= 3 == true").as_deref(), Some("true"));
+        assert_eq!(folded("\"ab\" < \"b\"").as_deref(), Some("true"));
+
+        // Anything the VM would raise an error for, or that isn't all literals,
+        // is left to run
+        for source in ["1 / 0", "5 % (1 - 1)", "-\"a\"", "!1", "1 + \"a\"", "(a and 1) + 2", "a + 1 + 2", "-nil"] {
+            assert_eq!(folded(source), None, "{}", source);
+        }
+
+        // Everything but the folded result is rolled back
+        assert_expr("a(2 * 3, -1)", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x01,
+            OpCode::Constant.into(), 0x02,
+            OpCode::Call.into(), 0x02,
+        ]);
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let function_code = |source: &str| {
+            let mut chunk = Chunk::default();
+            let mut heap = ObjHeap::default();
+            compile(source, &mut chunk, &mut heap).unwrap();
+            let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+            assert!(function.chunk.verify_with_depth(function.arity + 1).is_ok());
+            (function.chunk.code.clone(), function.chunk.constants().len())
+        };
+
+        // Nothing after a return, and no implicit return either
+        let (code, constants) = function_code("fun f() { return 1; print 2; { var x = 3; } }");
+        assert_eq!(code, vec![OpCode::Constant.into(), 0x00, OpCode::Return.into()]);
+        assert_eq!(constants, 1);
+
+        let (code, _) = function_code("fun f(c) { if (c) return 1; else throw 2; }");
+        assert_eq!(code.last(), Some(&OpCode::Throw.into()));
+        assert!(!code.ends_with(&[OpCode::Nil.into(), OpCode::Return.into()]));
+
+        // Only one branch returns, or the loop may not run
+        for source in ["fun f(c) { if (c) return 1; }", "fun f(c) { while (c) return 1; }", "fun f() { try { return 1; } catch (e) {} }"] {
+            let (code, _) = function_code(source);
+            assert!(code.ends_with(&[OpCode::Nil.into(), OpCode::Return.into()]), "{}", source);
+        }
+
+        // A literal condition keeps only the branch it takes
+        for (source, expected) in [
+            ("if (true) print 1; else print 2;", vec![OpCode::Constant.into(), 0x00, OpCode::Print.into()]),
+            ("if (nil) print 1; else print 2;", vec![OpCode::Constant.into(), 0x00, OpCode::Print.into()]),
+            ("if (1 > 2) print 1;", vec![]),
+            ("while (false) print 1;", vec![]),
+            ("throw 1; print 2;", vec![OpCode::Constant.into(), 0x00, OpCode::Throw.into()]),
+        ] {
+            let mut chunk = Chunk::default();
+            compile(source, &mut chunk, &mut ObjHeap::default()).unwrap();
+            assert_eq!(chunk.code, [expected, vec![OpCode::Nil.into(), OpCode::Return.into()]].concat(), "{}", source);
+            assert_eq!(chunk.constants().len(), chunk.code.len() / 3, "{}", source);
+        }
+
+        let mut chunk = Chunk::default();
+        compile("while (c) { continue; print 1; }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert!(!chunk.code.contains(&OpCode::Print.into()));
+
+        // Code emitted where dropped code was isn't folded into it
+        for source in [
+            "var x = 5; if (false) { 2; } print -x;",
+            "var x = 5; while (false) { 2; } print -x;",
+            "var x = 5; if (true) {} else { 2; } print -x;",
+        ] {
+            let mut chunk = Chunk::default();
+            compile(source, &mut chunk, &mut ObjHeap::default()).unwrap();
+            assert!(chunk.code.contains(&OpCode::Negate.into()), "{}", source);
+        }
+
+        // The first statement dropped after an exit is warned about
+        let warnings = compile_warnings("fun f() {\n  return 1;\n  print 2;\n  print 3;\n}\nwhile (c) { continue; print 1; }");
+        let messages: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(messages, vec!["[line 3] Warning: Unreachable code.", "[line 6] Warning: Unreachable code."]);
+        assert_eq!(warnings[0].span, Span { start: 24, end: 29, line: 3, column: 3 });
+        assert!(compile_warnings("fun f(c) { if (c) return 1; print 2; }").is_empty());
+
+        // Dead code is still checked for errors
+        let errors = compile_errors("fun f() { return; print ; }\nif (false) print ;");
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_long_constants() {
+        // Added to a variable, so the literals aren't folded together
+        let source = format!("x + {}", (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + "));
+        let mut heap = ObjHeap::default();
+        let mut p = Parser::new(&source, &mut heap);
+        p.advance();
+        p.expression();
+
+        let code = &p.compiler.chunk.code;
+        assert_eq!(code[..2], [OpCode::GetGlobal.into(), 0x00]);
+        // 255 is the last short constant, folded into its add; 256 follows it
+        // and keeps a separate OP_ADD
+        let long = 2 + 255 * 2;
+        assert_eq!(code[long - 2..long], [OpCode::AddConstant.into(), 0xff]);
+        assert_eq!(code[long..long + 5], [OpCode::ConstantLong.into(), 0x00, 0x01, 0x00, OpCode::Add.into()]);
+        assert!(!p.had_error);
+    }
+
+    #[test]
+    fn test_long_names() {
+        // Declared without initializers, so each name is the only constant it adds
+        let source = (0..300).map(|i| format!("var v{};", i)).collect::<String>() + "v299 = v0;";
+        let mut chunk = Chunk::default();
+        compile(&source, &mut chunk, &mut ObjHeap::default()).unwrap();
+
+        let code = &chunk.code;
+        assert_eq!(code[255 * 3..255 * 3 + 3], [OpCode::Nil.into(), OpCode::DefineGlobal.into(), 0xff]);
+        assert_eq!(code[256 * 3..256 * 3 + 5], [OpCode::Nil.into(), OpCode::DefineGlobalLong.into(), 0x00, 0x01, 0x00]);
+        assert_eq!(code[code.len() - 9..], [
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::SetGlobalLong.into(), 0x00, 0x01, 0x2b,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+    }
+
+    #[test]
+    fn test_long_locals() {
+        let source = format!(
+            "fun f() {{ {} v299 = v0 + v299; return v299; }}",
+            (0..300).map(|i| format!("var v{} = nil;", i)).collect::<String>()
+        );
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile(&source, &mut chunk, &mut heap).unwrap();
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+
+        // v0 is in slot 1, after the function itself, so v299 is in slot 300
+        let code = &function.chunk.code;
+        assert_eq!(code[300..], [
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::GetLocalLong.into(), 0x01, 0x2c,
+            OpCode::Add.into(),
+            OpCode::SetLocalLong.into(), 0x01, 0x2c,
+            OpCode::Pop.into(),
+            OpCode::GetLocalLong.into(), 0x01, 0x2c,
+            OpCode::Return.into(),
+        ]);
+        assert!(function.chunk.verify_with_depth(1).is_ok());
+    }
+
+    #[test]
+    fn test_long_jumps() {
+        // Padded straight into the chunk, since scanning that much source is slow
+        let compile_padded = |len: usize, jump_over: bool| {
+            let mut heap = ObjHeap::default();
+            let mut p = Parser::new("}", &mut heap);
+            p.advance();
+            p.advance();
+
+            let start = p.compiler.chunk.code.len();
+            let jump = jump_over.then(|| p.emit_jump(OpCode::Jump));
+            for _ in 0..len {
+                p.emit_byte(OpCode::Nil);
+            }
+            match jump {
+                Some(jump) => p.patch_jump(jump),
+                None => p.emit_loop(start),
+            }
+            p.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        };
+
+        let max = u16::MAX as usize;
+        assert!(compile_padded(max, true).is_empty());
+        assert_eq!(compile_padded(max + 1, true), ["[line 1] Error at '}': Too much code to jump over."]);
+
+        // A loop's offset also covers the OP_LOOP and its operand
+        assert!(compile_padded(max - 3, false).is_empty());
+        assert_eq!(compile_padded(max - 2, false), ["[line 1] Error at '}': Loop body too large."]);
+    }
+
+    #[test]
+    fn test_grouping() {
+        assert_expr("(a + 1) * b", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Multiply.into(),
+        ]);
+
+        // Repeated literals share a constant
+        assert_expr("(a + 1) * (b - 1) / 4", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Constant.into(), 0x01,
+            OpCode::Subtract.into(),
+            OpCode::Multiply.into(),
+            OpCode::Constant.into(), 0x03,
+            OpCode::Divide.into(),
+        ]);
+
+        // Int and Number constants are kept apart even when equal
+        assert_expr("a + 1 + 1.0", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::AddConstant.into(), 0x02,
+        ]);
+    }
+
+    #[test]
+    fn test_string_concatenation() {
+        assert_expr("a + \"b\"", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x01,
+            OpCode::Add.into(),
+        ]);
+
+        assert_expr("1 + (\"a\") + 2 * b + 4", vec![
+            OpCode::Constant.into(), 0x00,
+            OpCode::Constant.into(), 0x01,
+            OpCode::Constant.into(), 0x02,
+            OpCode::GetGlobal.into(), 0x03,
+            OpCode::Multiply.into(),
+            OpCode::Constant.into(), 0x04,
+            OpCode::ConcatN.into(), 0x04,
+        ]);
+
+        assert_expr("a + 2 + 3", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::AddConstant.into(), 0x02,
+        ]);
+
+        assert_expr("-\"a\" + 1 + 2", vec![
+            OpCode::Constant.into(), 0x00,
+            OpCode::Negate.into(),
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::AddConstant.into(), 0x02,
+        ]);
+
+        // Nothing is folded once a string literal has joined the chain
+        assert_expr("x + \"a\" + 1", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x01,
+            OpCode::Constant.into(), 0x02,
+            OpCode::ConcatN.into(), 0x03,
+        ]);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let errors = compile_errors("1 +");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect expression.");
+
+        let errors = compile_errors("\n(1 2");
+        assert_eq!(errors[0].to_string(), "[line 2] Error at '2': Expect ')' after expression.");
+
+        let errors = compile_errors("1 + @");
+        assert_eq!(errors[0].to_string(), "[line 1] Error: Unexpected character.");
+        assert_eq!(errors[0].span, Span { start: 4, end: 5, line: 1, column: 5 });
+
+        let errors = compile_errors("print 1");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect ';' after value.");
+        assert_eq!(errors[0].span, Span { start: 7, end: 7, line: 1, column: 8 });
+
+        let errors = compile_errors("var 1 = 2;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '1': Expect variable name.");
+
+        let errors = compile_errors("a + b = 3;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '=': Invalid assignment target.");
+
+        let errors = compile_errors("-a = 3;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '=': Invalid assignment target.");
+
+        let errors = compile_errors("return 1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'return': Can't return from top-level code.");
+
+        let errors = compile_errors("continue;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'continue': Can't use 'continue' outside of a loop.");
+
+        let errors = compile_errors("while (true) { fun f() { continue; } }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'continue': Can't use 'continue' outside of a loop.");
+
+        let errors = compile_errors("fun f(a b) {}");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'b': Expect ')' after parameters.");
+
+        let errors = compile_errors("f(1 2);");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '2': Expect ')' after arguments.");
+
+        let errors = compile_errors("1 + 2");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect ';' after expression.");
+
+        let errors = compile_errors("var a = 1;\n{ var a = a + 1; }");
+        assert_eq!(errors[0].to_string(), "[line 2] Error at 'a': Can't read local variable in its own initializer.");
+
+        let errors = compile_errors("{ var (a, b) = [1, b]; }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'b': Can't read local variable in its own initializer.");
+
+        // Globals and functions can refer to themselves
+        assert!(compile("var a = a; { fun f() { return f; } }", &mut Chunk::default(), &mut ObjHeap::default()).is_ok());
+
+        // Functions aren't closures, so an enclosing function's locals are out of reach
+        let errors = compile_errors("fun outer() {\n  var x = 1;\n  fun inner() { return x; }\n  return inner;\n}");
+        assert_eq!(errors[0].to_string(), "[line 3] Error at 'x': Can't capture local variable 'x' from an enclosing function.");
+        let errors = compile_errors("{ fun f() { fun g() { return f; } return g; } print f; }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'f': Can't capture local variable 'f' from an enclosing function.");
+
+        assert!(compile("1 + 2;", &mut Chunk::default(), &mut ObjHeap::default()).is_ok());
+    }
+
+    #[test]
+    fn test_unused_locals() {
+        let warnings = compile_warnings("{ var a = 1; }");
+        let span = Span { start: 6, end: 7, line: 1, column: 7 };
+        let message = "Unused variable 'a'.".to_string();
+        assert_eq!(warnings, vec![Diagnostic { span, severity: Severity::Warning, message, location: String::new() }]);
+
+        let warnings = compile_warnings("{\n  var a = 1;\n  const b = 2;\n  fun f() {}\n}");
+        let messages: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(messages, vec![
+            "[line 4] Warning: Unused function 'f'.",
+            "[line 3] Warning: Unused constant 'b'.",
+            "[line 2] Warning: Unused variable 'a'.",
+        ]);
+
+        // Assigning isn't reading
+        assert_eq!(compile_warnings("{ var a; a = 1; }").len(), 1);
+        assert_eq!(compile_warnings("fun f() { var a = 1; }")[0].message, "Unused variable 'a'.");
+
+        // Read locals, parameters, catch variables and globals
+        assert!(compile_warnings("{ var a = 1; print a; }").is_empty());
+        assert!(compile_warnings("fun f(a, b) {}").is_empty());
+        assert!(compile_warnings("try { throw 1; } catch (e) {}").is_empty());
+        assert!(compile_warnings("var a = 1; fun f() {}").is_empty());
+        assert!(compile_warnings("{ fun f(n) { if (n > 0) f(n - 1); } f(1); }").is_empty());
+
+        // A captured local has its error already, without being warned about as well
+        let mut diagnostics = Vec::new();
+        assert!(compile_with_sink("fun f() { var x = 1; fun g() { return x; } return g; }", &mut Chunk::default(), &mut ObjHeap::default(), &mut diagnostics).is_err());
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+        assert!(compile_warnings("class A { m() { return 1; } }").is_empty());
+
+        // A missing name doesn't declare the token before it
+        for source in ["fun f() { var = 1; }", "{ class {} }", "{ fun (a) {} }", "{ var (a, ) = [1]; print a; }"] {
+            let mut diagnostics = Vec::new();
+            assert!(compile_with_sink(source, &mut Chunk::default(), &mut ObjHeap::default(), &mut diagnostics).is_err());
+            assert!(diagnostics.iter().all(|d| d.severity == Severity::Error), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_diagnostic_sink() {
+        let mut diagnostics = Vec::new();
+        let errors = compile_with_sink("{ var a = 1; }\nprint ;", &mut Chunk::default(), &mut ObjHeap::default(), &mut diagnostics)
+            .unwrap_err();
+
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(messages, vec![
+            "[line 1] Warning: Unused variable 'a'.",
+            "[line 2] Error at ';': Expect expression.",
+        ]);
+        assert_eq!(diagnostics[1], Diagnostic::from(&errors[0]));
+        assert_eq!(diagnostics[1].severity, Severity::Error);
+        assert_eq!(diagnostics[1].span, Span { start: 21, end: 22, line: 2, column: 7 });
+    }
+
+    #[test]
+    fn test_synchronize() {
+        // Each bad statement is reported once, and parsing picks up at the next one
+        let errors = compile_errors("var 1 = 2;\nprint 3;\nprint (4;\n1 +\nvar b = 5;\nfun f( {}\nprint );");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec![
+            "[line 1] Error at '1': Expect variable name.",
+            "[line 3] Error at ';': Expect ')' after expression.",
+            "[line 5] Error at 'var': Expect expression.",
+            "[line 6] Error at '{': Expect parameter name.",
+            "[line 7] Error at ')': Expect expression.",
+        ]);
+
+        // Errors inside a block don't hide the ones after it
+        let errors = compile_errors("{ var a = ; }\nwhile (true) { print; }");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].span.line, 2);
+
+        let errors = compile_errors("print @;\nprint ;");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].to_string(), "[line 2] Error at ';': Expect expression.");
+    }
+
+    #[test]
+    fn test_statements() {
+        let mut chunk = Chunk::default();
+        compile("print a + 2;\n3;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::AddConstant.into(), 0x01,
+            OpCode::Print.into(),
+            OpCode::Constant.into(), 0x02,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        let mut chunk = Chunk::default();
+        compile("var a = 1;\nvar b;\nprint a;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x01,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::Nil.into(),
+            OpCode::DefineGlobal.into(), 0x02,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Print.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        let mut chunk = Chunk::default();
+        compile("a = b = 3;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x02,
+            OpCode::SetGlobal.into(), 0x01,
+            OpCode::SetGlobal.into(), 0x00,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        let mut chunk = Chunk::default();
+        compile("", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![OpCode::Nil.into(), OpCode::Return.into()]);
+    }
+
+    #[test]
+    fn test_locals() {
+        let mut chunk = Chunk::default();
+        compile("{ var a = 1; { var b = a; b = 2; } }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        // Slot zero belongs to the script itself
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x00,
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::Constant.into(), 0x01,
+            OpCode::SetLocal.into(), 0x02,
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let errors = compile_errors("{ var a = 1; var a = 2; }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'a': Already a variable with this name in this scope.");
+
+        let errors = compile_errors("{ var a = 1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect '}' after block.");
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let mut chunk = Chunk::default();
+        compile("for (var i = 0; i < 2; i = i + 1) print i;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x00,
+            // Condition
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::Constant.into(), 0x01,
+            OpCode::CompareJump.into(), OpCode::Less.into(), 0x00, 0x14,
+            OpCode::Pop.into(),
+            OpCode::Jump.into(), 0x00, 0x0a,
+            // Increment
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::AddConstant.into(), 0x02,
+            OpCode::SetLocal.into(), 0x01,
+            OpCode::Pop.into(),
+            OpCode::Loop.into(), 0x00, 0x16,
+            // Body
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::Print.into(),
+            OpCode::Loop.into(), 0x00, 0x10,
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let mut chunk = Chunk::default();
+        compile("for (;;) {}", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Loop.into(), 0x00, 0x03,
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile("fun add(a, b) { return a + b; }\nadd(1, 2);", &mut chunk, &mut heap).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x01,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x02,
+            OpCode::Constant.into(), 0x03,
+            OpCode::Call.into(), 0x02,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+        assert_eq!((function.arity, function.name.as_deref()), (2, Some("add")));
+        assert_eq!(function.chunk.code, vec![
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::GetLocal.into(), 0x02,
+            OpCode::Add.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(function.chunk.verify_with_depth(3).is_ok());
+
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile("var f = fun (x) { return x; };", &mut chunk, &mut heap).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x01,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+        assert_eq!((function.arity, function.name.as_deref()), (1, Some("lambda")));
+
+        let mut heap = ObjHeap::default();
+        compile("fun f(a, ...rest) {}", &mut chunk, &mut heap).unwrap();
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+        assert_eq!((function.arity, function.variadic), (2, true));
+
+        let errors = compile_errors("fun f(...rest, a) {}");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ',': A rest parameter must come last.");
+
+        // A statement starting with `fun` is always a declaration
+        let errors = compile_errors("fun () {}();");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '(': Expect function name.");
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile("fun f(n) { return f(n); }", &mut chunk, &mut heap).unwrap();
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+        assert_eq!(function.chunk.code, vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::TailCall.into(), 0x01,
+            OpCode::Return.into(),
+        ]);
+
+        // Only a call that's the whole return value, outside any try block
+        for source in ["fun f(n) { return f(n) + 1; }", "fun f(n) { return n and f(n); }", "fun f(n) { try { return f(n); } catch (e) {} }"] {
+            let mut chunk = Chunk::default();
+            let mut heap = ObjHeap::default();
+            compile(source, &mut chunk, &mut heap).unwrap();
+            let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+            assert!(!function.chunk.code.contains(&OpCode::TailCall.into()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut chunk = Chunk::default();
+        compile("class A {}\nA().x = A.y;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Class.into(), 0x00,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Pop.into(),
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Call.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetProperty.into(), 0x02,
+            OpCode::SetProperty.into(), 0x01,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        let mut chunk = Chunk::default();
+        compile("a.b(1);", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x02,
+            OpCode::Invoke.into(), 0x01, 0x01,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile("class A { x { return this; } }", &mut chunk, &mut heap).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Class.into(), 0x00,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x02,
+            OpCode::Getter.into(), 0x01,
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        let getter = heap.as_function(chunk.constant_ref(2).unwrap()).unwrap();
+        assert_eq!(getter.arity, 0);
+        assert_eq!(getter.chunk.code[..2], [OpCode::GetLocal0.into(), OpCode::Return.into()]);
+
+        let errors = compile_errors("class A { init { } }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'init': An initializer can't be a getter.");
+
+        let errors = compile_errors("print this;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'this': Can't use 'this' outside of a class.");
+
+        let errors = compile_errors("class A { init() { return 1; } }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'return': Can't return a value from an initializer.");
+
+        let errors = compile_errors("class {}");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '{': Expect class name.");
+
+        assert_expr("a?.b.c", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::JumpIfNil.into(), 0x00, 0x04,
+            OpCode::GetProperty.into(), 0x01,
+            OpCode::GetProperty.into(), 0x02,
+        ]);
+
+        let errors = compile_errors("a?.b = 1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '=': Invalid assignment target.");
+
+        let errors = compile_errors("a.1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '1': Expect property name after '.'.");
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let mut chunk = Chunk::default();
+        compile("var (a, b) = xs; { var (c, d) = [b, a]; print d; }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Unpack.into(), 0x02,
+            OpCode::DefineGlobal.into(), 0x01,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::BuildList.into(), 0x02,
+            OpCode::Unpack.into(), 0x02,
+            OpCode::GetLocal.into(), 0x02,
+            OpCode::Print.into(),
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        for (source, message) in [
+            ("{ var (a, a) = xs; }", "[line 1] Error at 'a': Already a variable with this name in this scope."),
+            ("var (a b) = xs;", "[line 1] Error at 'b': Expect ')' after variable names."),
+            ("var (a, b);", "[line 1] Error at ';': Expect '=' after variable names."),
+            ("var () = xs;", "[line 1] Error at ')': Expect variable name."),
+        ] {
+            assert_eq!(compile_errors(source)[0].to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut chunk = Chunk::default();
+        compile("const a = 1; { const b = a; print b; }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x01,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::Print.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+
+        // A local can shadow a constant, and is assignable itself
+        assert!(compile("const a = 1; { var a = 2; a = 3; }", &mut Chunk::default(), &mut ObjHeap::default()).is_ok());
+
+        let errors = compile_errors("const a = 1; a = 2;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'a': Can't assign to constant 'a'.");
+
+        let errors = compile_errors("{ const b = 1; b = 2; }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'b': Can't assign to constant 'b'.");
+
+        let errors = compile_errors("const a = 1; fun f() { a = 2; }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'a': Can't assign to constant 'a'.");
+
+        let errors = compile_errors("const a = 1; var a = 2;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'a': Already a constant with this name.");
+
+        let errors = compile_errors("const a;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect '=' after constant name.");
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let mut chunk = Chunk::default();
+        compile("try { throw 1; } catch (e) { print e; }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::TryBegin.into(), 0x00, 0x07,
+            OpCode::Constant.into(), 0x00,
+            OpCode::Throw.into(),
+            OpCode::TryEnd.into(),
+            OpCode::Jump.into(), 0x00, 0x04,
+            // Handler, with the thrown value in slot 1
+            OpCode::GetLocal.into(), 0x01,
+            OpCode::Print.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        // Continuing out of a try block leaves it
+        let mut chunk = Chunk::default();
+        compile("while (true) { try { continue; } catch (e) {} }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code[8..10], [OpCode::TryEnd.into(), OpCode::Loop.into()]);
+
+        let errors = compile_errors("try { }");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect 'catch' after try block.");
+
+        let errors = compile_errors("try { } catch () {}");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ')': Expect exception variable name.");
+
+        let errors = compile_errors("throw;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect expression.");
+    }
+
+    #[test]
+    fn test_imports() {
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile("import \"a.lox\"; import \"b.lox\" as b; var as = 1;", &mut chunk, &mut heap).unwrap();
+        assert_eq!(chunk.code[..12], [
+            OpCode::Import.into(), 0x00,
+            OpCode::Pop.into(),
+            OpCode::ImportAll.into(),
+            OpCode::Import.into(), 0x01,
+            OpCode::Pop.into(),
+            OpCode::DefineGlobal.into(), 0x02,
+            OpCode::Constant.into(), 0x04,
+            OpCode::DefineGlobal.into(),
+        ]);
+        assert_eq!(heap.as_str(chunk.constant_ref(1).unwrap()), Some("b.lox"));
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let errors = compile_errors("import a;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'a': Expect module path.");
+
+        let errors = compile_errors("import \"a.lox\" as;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect module name after 'as'.");
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut chunk = Chunk::default();
+        compile("var a = [1, [], 2]; a[0] = a[-1];", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::Constant.into(), 0x01,
+            OpCode::BuildList.into(), 0x00,
+            OpCode::Constant.into(), 0x02,
+            OpCode::BuildList.into(), 0x03,
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x03,
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Constant.into(), 0x04,
+            OpCode::IndexGet.into(),
+            OpCode::IndexSet.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let errors = compile_errors("[1, 2;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect ']' after list items.");
+
+        let errors = compile_errors("a[1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at ';': Expect ']' after index.");
+
+        let errors = compile_errors("a + b[0] = 1;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at '=': Invalid assignment target.");
+    }
+
+    #[test]
+    fn test_for_in() {
+        let mut chunk = Chunk::default();
+        compile("for (x in xs) print x;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code, vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::IterNew.into(),
+            OpCode::IterNext.into(), 0x00, 0x07,
+            OpCode::GetLocal.into(), 0x03,
+            OpCode::Print.into(),
+            OpCode::Pop.into(),
+            OpCode::Loop.into(), 0x00, 0x0a,
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        // `continue` pops the item along with the body's own locals
+        let mut chunk = Chunk::default();
+        compile("for (x in xs) { var y = x; continue; }", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        let errors = compile_errors("for (x in xs print x;");
+        assert_eq!(errors[0].to_string(), "[line 1] Error at 'print': Expect ')' after loop collection.");
+    }
+
+    #[test]
+    fn test_ranges() {
+        // Looser than arithmetic but tighter than comparison
+        assert_expr("0..n - 1 == r", vec![
+            OpCode::Constant.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::Constant.into(), 0x02,
+            OpCode::Subtract.into(),
+            OpCode::Range.into(),
+            OpCode::GetGlobal.into(), 0x03,
+            OpCode::Equal.into(),
+        ]);
+
+        assert_expr("1..=2", vec![
+            OpCode::Constant.into(), 0x00,
+            OpCode::Constant.into(), 0x01,
+            OpCode::RangeInclusive.into(),
+        ]);
+    }
+
+    #[test]
+    fn test_match() {
+        assert_expr("match x { 1 => a, _ => b }", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::Dup.into(),
+            OpCode::Constant.into(), 0x01,
+            OpCode::CompareJump.into(), OpCode::Equal.into(), 0x00, 0x07,
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::GetGlobal.into(), 0x02,
+            OpCode::Jump.into(), 0x00, 0x04,
+            OpCode::Pop.into(),
+            OpCode::Pop.into(),
+            OpCode::GetGlobal.into(), 0x03,
+        ]);
+
+        // Without a wildcard, falling off the last arm leaves nil
+        let mut chunk = Chunk::default();
+        compile("var y = match x { \"a\" => 1, -2 => 2, nil => 3, };", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert_eq!(chunk.code[chunk.code.len() - 6..], [
+            OpCode::Pop.into(),
+            OpCode::Nil.into(),
+            OpCode::DefineGlobal.into(), 0x00,
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+        assert!(chunk.verify_with_depth(1).is_ok());
+
+        for (source, message) in [
+            ("match x { _ => 1, 2 => 3 };", "[line 1] Error at '2': The '_' arm must come last."),
+            ("match x { y => 1 };", "[line 1] Error at 'y': Expect a literal pattern or '_'."),
+            ("match x { 1 -> 2 };", "[line 1] Error at '-': Expect '=>' after match pattern."),
+            ("match x { 1 => 2 3 };", "[line 1] Error at '3': Expect '}' after match arms."),
+            ("match x 1 => 2;", "[line 1] Error at '1': Expect '{' after match value."),
+        ] {
+            assert_eq!(compile_errors(source)[0].to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        assert_expr("true and false", vec![
+            OpCode::True.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x02,
+            OpCode::Pop.into(),
+            OpCode::False.into(),
+        ]);
+
+        assert_expr("true or false", vec![
+            OpCode::True.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x03,
+            OpCode::Jump.into(), 0x00, 0x02,
+            OpCode::Pop.into(),
+            OpCode::False.into(),
+        ]);
+
+        // and binds tighter than or
+        assert_expr("nil or true and false", vec![
+            OpCode::Nil.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x03,
+            OpCode::Jump.into(), 0x00, 0x07,
+            OpCode::Pop.into(),
+            OpCode::True.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x02,
+            OpCode::Pop.into(),
+            OpCode::False.into(),
+        ]);
+
+        // ?? binds looser than or
+        assert_expr("nil ?? false or 1", vec![
+            OpCode::Nil.into(),
+            OpCode::JumpIfNotNil.into(), 0x00, 0x0b,
+            OpCode::Pop.into(),
+            OpCode::False.into(),
+            OpCode::JumpIfFalse.into(), 0x00, 0x03,
+            OpCode::Jump.into(), 0x00, 0x03,
+            OpCode::Pop.into(),
+            OpCode::Constant.into(), 0x00,
+        ]);
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        assert_expr("a < b and c", vec![
+            OpCode::GetGlobal.into(), 0x00,
+            OpCode::GetGlobal.into(), 0x01,
+            OpCode::CompareJump.into(), OpCode::Less.into(), 0x00, 0x03,
+            OpCode::Pop.into(),
+            OpCode::GetGlobal.into(), 0x02,
+        ]);
+
+        // The or's jump lands between the comparison and the if's jump, so they stay apart
+        let mut chunk = Chunk::default();
+        compile("if (a or b < c) nil;", &mut chunk, &mut ObjHeap::default()).unwrap();
+        assert!(chunk.code.windows(2).any(|w| w == [OpCode::Less.into(), OpCode::JumpIfFalse.into()]));
+        assert!(chunk.verify_with_depth(1).is_ok());
+    }
+
+    fn compile_warnings(source: &str) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        compile_with_sink(source, &mut Chunk::default(), &mut ObjHeap::default(), &mut warnings).unwrap();
+        warnings
+    }
+
+    fn compile_errors(source: &str) -> Vec<CompileError> {
+        compile(source, &mut Chunk::default(), &mut ObjHeap::default()).unwrap_err()
+    }
+
+    fn assert_expr(source: &str, code: Vec<u8>) {
+        let mut heap = ObjHeap::default();
+        let mut p = Parser::new(source, &mut heap);
 
         p.advance();
         p.expression();
         p.consume(TokenType::EOF, "Expect end of expression.");
 
-        eprintln!("{:?}", chunk);
-        assert_eq!(chunk.code, code);
+        eprintln!("{:?}", p.compiler.chunk);
+        assert_eq!(p.compiler.chunk.code, code);
     }
 }
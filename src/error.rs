@@ -1,18 +1,186 @@
+use crate::chunk::OpCode;
+use crate::token::Span;
+
+use std::fmt;
+
 #[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
-    ValueError(&'static str),
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
+    // Raised while executing an instruction; the VM turns it into a RuntimeError
+    // once it has worked out where it happened
+    ValueError(String),
+    // The VM's instruction budget ran out; see VM::with_fuel
+    FuelExhausted,
+    // Another thread tripped the VM's CancellationHandle
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: Option<u32>,
+    // The source the failing instruction was compiled from, when the chunk kept it
+    pub span: Option<Span>,
+    pub op: Option<OpCode>,
+    // Every call active when the error was raised, innermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+// When printed, a trace shows this many copies of a repeated frame (as runaway
+// recursion leaves) before summarizing the rest
+const TRACE_REPEATS: usize = 3;
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        let mut frames = self.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut copies = 1;
+            while frames.next_if_eq(&frame).is_some() {
+                copies += 1;
+            }
+
+            for _ in 0..copies.min(TRACE_REPEATS) {
+                write!(f, "\n{}", frame)?;
+            }
+            if copies > TRACE_REPEATS {
+                write!(f, "\n[previous frame repeated {} more times]", copies - TRACE_REPEATS)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    // None for the top-level script
+    pub function: Option<String>,
+    pub line: u32,
+    // The file the program was loaded from, when known
+    pub source: Option<String>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(path) => write!(f, "[{}:{}]", path, self.line)?,
+            None => write!(f, "[line {}]", self.line)?,
+        }
+        match &self.function {
+            Some(name) => write!(f, " in {}()", name),
+            None => write!(f, " in script"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub span: Span,
+    // Where on the line the error is, e.g. " at end" or " at 'foo'"
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error{}: {}", self.span.line, self.location, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // Something suspicious but legal, which doesn't stop the program compiling
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+// Everything the compiler has to say, in the form a DiagnosticSink is handed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+    // As in CompileError; warnings leave it empty
+    pub location: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}{}: {}", self.span.line, self.severity, self.location, self.message)
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Diagnostic {
+        Diagnostic {
+            span: error.span,
+            severity: Severity::Error,
+            message: error.message.clone(),
+            location: error.location.clone(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ChunkError {
     IPOutOfBoundsError,
     BadOPCodeError(u8),
+    TruncatedOperandError(usize),
+    BadConstantError(usize),
+    BadSlotError(usize),
+    StackUnderflowError(usize),
+    StackMismatchError(usize),
+    BadJumpError(usize),
+    // A constant the bytecode format has no encoding for, by its index
+    UnserializableConstantError(usize),
 }
 
 impl From<ChunkError> for InterpretError {
-    fn from(_value: ChunkError) -> InterpretError {
-        InterpretError::RuntimeError
+    fn from(value: ChunkError) -> InterpretError {
+        InterpretError::ValueError(format!("Bad bytecode ({:?}).", value))
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    BadMagicError,
+    UnsupportedVersionError(u8),
+    TruncatedError,
+    BadConstantTagError(u8),
+    BadStringError,
+    InvalidChunk(ChunkError),
+}
+
+impl From<ChunkError> for DecodeError {
+    fn from(value: ChunkError) -> DecodeError {
+        DecodeError::InvalidChunk(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    TooManyConstants,
+    JumpTooLarge,
+    UnboundLabel,
+    LabelRebound,
+    ForeignLabel,
+    BackwardConditionalJump,
+    InvalidChunk(ChunkError),
+}
+
+impl From<ChunkError> for BuildError {
+    fn from(value: ChunkError) -> BuildError {
+        BuildError::InvalidChunk(value)
     }
 }
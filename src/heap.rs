@@ -0,0 +1,303 @@
+use crate::value::{Value, ObjectType, ObjHandle, Function, Class, Instance, Module, RangeObject};
+
+use std::fmt;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Debug, Default)]
+pub struct ObjHeap {
+    objects: Vec<ObjectType>,
+    // Every string is allocated once, so equal strings always share a handle
+    strings: HashMap<Rc<str>, ObjHandle>,
+    // Instances and lists that freeze() has made read-only
+    frozen: HashSet<ObjHandle>,
+}
+
+impl ObjHeap {
+    pub fn alloc(&mut self, object: ObjectType) -> ObjHandle {
+        self.objects.push(object);
+        ObjHandle(self.objects.len() - 1)
+    }
+
+    pub fn alloc_str(&mut self, s: String) -> Value {
+        if let Some(&handle) = self.strings.get(s.as_str()) {
+            return Value::Object(handle);
+        }
+
+        let s: Rc<str> = s.into();
+        let handle = self.alloc(ObjectType::Str(Rc::clone(&s)));
+        self.strings.insert(s, handle);
+        Value::Object(handle)
+    }
+
+    // There's no thawing, so a frozen object stays that way
+    pub fn freeze(&mut self, handle: ObjHandle) {
+        self.frozen.insert(handle);
+    }
+
+    // Checked before every write to an instance's fields or a list's items
+    pub fn check_mutable(&self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Object(h) if self.frozen.contains(h) => Err(format!("Can't modify frozen {}.", self.describe(value))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get(&self, handle: ObjHandle) -> &ObjectType {
+        &self.objects[handle.0]
+    }
+
+    pub fn get_mut(&mut self, handle: ObjHandle) -> &mut ObjectType {
+        &mut self.objects[handle.0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn as_str(&self, value: &Value) -> Option<&str> {
+        match value {
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::Str(s) => Some(s.as_ref()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self, value: &Value) -> Option<&Function> {
+        match value {
+            Value::Object(h) => self.function(*h),
+            _ => None,
+        }
+    }
+
+    pub fn function(&self, handle: ObjHandle) -> Option<&Function> {
+        match self.get(handle) {
+            ObjectType::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self, handle: ObjHandle) -> Option<&Class> {
+        match self.get(handle) {
+            ObjectType::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self, value: &Value) -> Option<&Instance> {
+        match value {
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::Instance(i) => Some(i),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, value: &Value) -> Option<&mut Instance> {
+        match value {
+            Value::Object(h) => match self.get_mut(*h) {
+                ObjectType::Instance(i) => Some(i),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self, value: &Value) -> Option<&Vec<Value>> {
+        match value {
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::List(l) => Some(l),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self, value: &Value) -> Option<&mut Vec<Value>> {
+        match value {
+            Value::Object(h) => match self.get_mut(*h) {
+                ObjectType::List(l) => Some(l),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_module(&self, value: &Value) -> Option<&Module> {
+        match value {
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::Module(m) => Some(m),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_range(&self, value: &Value) -> Option<&RangeObject> {
+        match value {
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::Range(r) => Some(r),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn display<'a>(&'a self, value: &'a Value) -> ValueDisplay<'a> {
+        ValueDisplay { heap: self, value }
+    }
+
+    pub fn type_name(&self, value: &Value) -> &'static str {
+        match value {
+            Value::Bool(_) => "Bool",
+            Value::Nil => "Nil",
+            Value::Number(_) => "Number",
+            Value::Int(_) => "Int",
+            Value::Object(h) => match self.get(*h) {
+                ObjectType::Str(_) => "Str",
+                ObjectType::Function(_) => "Function",
+                ObjectType::Class(_) => "Class",
+                ObjectType::Instance(_) => "Instance",
+                ObjectType::BoundMethod(_) => "BoundMethod",
+                ObjectType::List(_) => "List",
+                ObjectType::Module(_) => "Module",
+                ObjectType::Range(_) => "Range",
+                ObjectType::Native(_) => "Native",
+            },
+        }
+    }
+
+    // Type and value together, for error messages (e.g. `Number(3)`)
+    pub fn describe(&self, value: &Value) -> String {
+        match value {
+            Value::Nil => self.type_name(value).to_string(),
+            _ => format!("{}({})", self.type_name(value), self.display(value)),
+        }
+    }
+
+    pub fn equal(&self, a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
+            (Value::Int(_) | Value::Number(_), Value::Int(_) | Value::Number(_)) => a == b,
+            (Value::Object(h1), Value::Object(h2)) => h1 == h2 || self.objects_equal(*h1, *h2),
+            _ => a == b,
+        }
+    }
+
+    // Decides when two distinct objects are still equal. Strings are interned,
+    // so two distinct strings never are. Ranges compare by their bounds; every
+    // other kind of object should only be equal to itself, unless it's an
+    // instance whose class defines `__eq__`, which the VM calls instead
+    fn objects_equal(&self, a: ObjHandle, b: ObjHandle) -> bool {
+        match (self.get(a), self.get(b)) {
+            (ObjectType::Range(r1), ObjectType::Range(r2)) => r1 == r2,
+            // The same method looked up twice on the same instance
+            (ObjectType::BoundMethod(m1), ObjectType::BoundMethod(m2)) => {
+                m1.method == m2.method && m1.receiver == m2.receiver
+            },
+            _ => false,
+        }
+    }
+
+    pub fn compare(&self, a: &Value, b: &Value) -> Option<Ordering> {
+        match (a, b) {
+            (Value::Object(h1), Value::Object(h2)) => match (self.get(*h1), self.get(*h2)) {
+                (ObjectType::Str(s1), ObjectType::Str(s2)) => Some(s1.cmp(s2)),
+                _ => None,
+            },
+            _ => a.partial_cmp(b),
+        }
+    }
+}
+
+pub struct ValueDisplay<'a> {
+    heap: &'a ObjHeap,
+    value: &'a Value,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Object(h) => match self.heap.get(*h) {
+                ObjectType::Str(s) => write!(f, "\"{}\"", s),
+                ObjectType::Function(function) => match &function.name {
+                    Some(name) => write!(f, "<fn {}>", name),
+                    None => write!(f, "<script>"),
+                },
+                ObjectType::Class(class) => write!(f, "{}", class.name),
+                ObjectType::Instance(instance) => match self.heap.class(instance.class) {
+                    Some(class) => write!(f, "{} instance", class.name),
+                    None => write!(f, "instance"),
+                },
+                ObjectType::BoundMethod(bound) => {
+                    write!(f, "{}", self.heap.display(&Value::Object(bound.method)))
+                },
+                ObjectType::List(items) => {
+                    write!(f, "[")?;
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 { write!(f, ", ")?; }
+                        match f.precision() {
+                            Some(precision) => write!(f, "{:.*}", precision, self.heap.display(item))?,
+                            None => write!(f, "{}", self.heap.display(item))?,
+                        }
+                    }
+                    write!(f, "]")
+                },
+                ObjectType::Module(module) => write!(f, "<module {}>", module.path),
+                ObjectType::Range(range) => write!(f, "{}..{}", range.start, range.end),
+                ObjectType::Native(native) => write!(f, "<native fn {}>", native.name),
+            },
+            v => match f.precision() {
+                Some(precision) => write!(f, "{:.*}", precision, v),
+                None => write!(f, "{}", v),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strings_compare_by_contents() {
+        let mut heap = ObjHeap::default();
+        let a1 = heap.alloc_str("a".to_string());
+        let a2 = heap.alloc_str("a".to_string());
+        let b = heap.alloc_str("b".to_string());
+
+        // Interned, so the second "a" is the same object as the first
+        assert_eq!(a1, a2);
+        assert_eq!(heap.len(), 2);
+        assert!(heap.equal(&a1, &a2));
+        assert!(!heap.equal(&a1, &b));
+        assert_eq!(heap.compare(&a1, &b), Some(Ordering::Less));
+        assert_eq!(heap.describe(&b), "Str(\"b\")");
+
+        let nan = Value::Number(f64::NAN);
+        assert!(!heap.equal(&nan, &nan));
+    }
+
+    #[test]
+    fn test_list_display() {
+        let mut heap = ObjHeap::default();
+        let s = heap.alloc_str("a".to_string());
+        let inner = Value::Object(heap.alloc(ObjectType::List(vec![])));
+        let list = Value::Object(heap.alloc(ObjectType::List(vec![Value::Number(2.0 / 3.0), s, inner])));
+
+        assert_eq!(format!("{:.2}", heap.display(&list)), "[0.67, \"a\", []]");
+        assert_eq!(heap.describe(&inner), "List([])");
+
+        // Lists are only equal to themselves
+        let other = Value::Object(heap.alloc(ObjectType::List(vec![])));
+        assert!(!heap.equal(&inner, &other));
+    }
+}
@@ -1,8 +1,18 @@
 pub mod error;
 pub mod chunk;
+pub mod builder;
 pub mod value;
+pub mod heap;
+pub mod stack;
+#[cfg(feature = "stats")]
+pub mod stats;
 pub mod token;
 pub mod vm;
+pub mod natives;
+pub mod stdlib;
 pub mod scanner;
 pub mod compiler;
+mod codegen;
+pub mod ast;
+pub mod lower;
 pub mod precedence;
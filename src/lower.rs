@@ -0,0 +1,681 @@
+use crate::ast::{self, BinaryOp, Expr, ExprKind, Identifier, LogicalOp, MatchArm, Stmt, StmtKind, UnaryOp};
+use crate::chunk::{Chunk, OpCode};
+use crate::codegen::{CodeGen, FunctionState, FunctionType};
+use crate::compiler::{DiagnosticSink, Stderr};
+use crate::error::{CompileError, Diagnostic};
+use crate::heap::ObjHeap;
+use crate::token::Span;
+use crate::value::{ObjectType, Value};
+
+use std::collections::{HashMap, HashSet};
+
+// The two-phase alternative to compiler::compile: the source is parsed into an
+// ast first and the tree is then lowered to bytecode. The program behaves the
+// same either way, but the lowering only does what the bytecode needs, so none
+// of the single-pass compiler's folding, fusing or dead code removal happens.
+// Tail calls are the exception, since deep recursion depends on them
+pub fn compile(source: &str, chunk: &mut Chunk, heap: &mut ObjHeap) -> Result<(), Vec<CompileError>> {
+    compile_with_sink(source, chunk, heap, &mut Stderr)
+}
+
+pub fn compile_with_sink(source: &str, chunk: &mut Chunk, heap: &mut ObjHeap, sink: &mut dyn DiagnosticSink) -> Result<(), Vec<CompileError>> {
+    let program = ast::parse(source, sink)?;
+    lower(&program, chunk, heap, sink)
+}
+
+// Lowers a parsed program as the top-level script. The errors found here are
+// the ones that need to know about scopes, like reading a local in its own
+// initializer or assigning to a constant
+pub fn lower(program: &[Stmt], chunk: &mut Chunk, heap: &mut ObjHeap, sink: &mut dyn DiagnosticSink) -> Result<(), Vec<CompileError>> {
+    let mut l = Lowerer::new(heap);
+
+    l.statements(program);
+    l.emit_return();
+    *chunk = std::mem::take(&mut l.function.chunk);
+
+    for diagnostic in l.diagnostics.drain(..) {
+        sink.report(diagnostic);
+    }
+
+    if l.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(l.errors)
+    }
+}
+
+struct Lowerer<'a, 'h> {
+    heap: &'h mut ObjHeap,
+    function: FunctionState<'a>,
+    class_depth: usize,
+    const_globals: HashSet<&'a str>,
+    global_slots: HashMap<String, usize>,
+    // One list per optional chain being lowered, of the nil checks that jump to its end
+    nil_jumps: Vec<Vec<usize>>,
+    // Where the last OP_CALL was emitted, so a return can turn it into a tail call
+    last_call: Option<usize>,
+    // The node being lowered, which the code it emits is attributed to
+    span: Span,
+    errors: Vec<CompileError>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a, 'h> Lowerer<'a, 'h> {
+    fn new(heap: &'h mut ObjHeap) -> Self {
+        Lowerer {
+            heap,
+            function: FunctionState::new(FunctionType::Script, None),
+            class_depth: 0,
+            const_globals: HashSet::new(),
+            global_slots: HashMap::new(),
+            nil_jumps: Vec::new(),
+            last_call: None,
+            span: Span::default(),
+            errors: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt<'a>]) {
+        for stmt in statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        let outer = std::mem::replace(&mut self.span, stmt.span);
+
+        match &stmt.kind {
+            StmtKind::Expression(value) => {
+                self.expression(value);
+                self.emit_byte(OpCode::Pop);
+            },
+            StmtKind::Print(value) => {
+                self.expression(value);
+                self.emit_byte(OpCode::Print);
+            },
+            StmtKind::Return(value) => self.return_statement(value.as_ref()),
+            StmtKind::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                self.statement(then_branch);
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_byte(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            },
+            StmtKind::While(condition, body) => {
+                let loop_start = self.function.chunk.code.len();
+                self.expression(condition);
+
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                self.loop_body(body, loop_start);
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_byte(OpCode::Pop);
+            },
+            StmtKind::For { initializer, condition, increment, body } => {
+                self.for_statement(initializer.as_deref(), condition.as_ref(), increment.as_ref(), body);
+            },
+            StmtKind::ForIn { name, iterable, body } => self.for_in_statement(*name, iterable, body),
+            StmtKind::Continue => self.continue_statement(),
+            StmtKind::Try { body, name, handler } => self.try_statement(body, *name, handler),
+            StmtKind::Throw(value) => {
+                self.expression(value);
+                self.emit_byte(OpCode::Throw);
+            },
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope();
+            },
+            StmtKind::Var(name, initializer) => {
+                let global = self.declare_variable(*name);
+                match initializer {
+                    Some(value) => self.expression(value),
+                    None => self.emit_byte(OpCode::Nil),
+                }
+                self.define_variable(global);
+            },
+            StmtKind::Destructure(names, value) => self.destructuring_declaration(names, value),
+            StmtKind::Const(name, value) => {
+                let global = self.declare_variable(*name);
+                if self.function.scope_depth > 0 {
+                    if let Some(local) = self.function.locals.last_mut() {
+                        local.is_const = true;
+                    }
+                } else {
+                    self.const_globals.insert(name.name);
+                }
+                self.expression(value);
+                self.define_variable(global);
+            },
+            StmtKind::Fun(function) => {
+                let global = self.declare_variable(function.name);
+                self.mark_initialized();
+                if self.function.scope_depth > 0 {
+                    if let Some(local) = self.function.locals.last_mut() {
+                        local.is_function = true;
+                    }
+                }
+                self.function(function, FunctionType::Function);
+                self.define_variable(global);
+            },
+            StmtKind::Class(name, methods) => self.class_declaration(*name, methods),
+            StmtKind::Import { path, alias } => {
+                let path = self.identifier_constant(path);
+                let global = alias.map(|alias| self.declare_variable(alias));
+
+                self.emit_operand(OpCode::Import, path);
+                self.emit_byte(OpCode::Pop);
+                match global {
+                    Some(global) => self.define_variable(global),
+                    None => self.emit_byte(OpCode::ImportAll),
+                }
+            },
+        }
+
+        self.span = outer;
+    }
+
+    fn return_statement(&mut self, value: Option<&Expr<'a>>) {
+        if self.function.function_type == FunctionType::Script {
+            self.error_at(self.span, "return", "Can't return from top-level code.");
+        }
+
+        match value {
+            None => self.emit_return(),
+            Some(value) => {
+                if self.function.function_type == FunctionType::Initializer {
+                    self.error_at(self.span, "return", "Can't return a value from an initializer.");
+                }
+                self.expression(value);
+
+                // A try block's handler belongs to this frame, so the call has to return here
+                let end = self.function.chunk.code.len();
+                let is_call = matches!(value.kind, ExprKind::Call(..));
+                if is_call && self.function.try_depth == 0 && self.function.function_type != FunctionType::Script
+                    && self.last_call == end.checked_sub(2) {
+                    self.function.chunk.code[end - 2] = OpCode::TailCall.into();
+                }
+                self.emit_byte(OpCode::Return);
+            },
+        }
+    }
+
+    // Laid out as the single-pass compiler does it
+    fn for_statement(&mut self, initializer: Option<&Stmt<'a>>, condition: Option<&Expr<'a>>, increment: Option<&Expr<'a>>, body: &Stmt<'a>) {
+        self.begin_scope();
+        if let Some(initializer) = initializer {
+            self.statement(initializer);
+        }
+
+        let mut loop_start = self.function.chunk.code.len();
+        let exit_jump = condition.map(|condition| {
+            self.expression(condition);
+            let jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_byte(OpCode::Pop);
+            jump
+        });
+
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.function.chunk.code.len();
+            self.expression(increment);
+            self.emit_byte(OpCode::Pop);
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(body, loop_start);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+        self.end_scope();
+    }
+
+    fn for_in_statement(&mut self, name: Identifier<'a>, iterable: &Expr<'a>, body: &Stmt<'a>) {
+        self.begin_scope();
+        self.expression(iterable);
+        let for_in = self.begin_for_in();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement(body);
+        self.end_for_in(for_in);
+        self.end_scope();
+    }
+
+    fn loop_body(&mut self, body: &Stmt<'a>, start: usize) {
+        self.begin_loop(start);
+        self.statement(body);
+        self.function.loops.pop();
+    }
+
+    fn continue_statement(&mut self) {
+        if !self.emit_continue() {
+            self.error_at(self.span, "continue", "Can't use 'continue' outside of a loop.");
+        }
+    }
+
+    fn try_statement(&mut self, body: &[Stmt<'a>], name: Identifier<'a>, handler: &[Stmt<'a>]) {
+        let handler_jump = self.emit_jump(OpCode::TryBegin);
+        self.function.try_depth += 1;
+        self.begin_scope();
+        self.statements(body);
+        self.end_scope();
+        self.function.try_depth -= 1;
+
+        self.emit_byte(OpCode::TryEnd);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(handler_jump);
+
+        self.begin_scope();
+        self.declare_variable(name);
+        self.mark_initialized();
+        self.mark_used();
+        self.statements(handler);
+        self.end_scope();
+
+        self.patch_jump(end_jump);
+    }
+
+    fn destructuring_declaration(&mut self, names: &[Identifier<'a>], value: &Expr<'a>) {
+        let globals: Vec<usize> = names.iter().map(|&name| self.declare_variable(name)).collect();
+        self.expression(value);
+        self.define_unpacked(globals);
+    }
+
+    fn class_declaration(&mut self, name: Identifier<'a>, methods: &[ast::Method<'a>]) {
+        let name_constant = self.identifier_constant(name.name);
+        self.declare_variable(name);
+        self.emit_operand(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.class_depth += 1;
+        self.named_variable(name);
+        for method in methods {
+            let constant = self.identifier_constant(method.function.name.name);
+            let (function_type, op) = match (method.getter, method.function.name.name) {
+                (true, _) => (FunctionType::Getter, OpCode::Getter),
+                (false, "init") => (FunctionType::Initializer, OpCode::Method),
+                (false, _) => (FunctionType::Method, OpCode::Method),
+            };
+            self.function(&method.function, function_type);
+            self.emit_operand(op, constant);
+        }
+        self.emit_byte(OpCode::Pop);
+        self.class_depth -= 1;
+    }
+
+    // Lowers the function into a new function object, which is left on the
+    // stack as a constant
+    fn function(&mut self, function: &ast::Function<'a>, function_type: FunctionType) {
+        self.begin_function(function_type, Some(function.name.name));
+        self.function.arity = function.params.len();
+        self.function.variadic = function.variadic;
+
+        for &param in &function.params {
+            self.declare_variable(param);
+            self.mark_initialized();
+            self.mark_used();
+        }
+        self.statements(&function.body);
+        self.emit_return();
+
+        let function = self.end_function().into_function();
+        let value = Value::Object(self.heap.alloc(ObjectType::Function(function)));
+        self.emit_constant(value);
+    }
+
+    fn expression(&mut self, expr: &Expr<'a>) {
+        let outer = std::mem::replace(&mut self.span, expr.span);
+
+        match &expr.kind {
+            ExprKind::Nil => self.emit_byte(OpCode::Nil),
+            ExprKind::Bool(true) => self.emit_byte(OpCode::True),
+            ExprKind::Bool(false) => self.emit_byte(OpCode::False),
+            ExprKind::Number(value) => self.emit_constant(*value),
+            ExprKind::String(s) => {
+                let value = self.heap.alloc_str(s.to_string());
+                self.emit_constant(value);
+            },
+            ExprKind::Variable(name) => self.named_variable(*name),
+            ExprKind::Assign(name, value) => self.assignment(*name, value),
+            ExprKind::This => {
+                if self.class_depth == 0 {
+                    self.error_at(expr.span, "this", "Can't use 'this' outside of a class.");
+                } else {
+                    self.named_variable(Identifier { name: "this", span: expr.span });
+                }
+            },
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Unary(op, operand) => {
+                self.expression(operand);
+                match op {
+                    UnaryOp::Not => self.emit_byte(OpCode::Not),
+                    UnaryOp::Negate => self.emit_byte(OpCode::Negate),
+                }
+            },
+            ExprKind::Binary(op, left, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.binary_op(*op);
+            },
+            ExprKind::Logical(op, left, right) => self.logical(*op, left, right),
+            ExprKind::Call(callee, args) => self.call(callee, args),
+            ExprKind::Get { object, name, optional } => {
+                self.expression(object);
+                self.nil_check(*optional);
+                let constant = self.identifier_constant(name.name);
+                self.emit_operand(OpCode::GetProperty, constant);
+            },
+            ExprKind::Set { object, name, value } => {
+                self.expression(object);
+                let constant = self.identifier_constant(name.name);
+                self.expression(value);
+                self.emit_operand(OpCode::SetProperty, constant);
+            },
+            ExprKind::Index(object, index) => {
+                self.expression(object);
+                self.expression(index);
+                self.emit_byte(OpCode::IndexGet);
+            },
+            ExprKind::IndexSet { object, index, value } => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.emit_byte(OpCode::IndexSet);
+            },
+            ExprKind::List(items) => {
+                for item in items {
+                    self.expression(item);
+                }
+                self.emit_bytes(OpCode::BuildList.into(), count(items.len()));
+            },
+            ExprKind::Lambda(function) => self.function(function, FunctionType::Function),
+            ExprKind::Match(value, arms) => self.match_expression(value, arms),
+            ExprKind::OptionalChain(chain) => {
+                self.nil_jumps.push(Vec::new());
+                self.expression(chain);
+                for jump in self.nil_jumps.pop().unwrap_or_default() {
+                    self.patch_jump(jump);
+                }
+            },
+        }
+
+        self.span = outer;
+    }
+
+    fn binary_op(&mut self, op: BinaryOp) {
+        match op {
+            BinaryOp::Add => self.emit_byte(OpCode::Add),
+            BinaryOp::Subtract => self.emit_byte(OpCode::Subtract),
+            BinaryOp::Multiply => self.emit_byte(OpCode::Multiply),
+            BinaryOp::Divide => self.emit_byte(OpCode::Divide),
+            BinaryOp::Modulo => self.emit_byte(OpCode::Modulo),
+            BinaryOp::Power => self.emit_byte(OpCode::Power),
+            BinaryOp::Equal => self.emit_byte(OpCode::Equal),
+            BinaryOp::NotEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
+            BinaryOp::Greater => self.emit_byte(OpCode::Greater),
+            BinaryOp::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
+            BinaryOp::Less => self.emit_byte(OpCode::Less),
+            BinaryOp::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
+            BinaryOp::Range => self.emit_byte(OpCode::Range),
+            BinaryOp::RangeInclusive => self.emit_byte(OpCode::RangeInclusive),
+        }
+    }
+
+    fn logical(&mut self, op: LogicalOp, left: &Expr<'a>, right: &Expr<'a>) {
+        self.expression(left);
+        match op {
+            // If the left operand is falsey it's the result, and the right is skipped
+            LogicalOp::And => {
+                let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                self.expression(right);
+                self.patch_jump(end_jump);
+            },
+            LogicalOp::Or => {
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                let end_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(else_jump);
+                self.emit_byte(OpCode::Pop);
+                self.expression(right);
+                self.patch_jump(end_jump);
+            },
+            LogicalOp::Coalesce => {
+                let end_jump = self.emit_jump(OpCode::JumpIfNotNil);
+                self.emit_byte(OpCode::Pop);
+                self.expression(right);
+                self.patch_jump(end_jump);
+            },
+        }
+    }
+
+    // A parenthesized property is fetched before it's called, as it is in the
+    // single-pass compiler, while calling one straight away is an OP_INVOKE
+    // unless its name is past the one byte operand
+    fn call(&mut self, callee: &Expr<'a>, args: &[Expr<'a>]) {
+        if let ExprKind::Get { object, name, optional } = &callee.kind {
+            self.expression(object);
+            self.nil_check(*optional);
+            let constant = self.identifier_constant(name.name);
+            if let Ok(constant) = u8::try_from(constant) {
+                for arg in args {
+                    self.expression(arg);
+                }
+                self.emit_bytes(OpCode::Invoke.into(), constant);
+                self.emit_byte(count(args.len()));
+                return;
+            }
+            self.emit_operand(OpCode::GetProperty, constant);
+        } else {
+            self.expression(callee);
+        }
+
+        for arg in args {
+            self.expression(arg);
+        }
+        self.last_call = Some(self.function.chunk.code.len());
+        self.emit_bytes(OpCode::Call.into(), count(args.len()));
+    }
+
+    // A nil receiver skips to the end of the optional chain it's in
+    fn nil_check(&mut self, optional: bool) {
+        if optional {
+            let jump = self.emit_jump(OpCode::JumpIfNil);
+            if let Some(jumps) = self.nil_jumps.last_mut() {
+                jumps.push(jump);
+            }
+        }
+    }
+
+    // Compared arm by arm against a copy of the value, as in the single-pass compiler
+    fn match_expression(&mut self, value: &Expr<'a>, arms: &[MatchArm<'a>]) {
+        self.expression(value);
+
+        let mut end_jumps = Vec::new();
+        for arm in arms {
+            let next_arm = arm.pattern.as_ref().map(|pattern| {
+                self.emit_byte(OpCode::Dup);
+                self.expression(pattern);
+                self.emit_byte(OpCode::Equal);
+                let jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                jump
+            });
+
+            self.emit_byte(OpCode::Pop);
+            self.expression(&arm.body);
+
+            if let Some(next_arm) = next_arm {
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_arm);
+                self.emit_byte(OpCode::Pop);
+            }
+        }
+
+        if arms.last().is_none_or(|arm| arm.pattern.is_some()) {
+            self.emit_bytes(OpCode::Pop, OpCode::Nil);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn named_variable(&mut self, name: Identifier<'a>) {
+        match self.resolve_local(name) {
+            Some(slot) => self.emit_get_local(slot),
+            None => {
+                let constant = self.identifier_constant(name.name);
+                self.emit_get_global(name.name, constant);
+            },
+        }
+    }
+
+    fn assignment(&mut self, name: Identifier<'a>, value: &Expr<'a>) {
+        let (set_op, arg, is_const) = match self.resolve_local(name) {
+            Some(slot) => (OpCode::SetLocal, slot, self.function.locals[slot].is_const),
+            None => (OpCode::SetGlobal, self.identifier_constant(name.name), self.const_globals.contains(name.name)),
+        };
+        if is_const {
+            self.error_at(name.span, name.name, &format!("Can't assign to constant '{}'.", name.name));
+        }
+
+        self.expression(value);
+        self.emit_operand(set_op, arg);
+    }
+
+}
+
+impl<'a> CodeGen<'a> for Lowerer<'a, '_> {
+    fn state(&mut self) -> &mut FunctionState<'a> {
+        &mut self.function
+    }
+
+    fn heap(&mut self) -> &mut ObjHeap {
+        self.heap
+    }
+
+    fn const_globals(&mut self) -> &mut HashSet<&'a str> {
+        &mut self.const_globals
+    }
+
+    fn global_slots(&mut self) -> &mut HashMap<String, usize> {
+        &mut self.global_slots
+    }
+
+    fn span(&self) -> Span {
+        self.span
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.span, "", message);
+    }
+
+    // Errors found while lowering point at a name or keyword, and the location
+    // is left out when there's no text to quote
+    fn error_at(&mut self, span: Span, text: &str, message: &str) {
+        let location = if text.is_empty() { String::new() } else { format!(" at '{}'", text) };
+        let error = CompileError { span, location, message: message.to_string() };
+        self.diagnostics.push(Diagnostic::from(&error));
+        self.errors.push(error);
+    }
+
+    fn warn(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+}
+
+// Counts past the limit have already been reported by the parser
+fn count(n: usize) -> u8 {
+    n.min(u8::MAX as usize) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler;
+    use crate::error::Severity;
+
+    fn lower_code(source: &str) -> Vec<u8> {
+        let mut chunk = Chunk::default();
+        compile_with_sink(source, &mut chunk, &mut ObjHeap::default(), &mut Vec::new()).unwrap();
+        chunk.code
+    }
+
+    fn lower_errors(source: &str) -> Vec<String> {
+        let errors = compile_with_sink(source, &mut Chunk::default(), &mut ObjHeap::default(), &mut Vec::new()).unwrap_err();
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_lower_expressions() {
+        // Unlike the single-pass compiler, constants aren't folded
+        assert_eq!(lower_code("print 1 + 2;"), vec![
+            OpCode::Constant.into(), 0,
+            OpCode::Constant.into(), 1,
+            OpCode::Add.into(),
+            OpCode::Print.into(),
+            OpCode::Nil.into(),
+            OpCode::Return.into(),
+        ]);
+    }
+
+    #[test]
+    fn test_lower_lambda() {
+        let mut chunk = Chunk::default();
+        let mut heap = ObjHeap::default();
+        compile_with_sink("var f = fun (a) {};", &mut chunk, &mut heap, &mut Vec::new()).unwrap();
+        let function = heap.as_function(chunk.constant_ref(1).unwrap()).unwrap();
+        assert_eq!((function.arity, function.name.as_deref()), (1, Some("lambda")));
+    }
+
+    #[test]
+    fn test_lower_errors() {
+        assert_eq!(lower_errors("return 1;"), vec!["[line 1] Error at 'return': Can't return from top-level code."]);
+        assert_eq!(lower_errors("const a = 1;\na = 2;"), vec!["[line 2] Error at 'a': Can't assign to constant 'a'."]);
+        assert_eq!(lower_errors("print this;"), vec!["[line 1] Error at 'this': Can't use 'this' outside of a class."]);
+        assert_eq!(lower_errors("{ var a = a; }"), vec!["[line 1] Error at 'a': Can't read local variable in its own initializer."]);
+        assert_eq!(lower_errors("{ var a = 1; var a = 2; }"), vec!["[line 1] Error at 'a': Already a variable with this name in this scope."]);
+    }
+
+    #[test]
+    fn test_lower_errors_match_compiler() {
+        for source in [
+            "const a = 1;\na = 2;",
+            "{ const b = 1; b = 2; }",
+            "continue;",
+            "if (true) { continue; }",
+            "return 1;",
+            "print this;",
+            "{ var a = a; }",
+            "{ var a = 1; var a = 2; }",
+        ] {
+            let single_pass = compiler::compile_with_sink(source, &mut Chunk::default(), &mut ObjHeap::default(), &mut Vec::new()).unwrap_err();
+            let single_pass: Vec<String> = single_pass.iter().map(|e| e.to_string()).collect();
+            assert_eq!(lower_errors(source), single_pass, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_lower_warnings() {
+        let mut warnings: Vec<Diagnostic> = Vec::new();
+        compile_with_sink("{ var a = 1; var b = 2; print b; }", &mut Chunk::default(), &mut ObjHeap::default(), &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+        assert_eq!(warnings[0].message, "Unused variable 'a'.");
+    }
+}
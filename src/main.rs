@@ -2,48 +2,429 @@ extern crate rlox;
 
 use std::io::Result;
 use std::fs::read_to_string;
-use rlox::vm::VM;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use rlox::vm::{InterpretResult, VM};
+use rlox::ast::{self, Stmt, StmtKind};
+use rlox::chunk::{Chunk, BYTECODE_VERSION};
+use rlox::compiler::{DiagnosticSink, COMPILER_REVISION};
+use rlox::error::{Diagnostic, InterpretError};
+use rlox::value::Value;
 
 use rustyline::error::ReadlineError;
-use rustyline::{Editor, Result as RLResult};
+use rustyline::{Config, Editor, Result as RLResult};
 
 fn main()  {
-    let mut args = std::env::args();
-    if args.len() == 1 {
-        if repl().is_err() {
-            eprintln!("Could not instantiate repl!");
-            std::process::exit(74);
-        }
-    } else if args.len() == 2 {
-        let file_name = args.nth(1).unwrap();
-        if run_file(&file_name).is_err() {
-            eprintln!("Could not run file {}", file_name);
-            std::process::exit(74);
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("test").is_some() {
+        let paths: Vec<String> = args.collect();
+        if paths.is_empty() {
+            usage();
+        }
+        std::process::exit(run_tests(&paths));
+    }
+
+    let mut metrics_path = None;
+    let mut emit_path = None;
+    let mut file_name = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metrics" => match args.next() {
+                Some(path) => metrics_path = Some(path),
+                None => usage(),
+            },
+            "--emit" => match args.next() {
+                Some(path) => emit_path = Some(path),
+                None => usage(),
+            },
+            _ if file_name.is_none() => file_name = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    match file_name {
+        None if metrics_path.is_some() || emit_path.is_some() => usage(),
+        None => {
+            if repl().is_err() {
+                eprintln!("Could not instantiate repl!");
+                std::process::exit(74);
+            }
+        },
+        Some(file_name) if emit_path.is_some() => {
+            if emit_file(&file_name, emit_path.as_deref().unwrap()).is_err() {
+                eprintln!("Could not compile file {}", file_name);
+                std::process::exit(74);
+            }
+        },
+        Some(file_name) => {
+            if run_file(&file_name, metrics_path.as_deref()).is_err() {
+                eprintln!("Could not run file {}", file_name);
+                std::process::exit(74);
+            }
+        },
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: rlox [--metrics out.json] [path]");
+    eprintln!("       rlox --emit out.roxc path");
+    eprintln!("       rlox test path...");
+    std::process::exit(64);
+}
+
+fn run_file(file_name: &str, metrics_path: Option<&str>) -> Result<()> {
+    let mut vm = VM::default();
+    vm.options_mut().trace_execution = std::env::var_os("ROXL_TRACE").is_some();
+    vm.options_mut().two_phase = std::env::var_os("ROXL_AST").is_some();
+
+    // Precompiled bytecode, as written by --emit, runs without its source
+    let result = if file_name.ends_with(".roxc") {
+        let bytes = std::fs::read(file_name)?;
+        match Chunk::deserialize(&bytes, vm.heap_mut()) {
+            Ok(chunk) => vm.instruct(chunk),
+            Err(e) => {
+                eprintln!("{}: bad bytecode file ({:?})", file_name, e);
+                std::process::exit(65);
+            },
         }
     } else {
-        eprintln!("Usage: rlox [path]");
-        std::process::exit(64);
+        let program = read_to_string(file_name)?;
+        vm.prefetch_imports(std::path::Path::new(file_name), &program);
+        let chunk = match std::env::var_os("ROXL_CACHE_DIR") {
+            Some(dir) => compile_cached(&mut vm, &program, std::path::Path::new(&dir)),
+            None => vm.compile(&program),
+        };
+        chunk.and_then(|mut chunk| {
+            chunk.source = Some(file_name.to_string());
+            vm.instruct(chunk)
+        })
+    };
+
+    #[cfg(feature = "stats")]
+    if std::env::var_os("ROXL_STATS").is_some() {
+        eprint!("{}", vm.stats());
+    }
+
+    // A program that failed at run time is measured up to the error
+    match result {
+        Ok(metrics) => match metrics_path {
+            Some(path) => std::fs::write(path, metrics.to_json() + "\n"),
+            None => Ok(()),
+        },
+        Err(InterpretError::CompileError(_)) => std::process::exit(65),
+        Err(e) => {
+            report(&e);
+            if let Some(path) = metrics_path {
+                std::fs::write(path, vm.metrics().to_json() + "\n")?;
+            }
+            std::process::exit(70);
+        },
     }
 }
 
-fn run_file(file_name: &str) -> Result<()> {
+// Runs each file, or each .lox file in a directory, in a VM of its own and
+// reports how its std:test tests went. Exits with 1 if any test failed or any
+// file didn't run to the end
+fn run_tests(paths: &[String]) -> i32 {
+    let mut files = Vec::new();
+    for path in paths {
+        match std::fs::read_dir(path) {
+            Ok(entries) => {
+                let mut found: Vec<String> = entries.filter_map(|entry| entry.ok())
+                                                    .map(|entry| entry.path())
+                                                    .filter(|path| path.extension().is_some_and(|e| e == "lox"))
+                                                    .map(|path| path.display().to_string())
+                                                    .collect();
+                found.sort();
+                files.extend(found);
+            },
+            Err(_) => files.push(path.clone()),
+        }
+    }
+
+    let mut status = 0;
+    for file in &files {
+        match run_test_file(file) {
+            Ok((passed, failed)) => {
+                println!("{}: {} passed, {} failed", file, passed, failed);
+                if failed > 0 {
+                    status = 1;
+                }
+            },
+            Err(e) => {
+                report(&e);
+                println!("{}: did not finish", file);
+                status = 1;
+            },
+        }
+    }
+    status
+}
+
+fn run_test_file(file_name: &str) -> std::result::Result<(i64, i64), InterpretError> {
+    let program = read_to_string(file_name).map_err(|e| InterpretError::ValueError(format!("Could not read '{}': {}.", file_name, e)))?;
+    let mut vm = VM::default();
+    vm.options_mut().two_phase = std::env::var_os("ROXL_AST").is_some();
+    vm.prefetch_imports(std::path::Path::new(file_name), &program);
+
+    let mut chunk = vm.compile(&program)?;
+    chunk.source = Some(file_name.to_string());
+    vm.instruct(chunk)?;
+
+    // The file has imported the module already, unless it has no tests
+    vm.interpret("import \"std:test\" as __tests;")?;
+    let count = |name: &str| {
+        let tests = vm.get_global("__tests")?;
+        match vm.heap().as_module(&tests)?.globals.get(name) {
+            Some(Value::Int(n)) => Some(*n),
+            _ => None,
+        }
+    };
+    Ok((count("passed").unwrap_or(0), count("failed").unwrap_or(0)))
+}
+
+// The source path and line table go into the file so errors still point at the source
+fn emit_file(file_name: &str, out_path: &str) -> Result<()> {
     let program = read_to_string(file_name)?;
     let mut vm = VM::default();
-    vm.interpret(&program).unwrap();
-    Ok(())
+
+    match vm.compile(&program) {
+        Ok(mut chunk) => {
+            chunk.source = Some(file_name.to_string());
+            match chunk.serialize(vm.heap()) {
+                Ok(bytes) => std::fs::write(out_path, bytes),
+                Err(e) => {
+                    eprintln!("{}: can't write bytecode ({:?})", file_name, e);
+                    std::process::exit(65);
+                },
+            }
+        },
+        Err(_) => std::process::exit(65),
+    }
+}
+
+// Opt in by setting ROXL_CACHE_DIR, e.g. to .roxl-cache, which git ignores.
+// Chunks are stored under a hash of the compiler version and the source, so a
+// stale entry is simply never looked up again. The warnings compiling it gave
+// are stored next to each chunk and printed again when it's loaded
+fn compile_cached(vm: &mut VM, program: &str, dir: &std::path::Path) -> std::result::Result<Chunk, InterpretError> {
+    let two_phase = vm.options_mut().two_phase;
+    let key = cache_key(program, two_phase);
+    let path = dir.join(format!("{:016x}.roxc", key));
+    let diagnostics_path = dir.join(format!("{:016x}.diagnostics", key));
+
+    if let (Ok(bytes), Ok(diagnostics)) = (std::fs::read(&path), read_to_string(&diagnostics_path)) {
+        if let Ok(chunk) = Chunk::deserialize(&bytes, vm.heap_mut()) {
+            eprint!("{}", diagnostics);
+            return Ok(chunk);
+        }
+    }
+
+    let recorder = Recorder::default();
+    vm.set_diagnostic_sink(Box::new(recorder.clone()));
+    let chunk = vm.compile(program)?;
+
+    // The cache is only an optimization, so failing to write it isn't an error
+    if let Ok(bytes) = chunk.serialize(vm.heap()) {
+        let diagnostics: String = recorder.0.lock().unwrap().iter().map(|d| format!("{}\n", d)).collect();
+        let _ = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&diagnostics_path, diagnostics))
+            .and_then(|_| std::fs::write(&path, bytes));
+    }
+    Ok(chunk)
+}
+
+// Prints diagnostics as they come, like the default sink, and keeps them too
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<Diagnostic>>>);
+
+impl DiagnosticSink for Recorder {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        eprintln!("{}", diagnostic);
+        self.0.lock().unwrap().push(diagnostic);
+    }
+}
+
+// FNV-1a, which unlike std's hasher is stable across Rust releases. The
+// bytecode format and compiler revision can change without a new crate
+// version, and the two front ends don't emit the same code
+fn cache_key(program: &str, two_phase: bool) -> u64 {
+    let version = format!("{} {} {} {}", env!("CARGO_PKG_VERSION"), BYTECODE_VERSION, COMPILER_REVISION, two_phase);
+    version.bytes().chain([0]).chain(program.bytes()).fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Compile errors are printed as they're found, so only runtime errors need reporting
+fn report(error: &InterpretError) {
+    report_with_color(error, false);
+}
+
+fn report_with_color(error: &InterpretError, color: bool) {
+    let message = match error {
+        InterpretError::CompileError(_) => return,
+        InterpretError::RuntimeError(e) => e.to_string(),
+        InterpretError::ValueError(msg) => msg.clone(),
+        InterpretError::FuelExhausted => "Instruction limit reached.".to_string(),
+        InterpretError::Cancelled => "Cancelled.".to_string(),
+    };
+
+    if color {
+        eprintln!("\x1b[31m{}\x1b[0m", message);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+// Read from ~/.roxlrc: `:set <name> <value>` lines change a setting and every
+// other line is Lox source run at the start of the session
+#[derive(Debug, PartialEq, Eq)]
+struct ReplConfig {
+    prompt: String,
+    color: bool,
+    history_size: usize,
+    // Pasted text arrives as a single entry, so multi-line programs are run once
+    bracketed_paste: bool,
+    source: String,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        ReplConfig {
+            prompt: "> ".to_string(),
+            color: false,
+            history_size: 100,
+            bracketed_paste: true,
+            source: String::new(),
+        }
+    }
+}
+
+impl ReplConfig {
+    fn load() -> ReplConfig {
+        let path = match std::env::var("HOME") {
+            Ok(home) => std::path::Path::new(&home).join(".roxlrc"),
+            Err(_) => return ReplConfig::default(),
+        };
+
+        match read_to_string(&path) {
+            Ok(contents) => ReplConfig::parse(&contents).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path.display(), e);
+                ReplConfig::default()
+            }),
+            Err(_) => ReplConfig::default(),
+        }
+    }
+
+    fn parse(contents: &str) -> std::result::Result<ReplConfig, String> {
+        let mut config = ReplConfig::default();
+        let mut source = Vec::new();
+
+        for (n, line) in contents.lines().enumerate() {
+            let setting = match line.trim().strip_prefix(":set ") {
+                Some(setting) => setting.trim(),
+                None => {
+                    source.push(line);
+                    continue;
+                },
+            };
+
+            let (name, value) = setting.split_once(' ').unwrap_or((setting, ""));
+            let value = value.trim();
+            match name {
+                "prompt" => {
+                    config.prompt = value.strip_prefix('"')
+                                         .and_then(|v| v.strip_suffix('"'))
+                                         .unwrap_or(value)
+                                         .to_string();
+                },
+                "color" => config.color = parse_switch(name, value, n + 1)?,
+                "bracketed_paste" => config.bracketed_paste = parse_switch(name, value, n + 1)?,
+                "history_size" => {
+                    config.history_size = value.parse().map_err(|_| {
+                        format!("line {}: history_size must be a number", n + 1)
+                    })?;
+                },
+                _ => return Err(format!("line {}: unknown setting '{}'", n + 1, name)),
+            }
+        }
+
+        config.source = source.join("\n");
+        Ok(config)
+    }
+}
+
+fn parse_switch(name: &str, value: &str, line: usize) -> std::result::Result<bool, String> {
+    match value {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(format!("line {}: {} must be on or off", line, name)),
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Ctrl-C while reading a line is handled by rustyline, but while a program is
+// running it raises SIGINT, which should only stop that program
+fn install_interrupt_handler() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
 }
 
 fn repl() -> RLResult<()> {
-    let mut rl = Editor::<()>::new()?;
+    let config = ReplConfig::load();
+    let mut rl = Editor::<()>::with_config(
+        Config::builder().max_history_size(config.history_size)
+                         .bracketed_paste(config.bracketed_paste)
+                         .build()
+    )?;
+    install_interrupt_handler();
+
+    let mut vm = VM::default();
+    vm.options_mut().trace_execution = std::env::var_os("ROXL_TRACE").is_some();
+    vm.options_mut().two_phase = std::env::var_os("ROXL_AST").is_some();
+    vm.set_interrupt_flag(&INTERRUPTED);
+    define_results(&mut vm);
 
     println!("Welcome to lox.");
 
+    if !config.source.trim().is_empty() {
+        if let Err(e) = vm.interpret(&config.source) {
+            report_with_color(&e, config.color);
+        }
+    }
+
     loop {
-        match rl.readline("> ") {
+        match rl.readline(&config.prompt) {
             Ok(l) => {
-                let mut vm = VM::default();
-                vm.interpret(l.as_str()).unwrap();
                 rl.add_history_entry(l.as_str());
+
+                let (source, timed) = match l.strip_prefix(":time ") {
+                    Some(rest) => (rest, true),
+                    None => (l.as_str(), false),
+                };
+
+                INTERRUPTED.store(false, Ordering::SeqCst);
+
+                // Errors shouldn't end the session
+                match interpret_entry(&mut vm, source) {
+                    Ok(result) if timed => {
+                        println!(
+                            "{} instructions in {:?} (peak stack {}, {} allocations)",
+                            result.instructions, result.elapsed, result.peak_stack, result.allocations
+                        );
+                    },
+                    Ok(_) => {},
+                    Err(e) => report_with_color(&e, config.color),
+                }
             },
             Err(ReadlineError::Eof) => {
                 std::process::exit(0);
@@ -55,3 +436,99 @@ fn repl() -> RLResult<()> {
         };
     }
 }
+
+// The last expression entered is `_`, and the nine before it `_1` to `_9`
+const RESULT_HISTORY: usize = 9;
+
+fn define_results(vm: &mut VM) {
+    vm.set_global("_", Value::Nil);
+    for n in 1..=RESULT_HISTORY {
+        vm.set_global(&format!("_{}", n), Value::Nil);
+    }
+}
+
+// An entry that's a single expression statement is run as an assignment to
+// `_`, and only once it succeeds do the earlier results move down one
+fn interpret_entry(vm: &mut VM, source: &str) -> std::result::Result<InterpretResult, InterpretError> {
+    let expression = match ast::parse(source, &mut Vec::new()).as_deref() {
+        Ok([Stmt { kind: StmtKind::Expression(expr), .. }]) => Some(&source[expr.span.start..expr.span.end]),
+        _ => None,
+    };
+    let Some(expression) = expression else {
+        return vm.interpret(source);
+    };
+
+    let previous = vm.get_global("_").unwrap_or(Value::Nil);
+    let result = vm.interpret(&format!("_ = ({});", expression))?;
+    for n in (2..=RESULT_HISTORY).rev() {
+        let older = vm.get_global(&format!("_{}", n - 1)).unwrap_or(Value::Nil);
+        vm.set_global(&format!("_{}", n), older);
+    }
+    vm.set_global("_1", previous);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_repl_config() {
+        let config = ReplConfig::parse(
+            ":set prompt \"lox> \"\n1 + 2\n  :set color on\n:set history_size 10\n:set bracketed_paste off\n3"
+        ).unwrap();
+        assert_eq!(config, ReplConfig {
+            prompt: "lox> ".to_string(),
+            color: true,
+            history_size: 10,
+            bracketed_paste: false,
+            source: "1 + 2\n3".to_string(),
+        });
+
+        assert_eq!(ReplConfig::parse("").unwrap(), ReplConfig::default());
+        assert!(ReplConfig::parse(":set color maybe").is_err());
+        assert!(ReplConfig::parse(":set history_size lots").is_err());
+        assert!(ReplConfig::parse(":set colour on").is_err());
+    }
+
+    #[test]
+    fn test_compile_cached() {
+        let dir = std::env::temp_dir().join(format!("roxl-cache-{}", std::process::id()));
+        let program = "{ var unused = 1; }";
+
+        let chunk = compile_cached(&mut VM::default(), program, &dir).unwrap();
+        let key = cache_key(program, false);
+        let diagnostics = read_to_string(dir.join(format!("{:016x}.diagnostics", key))).unwrap();
+        assert_eq!(diagnostics, "[line 1] Warning: Unused variable 'unused'.\n");
+
+        // A hit loads the stored chunk without compiling again
+        let recorder = Recorder::default();
+        let mut vm = VM::default();
+        vm.set_diagnostic_sink(Box::new(recorder.clone()));
+        assert_eq!(compile_cached(&mut vm, program, &dir).unwrap().code, chunk.code);
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_repl_results() {
+        let mut vm = VM::default();
+        define_results(&mut vm);
+
+        interpret_entry(&mut vm, "1 + 2;").unwrap();
+        interpret_entry(&mut vm, "var a = 10;").unwrap();
+        interpret_entry(&mut vm, "_ * a;").unwrap();
+        assert!(matches!(vm.get_global("_"), Some(Value::Int(30))));
+        assert!(matches!(vm.get_global("_1"), Some(Value::Int(3))));
+        assert!(matches!(vm.get_global("_2"), Some(Value::Nil)));
+
+        // A failed entry leaves the history alone
+        assert!(interpret_entry(&mut vm, "_ + nil;").is_err());
+        assert!(matches!(vm.get_global("_"), Some(Value::Int(30))));
+
+        interpret_entry(&mut vm, "_1 + 1;").unwrap();
+        assert!(matches!(vm.get_global("_"), Some(Value::Int(4))));
+        assert!(matches!(vm.get_global("_2"), Some(Value::Int(3))));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::heap::ObjHeap;
+use crate::value::{Class, Instance, Native, ObjectType, Value};
+
+// Built into every VM. A script sees these as globals it didn't define, and a
+// global of the same name hides one
+const NATIVES: &[Native] = &[
+    Native { name: "fields", arity: 1, function: fields },
+    Native { name: "methods", arity: 1, function: methods },
+    Native { name: "hasField", arity: 2, function: has_field },
+    Native { name: "getField", arity: 2, function: get_field },
+    Native { name: "setField", arity: 3, function: set_field },
+    Native { name: "freeze", arity: 1, function: freeze },
+    Native { name: "str", arity: 1, function: str },
+];
+
+pub fn lookup(name: &str) -> Option<Native> {
+    NATIVES.iter().find(|native| native.name == name).copied()
+}
+
+// The instance's field names, in the order they were first set
+fn fields(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    let names: Vec<String> = instance(heap, "fields", &args[0])?.fields.iter().map(|(name, _)| name.to_string()).collect();
+    Ok(string_list(heap, names))
+}
+
+// The names of the class's methods, getters included, sorted since a class
+// keeps them in no particular order
+fn methods(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    let class = class(heap, &args[0])?;
+    let mut names: Vec<String> = class.methods.keys().chain(class.getters.keys()).cloned().collect();
+    names.sort();
+    Ok(string_list(heap, names))
+}
+
+fn has_field(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    let name = field_name(heap, "hasField", &args[1])?;
+    Ok(Value::Bool(instance(heap, "hasField", &args[0])?.fields.get(name).is_some()))
+}
+
+fn get_field(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    let name = field_name(heap, "getField", &args[1])?;
+    instance(heap, "getField", &args[0])?.fields.get(name).copied().ok_or_else(|| format!("Undefined field '{}'.", name))
+}
+
+fn set_field(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    let name = field_name(heap, "setField", &args[1])?.to_string();
+    instance(heap, "setField", &args[0])?;
+    heap.check_mutable(&args[0])?;
+    if let Some(instance) = heap.as_instance_mut(&args[0]) {
+        instance.fields.insert(name, args[2]);
+    }
+    Ok(args[2])
+}
+
+// A string as it is, and anything else as print shows it
+fn str(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    if heap.as_str(&args[0]).is_some() {
+        return Ok(args[0]);
+    }
+    let text = heap.display(&args[0]).to_string();
+    Ok(heap.alloc_str(text))
+}
+
+// Makes an instance's fields or a list's items read-only from now on, and
+// returns it, so `var config = freeze(Config());` works
+fn freeze(heap: &mut ObjHeap, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Object(handle) if heap.as_instance(&args[0]).is_some() || heap.as_list(&args[0]).is_some() => {
+            heap.freeze(handle);
+            Ok(args[0])
+        },
+        _ => Err(format!("freeze() expects an instance or a list, not {}.", heap.describe(&args[0]))),
+    }
+}
+
+fn instance<'h>(heap: &'h ObjHeap, native: &str, value: &Value) -> Result<&'h Instance, String> {
+    heap.as_instance(value).ok_or_else(|| format!("{}() expects an instance, not {}.", native, heap.describe(value)))
+}
+
+fn class<'h>(heap: &'h ObjHeap, value: &Value) -> Result<&'h Class, String> {
+    let class = match value {
+        Value::Object(handle) => heap.class(*handle),
+        _ => None,
+    };
+    class.ok_or_else(|| format!("methods() expects a class, not {}.", heap.describe(value)))
+}
+
+fn field_name<'h>(heap: &'h ObjHeap, native: &str, value: &Value) -> Result<&'h str, String> {
+    heap.as_str(value).ok_or_else(|| format!("{}() expects a field name, not {}.", native, heap.describe(value)))
+}
+
+fn string_list(heap: &mut ObjHeap, names: Vec<String>) -> Value {
+    let items = names.into_iter().map(|name| heap.alloc_str(name)).collect();
+    Value::Object(heap.alloc(ObjectType::List(items)))
+}
+
@@ -4,13 +4,18 @@ use std::ops::{Add, Sub};
 pub enum Precedence {
     None,
     Assignment,
+    Coalesce,
     Or,
     And,
     Equality,
     Comparison,
+    // Looser than arithmetic, so 0..n - 1 ends at n - 1
+    Range,
     Term,
     Factor,
     Unary,
+    // Binds tighter than unary minus, so -2 ** 2 is -(2 ** 2)
+    Power,
     Call,
     Primary,
 }
@@ -38,15 +43,18 @@ impl From<Precedence> for usize {
         match value {
             Precedence::None => 0,
             Precedence::Assignment => 1,
-            Precedence::Or => 2,
-            Precedence::And => 3,
-            Precedence::Equality => 4,
-            Precedence::Comparison => 5,
-            Precedence::Term => 6,
-            Precedence::Factor => 7,
-            Precedence::Unary => 8,
-            Precedence::Call => 9,
-            Precedence::Primary => 10,
+            Precedence::Coalesce => 2,
+            Precedence::Or => 3,
+            Precedence::And => 4,
+            Precedence::Equality => 5,
+            Precedence::Comparison => 6,
+            Precedence::Range => 7,
+            Precedence::Term => 8,
+            Precedence::Factor => 9,
+            Precedence::Unary => 10,
+            Precedence::Power => 11,
+            Precedence::Call => 12,
+            Precedence::Primary => 13,
         }
     }
 }
@@ -58,15 +66,18 @@ impl TryFrom<usize> for Precedence {
         match value {
             0 => Ok(Precedence::None),
             1 => Ok(Precedence::Assignment),
-            2 => Ok(Precedence::Or),
-            3 => Ok(Precedence::And),
-            4 => Ok(Precedence::Equality),
-            5 => Ok(Precedence::Comparison),
-            6 => Ok(Precedence::Term),
-            7 => Ok(Precedence::Factor),
-            8 => Ok(Precedence::Unary),
-            9 => Ok(Precedence::Call),
-            10 => Ok(Precedence::Primary),
+            2 => Ok(Precedence::Coalesce),
+            3 => Ok(Precedence::Or),
+            4 => Ok(Precedence::And),
+            5 => Ok(Precedence::Equality),
+            6 => Ok(Precedence::Comparison),
+            7 => Ok(Precedence::Range),
+            8 => Ok(Precedence::Term),
+            9 => Ok(Precedence::Factor),
+            10 => Ok(Precedence::Unary),
+            11 => Ok(Precedence::Power),
+            12 => Ok(Precedence::Call),
+            13 => Ok(Precedence::Primary),
             // Really shouldn't have to be used, since the error is captured in Add and Sub
             _ => Err(())
         }
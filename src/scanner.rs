@@ -1,11 +1,18 @@
-use crate::token::{Token, TokenType};
+use crate::token::{Span, Token, TokenType};
 
-#[derive(Debug, Default)]
+use std::fmt;
+
+#[derive(Debug, Default, Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: u32,
+    // Where the line being scanned begins, for working out columns
+    line_start: usize,
+    // Where the token being scanned begins, as a multi-line string ends on a later line
+    start_line: u32,
+    start_column: u32,
 }
 
 #[derive(Debug)]
@@ -16,14 +23,36 @@ pub enum ScanError {
     BadPeekOffset,
 }
 
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::UnexpectedCharacter => write!(f, "Unexpected character."),
+            ScanError::ExpectedMoreInput => write!(f, "Expected more input."),
+            ScanError::UnterminatedString => write!(f, "Unterminated string."),
+            ScanError::BadPeekOffset => write!(f, "Bad peek offset."),
+        }
+    }
+}
+
 impl <'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Self {
-        Scanner {source, start: 0, current: 0, line: 1}
+        Scanner {source, start: 0, current: 0, line: 1, line_start: 0, start_line: 1, start_column: 1}
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    // The text scanned for the latest token, or what was scanned before an error
+    pub fn span(&self) -> Span {
+        Span { start: self.start, end: self.current, line: self.start_line, column: self.start_column }
     }
 
     pub fn scan_token(&mut self) -> Result<Token<'a>, ScanError> {
         self.skip_whitespace()?;
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = (self.start - self.line_start) as u32 + 1;
 
         if self.is_at_end() { return Ok(self.make_token(TokenType::EOF)); }
 
@@ -32,19 +61,50 @@ impl <'a> Scanner<'a> {
             ')' => Ok(self.make_token(TokenType::RightParen)),
             '{' => Ok(self.make_token(TokenType::LeftBrace)),
             '}' => Ok(self.make_token(TokenType::RightBrace)),
+            '[' => Ok(self.make_token(TokenType::LeftBracket)),
+            ']' => Ok(self.make_token(TokenType::RightBracket)),
             ';' => Ok(self.make_token(TokenType::Semicolon)),
             ',' => Ok(self.make_token(TokenType::Comma)),
-            '.' => Ok(self.make_token(TokenType::Dot)),
+            '.' => {
+                if !self.match_char('.')? { return Ok(self.make_token(TokenType::Dot)); }
+                let token_type = if self.match_char('.')? {
+                    TokenType::DotDotDot
+                } else if self.match_char('=')? {
+                    TokenType::DotDotEqual
+                } else {
+                    TokenType::DotDot
+                };
+                Ok(self.make_token(token_type))
+            },
             '-' => Ok(self.make_token(TokenType::Minus)),
             '+' => Ok(self.make_token(TokenType::Plus)),
             '/' => Ok(self.make_token(TokenType::Slash)),
-            '*' => Ok(self.make_token(TokenType::Star)),
+            '*' => {
+                let token_type = if self.match_char('*')? { TokenType::StarStar } else { TokenType::Star };
+                Ok(self.make_token(token_type))
+            },
+            '^' => Ok(self.make_token(TokenType::Caret)),
+            '?' => {
+                if self.match_char('?')? { return Ok(self.make_token(TokenType::QuestionQuestion)); }
+                if self.match_char('.')? { return Ok(self.make_token(TokenType::QuestionDot)); }
+                Err(ScanError::UnexpectedCharacter)
+            },
+            '%' => Ok(self.make_token(TokenType::Percent)),
+            // A lone underscore is the wildcard; anything after it makes an
+            // identifier, like `__eq__` or the REPL's `_1`
+            '_' if !self.check(is_identifier_char)? => Ok(self.make_token(TokenType::Underscore)),
             '!' => {
                 let token_type = if self.match_char('=')? { TokenType::BangEqual } else { TokenType::Bang };
                 Ok(self.make_token(token_type))
             },
             '=' => {
-                let token_type = if self.match_char('=')? { TokenType::EqualEqual } else { TokenType::Equal };
+                let token_type = if self.match_char('=')? {
+                    TokenType::EqualEqual
+                } else if self.match_char('>')? {
+                    TokenType::EqualGreater
+                } else {
+                    TokenType::Equal
+                };
                 Ok(self.make_token(token_type))
             },
             '<' => {
@@ -57,26 +117,43 @@ impl <'a> Scanner<'a> {
             },
             '"' => self.string(),
             c if c.is_ascii_digit() => self.number(),
-            c if c.is_alphabetic() => self.identifier(),
+            c if c.is_alphabetic() || c == '_' => self.identifier(),
             _ => Err(ScanError::UnexpectedCharacter)
         }
     }
 
     fn identifier(&mut self) -> Result<Token<'a>, ScanError> {
-        while self.check(|c| c.is_ascii_digit() || c.is_alphabetic())? {
+        while self.check(is_identifier_char)? {
             self.advance()?;
         }
         Ok(self.make_token(self.identifier_type()?))
     }
 
     fn identifier_type(&self) -> Result<TokenType, ScanError> {
-        match self.source.chars().nth(self.start).ok_or(ScanError::BadPeekOffset)? {
+        match self.lexeme_char(0)? {
             'a' => Ok(self.check_keyword(1, "nd", TokenType::And)),
-            'c' => Ok(self.check_keyword(1, "lass", TokenType::Class)),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.lexeme_char(1)? {
+                        'a' => Ok(self.check_keyword(2, "tch", TokenType::Catch)),
+                        'l' => Ok(self.check_keyword(2, "ass", TokenType::Class)),
+                        'o' if self.current - self.start > 3 => {
+                            match self.lexeme_char(3)? {
+                                's' => Ok(self.check_keyword(2, "nst", TokenType::Const)),
+                                't' => Ok(self.check_keyword(2, "ntinue", TokenType::Continue)),
+                                _ => Ok(TokenType::Identifier),
+                            }
+                        },
+                        _ => Ok(TokenType::Identifier),
+                    }
+                } else {
+                    Ok(TokenType::Identifier)
+                }
+            }
             'e' => Ok(self.check_keyword(1, "lse", TokenType::Else)),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).ok_or(ScanError::BadPeekOffset)? {
+                    match self.lexeme_char(1)? {
                         'a' => Ok(self.check_keyword(2, "lse", TokenType::False)),
                         'o' => Ok(self.check_keyword(2, "r", TokenType::For)),
                         'u' => Ok(self.check_keyword(2, "n", TokenType::Fun)),
@@ -86,7 +163,18 @@ impl <'a> Scanner<'a> {
                     Ok(TokenType::Identifier)
                 }
             }
-            'i' => Ok(self.check_keyword(1, "f", TokenType::If)),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.lexeme_char(1)? {
+                        'f' => Ok(self.check_keyword(2, "", TokenType::If)),
+                        'm' => Ok(self.check_keyword(2, "port", TokenType::Import)),
+                        _ => Ok(TokenType::Identifier),
+                    }
+                } else {
+                    Ok(TokenType::Identifier)
+                }
+            }
+            'm' => Ok(self.check_keyword(1, "atch", TokenType::Match)),
             'n' => Ok(self.check_keyword(1, "il", TokenType::Nil)),
             'o' => Ok(self.check_keyword(1, "r", TokenType::Or)),
             'p' => Ok(self.check_keyword(1, "rint", TokenType::Print)),
@@ -94,9 +182,21 @@ impl <'a> Scanner<'a> {
             's' => Ok(self.check_keyword(1, "uper", TokenType::Super)),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).ok_or(ScanError::BadPeekOffset)? {
-                        'h' => Ok(self.check_keyword(2, "is", TokenType::This)),
-                        'r' => Ok(self.check_keyword(2, "ue", TokenType::True)),
+                    match self.lexeme_char(1)? {
+                        'h' if self.current - self.start > 2 => {
+                            match self.lexeme_char(2)? {
+                                'i' => Ok(self.check_keyword(3, "s", TokenType::This)),
+                                'r' => Ok(self.check_keyword(3, "ow", TokenType::Throw)),
+                                _ => Ok(TokenType::Identifier),
+                            }
+                        },
+                        'r' if self.current - self.start > 2 => {
+                            match self.lexeme_char(2)? {
+                                'u' => Ok(self.check_keyword(3, "e", TokenType::True)),
+                                'y' => Ok(self.check_keyword(3, "", TokenType::Try)),
+                                _ => Ok(TokenType::Identifier),
+                            }
+                        },
                         _ => Ok(TokenType::Identifier),
                     }
                 } else {
@@ -109,6 +209,11 @@ impl <'a> Scanner<'a> {
         }
     }
 
+    // Keywords are all ASCII, so comparing the lexeme a byte at a time is enough
+    fn lexeme_char(&self, offset: usize) -> Result<char, ScanError> {
+        self.source.as_bytes().get(self.start + offset).map(|&b| b as char).ok_or(ScanError::BadPeekOffset)
+    }
+
     fn check_keyword(&self, start: usize, rest: &'a str, token_type: TokenType) -> TokenType {
         let offset = self.start + start;
         if self.current - self.start == start + rest.len() && self.source[offset..offset + rest.len()] == rest[..] {
@@ -120,7 +225,10 @@ impl <'a> Scanner<'a> {
 
     fn string(&mut self) -> Result<Token<'a>, ScanError> {
         while self.check(|c| c != '"')? && !self.is_at_end() {
-            if self.check(|c| c == '\n')? { self.line += 1; }
+            if self.check(|c| c == '\n')? {
+                self.line += 1;
+                self.line_start = self.current + 1;
+            }
             self.advance()?;
         }
 
@@ -131,6 +239,15 @@ impl <'a> Scanner<'a> {
     }
 
     fn number(&mut self) -> Result<Token<'a>, ScanError> {
+        if self.lexeme_char(0)? == '0' {
+            if self.check(|c| c == 'x' || c == 'X')? && self.check_next(|c| c.is_ascii_hexdigit())? {
+                return self.radix_number(|c| c.is_ascii_hexdigit());
+            }
+            if self.check(|c| c == 'b' || c == 'B')? && self.check_next(|c| c == '0' || c == '1')? {
+                return self.radix_number(|c| c == '0' || c == '1');
+            }
+        }
+
         while self.check(|c| c.is_ascii_digit())? { self.advance()?; }
 
         if self.check(|c| c == '.')? && self.check_next(|c| c.is_ascii_digit())? {
@@ -139,6 +256,27 @@ impl <'a> Scanner<'a> {
             while self.check(|c| c.is_ascii_digit())? { self.advance()?; }
         }
 
+        // The exponent is only consumed when digits follow it, so `1e` scans as
+        // the number 1 followed by the identifier e
+        if self.check(|c| c == 'e' || c == 'E')? {
+            let signed = self.check_next(|c| c == '+' || c == '-')?;
+            let digit = self.peek_nth(if signed { 2 } else { 1 });
+
+            if digit.map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                self.advance()?;
+                if signed { self.advance()?; }
+
+                while self.check(|c| c.is_ascii_digit())? { self.advance()?; }
+            }
+        }
+
+        Ok(self.make_token(TokenType::Number))
+    }
+
+    fn radix_number<F: Fn(char) -> bool>(&mut self, is_digit: F) -> Result<Token<'a>, ScanError> {
+        self.advance()?;
+        while self.check(&is_digit)? { self.advance()?; }
+
         Ok(self.make_token(TokenType::Number))
     }
 
@@ -149,14 +287,11 @@ impl <'a> Scanner<'a> {
                 Some('\n') => {
                     self.line += 1;
                     self.advance()?;
+                    self.line_start = self.current;
+                },
+                Some('/') if self.peek_next()? == Some('/') => {
+                    while self.check(|c| c != '\n')? && !self.is_at_end() { self.advance()?; }
                 },
-                Some('/') => {
-                    if self.peek_next()? == Some('/') {
-                        while self.check(|c| c != '\n')? && !self.is_at_end() { self.advance()?; }
-                    } else {
-                        return Ok(());
-                    }
-                }
                 _ => { return Ok(()); },
             }
         }
@@ -167,25 +302,21 @@ impl <'a> Scanner<'a> {
             if self.is_at_end() || self.check(|c| c != expected)? {
                 false
             } else {
-                self.current += 1;
+                self.current += expected.len_utf8();
                 true
             }
         )
     }
 
+    // `current` is a byte offset, so it moves past the whole of a multi-byte character
     fn advance(&mut self) -> Result<char, ScanError> {
-        self.current += 1;
-        Ok(self.peek_nth(-1)?.unwrap())
+        let c = self.peek()?.ok_or(ScanError::ExpectedMoreInput)?;
+        self.current += c.len_utf8();
+        Ok(c)
     }
 
     fn peek_next(&self) -> Result<Option<char>, ScanError> {
-        Ok(
-            if self.is_at_end() {
-                None
-            } else {
-                self.peek_nth(1)?
-            }
-        )
+        Ok(self.peek_nth(1))
     }
 
     fn check_next<F: Fn(char) -> bool>(&self, pred: F) -> Result<bool, ScanError> {
@@ -197,21 +328,19 @@ impl <'a> Scanner<'a> {
     }
 
     fn peek(&self) -> Result<Option<char>, ScanError> {
-        self.peek_nth(0)
+        Ok(self.peek_nth(0))
     }
 
-    fn peek_nth(&self, offset: i32) -> Result<Option<char>, ScanError> {
-        Ok(self.source.chars()
-           .nth(((self.current as i32) + offset)
-                .try_into()
-                .map_err(|_| ScanError::BadPeekOffset)?))
+    // The character `offset` characters on from the current one
+    fn peek_nth(&self, offset: usize) -> Option<char> {
+        self.source[self.current..].chars().nth(offset)
     }
 
     fn make_token(&self, token_type: TokenType) -> Token<'a> {
         Token {
             token_type,
             literal: &self.source[self.start..self.current],
-            line: self.line,
+            span: self.span(),
         }
     }
 
@@ -220,6 +349,10 @@ impl <'a> Scanner<'a> {
     }
 }
 
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_digit() || c.is_alphabetic() || c == '_'
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -230,6 +363,8 @@ mod test {
         assert_eq!(test_scan_token(")"), TokenType::RightParen);
         assert_eq!(test_scan_token("{"), TokenType::LeftBrace);
         assert_eq!(test_scan_token("}"), TokenType::RightBrace);
+        assert_eq!(test_scan_token("["), TokenType::LeftBracket);
+        assert_eq!(test_scan_token("]"), TokenType::RightBracket);
         assert_eq!(test_scan_token(";"), TokenType::Semicolon);
         assert_eq!(test_scan_token(","), TokenType::Comma);
         assert_eq!(test_scan_token("."), TokenType::Dot);
@@ -237,10 +372,23 @@ mod test {
         assert_eq!(test_scan_token("+"), TokenType::Plus);
         assert_eq!(test_scan_token("/"), TokenType::Slash);
         assert_eq!(test_scan_token("*"), TokenType::Star);
+        assert_eq!(test_scan_token("%"), TokenType::Percent);
+        assert_eq!(test_scan_token("**"), TokenType::StarStar);
+        assert_eq!(test_scan_token(".."), TokenType::DotDot);
+        assert_eq!(test_scan_token("..="), TokenType::DotDotEqual);
+        assert_eq!(test_scan_token("..."), TokenType::DotDotDot);
+        assert_eq!(test_scan_token("??"), TokenType::QuestionQuestion);
+        assert_eq!(test_scan_token("?."), TokenType::QuestionDot);
+        assert_eq!(test_scan_token("^"), TokenType::Caret);
         assert_eq!(test_scan_token("!"), TokenType::Bang);
         assert_eq!(test_scan_token("!="), TokenType::BangEqual);
         assert_eq!(test_scan_token("="), TokenType::Equal);
         assert_eq!(test_scan_token("=="), TokenType::EqualEqual);
+        assert_eq!(test_scan_token("=>"), TokenType::EqualGreater);
+        assert_eq!(test_scan_token("_"), TokenType::Underscore);
+        test_scan("_12 ", "_12", TokenType::Identifier);
+        test_scan("__eq__(", "__eq__", TokenType::Identifier);
+        test_scan("a_b ", "a_b", TokenType::Identifier);
         assert_eq!(test_scan_token("<"), TokenType::Less);
         assert_eq!(test_scan_token("<="), TokenType::LessEqual);
         assert_eq!(test_scan_token(">"), TokenType::Greater);
@@ -251,6 +399,15 @@ mod test {
     fn test_number() {
         test_scan("   123 ", "123", TokenType::Number);
         test_scan("   123.123 ", "123.123", TokenType::Number);
+        test_scan("1..10", "1", TokenType::Number);
+        test_scan("0xFF;", "0xFF", TokenType::Number);
+        test_scan("0b1010 ", "0b1010", TokenType::Number);
+        test_scan("1.5e-3 ", "1.5e-3", TokenType::Number);
+        test_scan("2E+10 ", "2E+10", TokenType::Number);
+        test_scan("3e8 ", "3e8", TokenType::Number);
+        test_scan("1e ", "1", TokenType::Number);
+        test_scan("0x ", "0", TokenType::Number);
+        test_scan("0b2 ", "0", TokenType::Number);
     }
 
     #[test]
@@ -260,24 +417,36 @@ mod test {
 \"Here's a multiline
 string\"
 ", "\"Here's a multiline\nstring\"", TokenType::String);
+        test_scan("\"héllo\";", "\"héllo\"", TokenType::String);
     }
 
     #[test]
     fn test_keywords() {
         test_scan("and", "and", TokenType::And);
+        test_scan("catch", "catch", TokenType::Catch);
         test_scan("class", "class", TokenType::Class);
+        test_scan("continue", "continue", TokenType::Continue);
+        test_scan("const", "const", TokenType::Const);
+        test_scan("constant", "constant", TokenType::Identifier);
+        test_scan("cont", "cont", TokenType::Identifier);
         test_scan("else", "else", TokenType::Else);
         test_scan("false", "false", TokenType::False);
         test_scan("for", "for", TokenType::For);
         test_scan("fun", "fun", TokenType::Fun);
         test_scan("if", "if", TokenType::If);
+        test_scan("import", "import", TokenType::Import);
+        test_scan("match", "match", TokenType::Match);
         test_scan("nil", "nil", TokenType::Nil);
         test_scan("or", "or", TokenType::Or);
         test_scan("print", "print", TokenType::Print);
         test_scan("return", "return", TokenType::Return);
         test_scan("super", "super", TokenType::Super);
         test_scan("this", "this", TokenType::This);
+        test_scan("throw", "throw", TokenType::Throw);
         test_scan("true", "true", TokenType::True);
+        test_scan("try", "try", TokenType::Try);
+        test_scan("tr", "tr", TokenType::Identifier);
+        test_scan("thrown", "thrown", TokenType::Identifier);
         test_scan("var", "var", TokenType::Var);
         test_scan("while", "while", TokenType::While);
     }
@@ -286,6 +455,24 @@ string\"
     fn test_identifier() {
         test_scan("   blah ", "blah", TokenType::Identifier);
         test_scan("   foo9000 ", "foo9000", TokenType::Identifier);
+        test_scan("café = 1", "café", TokenType::Identifier);
+        test_scan("éf ", "éf", TokenType::Identifier);
+    }
+
+    #[test]
+    fn test_spans() {
+        let mut scanner = Scanner::new("var a =\n  \"x\ny\" + b;");
+        let spans: Vec<Span> = (0..6).map(|_| scanner.scan_token().unwrap().span).collect();
+        assert_eq!(spans[0], Span { start: 0, end: 3, line: 1, column: 1 });
+        assert_eq!(spans[1], Span { start: 4, end: 5, line: 1, column: 5 });
+        // A multi-line string starts where its opening quote is
+        assert_eq!(spans[3], Span { start: 10, end: 15, line: 2, column: 3 });
+        assert_eq!(spans[4], Span { start: 16, end: 17, line: 3, column: 4 });
+        assert_eq!(spans[5], Span { start: 18, end: 19, line: 3, column: 6 });
+
+        let mut scanner = Scanner::new("  @");
+        assert!(scanner.scan_token().is_err());
+        assert_eq!(scanner.span(), Span { start: 2, end: 3, line: 1, column: 3 });
     }
 
     fn test_scan(input: &str, expected: &str, expected_type: TokenType) {
@@ -0,0 +1,97 @@
+use crate::value::Value;
+use crate::error::InterpretError;
+
+use std::ops::{Deref, DerefMut};
+
+// The VM's value stack: a buffer allocated once at its full capacity, with
+// everything below `top` in use. Pushing past the end is a stack overflow
+// rather than a reallocation
+#[derive(Debug, Default)]
+pub struct Stack {
+    values: Box<[Value]>,
+    top: usize,
+}
+
+impl Stack {
+    pub fn new(capacity: usize) -> Self {
+        Stack { values: vec![Value::Nil; capacity].into_boxed_slice(), top: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        let slot = self.values.get_mut(self.top).ok_or_else(overflow)?;
+        *slot = value;
+        self.top += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Value> {
+        self.top = self.top.checked_sub(1)?;
+        Some(self.values[self.top])
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.top = self.top.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.top = 0;
+    }
+
+    // Removes everything from `start` up, like Vec::split_off
+    pub fn split_off(&mut self, start: usize) -> Vec<Value> {
+        let values = self[start..].to_vec();
+        self.truncate(start);
+        values
+    }
+
+    pub fn extend_from_slice(&mut self, values: &[Value]) -> Result<(), InterpretError> {
+        let end = self.top + values.len();
+        self.values.get_mut(self.top..end).ok_or_else(overflow)?.copy_from_slice(values);
+        self.top = end;
+        Ok(())
+    }
+}
+
+impl Deref for Stack {
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        &self.values[..self.top]
+    }
+}
+
+impl DerefMut for Stack {
+    fn deref_mut(&mut self) -> &mut [Value] {
+        &mut self.values[..self.top]
+    }
+}
+
+fn overflow() -> InterpretError {
+    InterpretError::ValueError("Stack overflow.".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stack() {
+        let mut stack = Stack::new(3);
+        stack.push(Value::Int(1)).unwrap();
+        stack.extend_from_slice(&[Value::Int(2), Value::Int(3)]).unwrap();
+        assert_eq!(&stack[..], &[Value::Int(1), Value::Int(2), Value::Int(3)]);
+
+        assert!(stack.push(Value::Nil).is_err());
+        assert!(stack.extend_from_slice(&[Value::Nil]).is_err());
+        assert_eq!(stack.len(), 3);
+
+        assert_eq!(stack.split_off(1), vec![Value::Int(2), Value::Int(3)]);
+        assert_eq!(stack.pop(), Some(Value::Int(1)));
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+    }
+}
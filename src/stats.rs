@@ -0,0 +1,93 @@
+use crate::chunk::{OpCode, OP_TABLE};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+
+// How often each opcode and each source line ran, summed over every run of a
+// VM until it's reset. Unlike InterpretResult this pays for a line lookup on
+// every instruction, so it's only built with the `stats` feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionStats {
+    // Indexed by opcode byte
+    op_counts: [u64; OP_TABLE.len()],
+    // Imported modules share the line numbers of the script that ran them
+    line_counts: HashMap<u32, u64>,
+}
+
+impl Default for ExecutionStats {
+    fn default() -> Self {
+        ExecutionStats { op_counts: [0; OP_TABLE.len()], line_counts: HashMap::new() }
+    }
+}
+
+impl ExecutionStats {
+    pub fn record(&mut self, op: OpCode, line: Option<u32>) {
+        self.op_counts[op as usize] += 1;
+        if let Some(line) = line {
+            *self.line_counts.entry(line).or_default() += 1;
+        }
+    }
+
+    pub fn op_count(&self, op: OpCode) -> u64 {
+        self.op_counts[op as usize]
+    }
+
+    pub fn line_count(&self, line: u32) -> u64 {
+        self.line_counts.get(&line).copied().unwrap_or(0)
+    }
+
+    // Busiest first, ties broken by opcode byte
+    pub fn hottest_ops(&self) -> Vec<(OpCode, u64)> {
+        let mut ops: Vec<(OpCode, u64)> = OP_TABLE.iter()
+                                                  .zip(self.op_counts)
+                                                  .filter(|&(_, count)| count > 0)
+                                                  .map(|(info, count)| (info.op, count))
+                                                  .collect();
+        ops.sort_by_key(|&(op, count)| (cmp::Reverse(count), op as u8));
+        ops
+    }
+
+    // Busiest first, ties broken by line number
+    pub fn hottest_lines(&self) -> Vec<(u32, u64)> {
+        let mut lines: Vec<(u32, u64)> = self.line_counts.iter().map(|(&line, &count)| (line, count)).collect();
+        lines.sort_by_key(|&(line, count)| (cmp::Reverse(count), line));
+        lines
+    }
+}
+
+impl fmt::Display for ExecutionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "== opcodes ==")?;
+        for (op, count) in self.hottest_ops() {
+            writeln!(f, "{:<20} {:>12}", OP_TABLE[op as usize].name, count)?;
+        }
+        writeln!(f, "== lines ==")?;
+        for (line, count) in self.hottest_lines() {
+            writeln!(f, "{:<20} {:>12}", line, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_execution_stats() {
+        let mut stats = ExecutionStats::default();
+        stats.record(OpCode::Constant, Some(1));
+        stats.record(OpCode::Constant, Some(2));
+        stats.record(OpCode::Add, Some(2));
+        stats.record(OpCode::Return, None);
+
+        assert_eq!(stats.op_count(OpCode::Constant), 2);
+        assert_eq!(stats.op_count(OpCode::Negate), 0);
+        assert_eq!(stats.line_count(2), 2);
+        assert_eq!(stats.line_count(3), 0);
+        assert_eq!(stats.hottest_ops()[0], (OpCode::Constant, 2));
+        assert_eq!(stats.hottest_lines(), vec![(2, 2), (1, 1)]);
+        assert!(stats.to_string().starts_with("== opcodes ==\nOP_CONSTANT"));
+    }
+}
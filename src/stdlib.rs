@@ -0,0 +1,9 @@
+// Lox modules built into the interpreter. They're imported by name, with a
+// `std:` prefix no file path can start with
+const MODULES: &[(&str, &str)] = &[
+    ("std:test", include_str!("stdlib/test.lox")),
+];
+
+pub fn source(name: &str) -> Option<&'static str> {
+    MODULES.iter().find(|&&(n, _)| n == name).map(|&(_, source)| source)
+}
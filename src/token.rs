@@ -1,26 +1,43 @@
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TokenType {
     // Single-character tokens
-    LeftParen, RightParen, LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket,
+    Caret, Comma, Dot, Minus, Percent, Plus, Semicolon, Slash, Star, Underscore,
 
     // One or two character tokens
-    Bang, BangEqual, Equal, EqualEqual, Greater,
-    Less, GreaterEqual, LessEqual,
+    Bang, BangEqual, Equal, EqualEqual, EqualGreater, Greater,
+    Less, GreaterEqual, LessEqual, StarStar, DotDot, DotDotEqual, DotDotDot, QuestionQuestion, QuestionDot,
 
     // Literals
     Identifier, String, Number,
 
     // Keywords
-    And, Class, Else, False, For, Fun, If, Nil, Or, Print,
-    Return, Super, This, True, Var, While,
+    And, Catch, Class, Const, Continue, Else, False, For, Fun, If, Import, Match, Nil, Or, Print,
+    Return, Super, This, Throw, True, Try, Var, While,
 
     EOF,
 }
 
+// Where a piece of source is: its byte range, and the line and column (both
+// counted from 1) it begins at
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    // From the start of this span to the end of `end`
+    pub fn to(self, end: Span) -> Span {
+        Span { end: end.end.max(self.end), ..self }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub literal: &'a str,
-    pub line: u32,
+    pub span: Span,
 }
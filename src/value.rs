@@ -1,88 +1,368 @@
-use crate::error::InterpretError;
+use crate::chunk::Chunk;
+use crate::heap::ObjHeap;
 
+use std::collections::HashMap;
 use std::fmt;
+use std::mem;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 use std::num::ParseFloatError;
-use std::ops::{Add, Sub, Mul, Neg, Div};
+use std::ops::{Add, Sub, Mul, Neg, Div, Rem};
+use std::rc::Rc;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug)]
 pub enum ObjectType {
-    Str(String),
+    // Shared with the heap's intern table
+    Str(Rc<str>),
+    Function(Function),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    Module(Module),
+    Range(RangeObject),
+    Native(Native),
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Default)]
+pub struct Function {
+    // Counts the rest parameter, if there is one
+    pub arity: usize,
+    // The last parameter collects any extra arguments into a list
+    pub variadic: bool,
+    pub chunk: Chunk,
+    // None for the top-level script
+    pub name: Option<String>,
+    // The imported module whose globals the function sees; None for the main script's
+    pub module: Option<ObjHandle>,
+}
+
+// Given the heap and the arguments, returns the result or the message of a
+// runtime error
+pub type NativeFn = fn(&mut ObjHeap, &[Value]) -> Result<Value, String>;
+
+// A function built into the VM rather than compiled from Lox
+#[derive(Debug, Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    // Method functions by name
+    pub methods: HashMap<String, ObjHandle>,
+    // Methods run on property access, without call parentheses
+    pub getters: HashMap<String, ObjHandle>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjHandle,
+    pub fields: Table,
+}
+
+// The globals of an imported file, which importers read as its properties
+#[derive(Debug)]
+pub struct Module {
+    pub path: String,
+    pub globals: Table,
+}
+
+// Values by name, in insertion order. An entry never moves once added, so a
+// call site can remember its index and check there before hashing the name
+#[derive(Debug, Default)]
+pub struct Table {
+    entries: Vec<(String, Value)>,
+    indices: HashMap<String, usize>,
+}
+
+impl Table {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.indices.get(name).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.indices.get(name).map(|&i| &mut self.entries[i].1)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    // The value at `index`, as long as that's where `name` lives
+    pub fn get_at(&self, index: usize, name: &str) -> Option<Value> {
+        self.entries.get(index).filter(|(n, _)| n == name).map(|&(_, value)| value)
+    }
+
+    pub fn insert(&mut self, name: String, value: Value) {
+        match self.indices.get(&name) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.indices.insert(name.clone(), self.entries.len());
+                self.entries.push((name, value));
+            },
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Value)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), *value))
+    }
+}
+
+impl Extend<(String, Value)> for Table {
+    fn extend<I: IntoIterator<Item = (String, Value)>>(&mut self, iter: I) {
+        for (name, value) in iter {
+            self.insert(name, value);
+        }
+    }
+}
+
+// A run of integers from `start` up to (or down to, when `step` is negative)
+// `end`, which is always exclusive; an inclusive range is stored one step on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeObject {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+}
+
+impl RangeObject {
+    pub fn len(&self) -> usize {
+        let span = (self.end as i128 - self.start as i128) / self.step as i128;
+        span.max(0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<i64> {
+        if index >= self.len() { return None; }
+        Some(self.start + index as i64 * self.step)
+    }
+}
+
+// A method looked up on an instance, remembering the instance to call it on
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjHandle,
+}
+
+// Index of an object living in the VM's heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjHandle(pub(crate) usize);
+
+// Equality here is key equality (NaN equals itself, an integral Number equals
+// the Int of the same value, objects compare by handle), so Values can key hash
+// tables; the VM's `==` goes through ObjHeap::equal instead
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    Object(ObjectType),
+    // Integer literals and arithmetic on them, which falls back to Number on overflow
+    Int(i64),
+    Object(ObjHandle),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    // Exact for an Int raised to a non-negative Int, unless that overflows
+    pub fn pow(self, exponent: Value) -> Option<Value> {
+        match (self, exponent) {
+            (Value::Int(base), Value::Int(exponent)) if exponent >= 0 => {
+                let exact = u32::try_from(exponent).ok().and_then(|e| base.checked_pow(e));
+                Some(exact.map_or(Value::Number((base as f64).powf(exponent as f64)), Value::Int))
+            },
+            _ => Some(Value::Number(self.as_f64()?.powf(exponent.as_f64()?))),
+        }
+    }
+
+    fn number_key(&self) -> Option<NumberKey> {
+        match self {
+            Value::Number(n) => Some(number_key(*n)),
+            Value::Int(i) => Some(NumberKey::Int(*i)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum NumberKey {
+    Int(i64),
+    Float(u64),
+}
+
+// Integral floats (including -0) key as the matching integer, and every NaN
+// shares a single pattern
+fn number_key(n: f64) -> NumberKey {
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        NumberKey::Int(n as i64)
+    } else if n.is_nan() {
+        NumberKey::Float(f64::NAN.to_bits())
+    } else {
+        NumberKey::Float(n.to_bits())
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            _ => match (self.number_key(), other.number_key()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.number_key() {
+            Some(key) => key.hash(state),
+            None => mem::discriminant(self).hash(state),
+        }
+        match self {
+            Value::Bool(b) => b.hash(state),
+            Value::Object(h) => h.hash(state),
+            _ => {},
+        }
+    }
+}
+
+// Numbers of either kind compare by value; other kinds only against their own
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Nil, Value::Nil) => Some(Ordering::Equal),
+            (Value::Object(a), Value::Object(b)) => a.partial_cmp(b),
+            _ => self.as_f64()?.partial_cmp(&other.as_f64()?),
+        }
+    }
 }
 
+// Significant digits shown for numbers with a fractional part, unless the
+// formatter asks for a different precision (e.g. `{:.6}`)
+pub const DEFAULT_PRECISION: usize = 15;
+
+fn fmt_number(f: &mut fmt::Formatter<'_>, n: f64) -> fmt::Result {
+    if n.fract() == 0.0 || !n.is_finite() {
+        return write!(f, "{}", n);
+    }
+
+    // Rounding away the last few digits hides artifacts like 0.30000000000000004
+    let precision = f.precision().unwrap_or(DEFAULT_PRECISION).max(1);
+    let rounded: f64 = format!("{:.*e}", precision - 1, n).parse().unwrap_or(n);
+    write!(f, "{}", rounded)
+}
+
+// Objects can only be shown through the heap that owns them (see ObjHeap::display)
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Bool(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Object(ObjectType::Str(s)) => write!(f, "\"{}\"", s),
+            Value::Number(n) => fmt_number(f, *n),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Object(ObjHandle(idx)) => write!(f, "<object {}>", idx),
         }
     }
 }
 
+// Arithmetic is only defined on numbers here; the VM handles string concatenation
+// and reports operands that don't fit. Two Ints give an Int unless the result
+// overflows, and anything involving a Number gives a Number
+
+fn floats(a: Value, b: Value) -> Option<(f64, f64)> {
+    Some((a.as_f64()?, b.as_f64()?))
+}
+
 impl Add<Value> for Value {
-    type Output = Result<Self, InterpretError>;
+    type Output = Option<Self>;
 
     fn add(self, o: Value) -> Self::Output {
         match (self, o) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 + n2)),
-            (Value::Object(ObjectType::Str(s1)), Value::Object(ObjectType::Str(s2))) => {
-                Ok(Value::Object(ObjectType::Str(s1 + &s2)))
-            },
-            _ => Err(InterpretError::ValueError("Can only add 2 number or string values")),
+            (Value::Int(i1), Value::Int(i2)) => Some(i1.checked_add(i2).map_or(Value::Number(i1 as f64 + i2 as f64), Value::Int)),
+            _ => floats(self, o).map(|(n1, n2)| Value::Number(n1 + n2)),
         }
     }
 }
 
 impl Sub<Value> for Value {
-    type Output = Result<Self, InterpretError>;
+    type Output = Option<Self>;
 
     fn sub(self, o: Value) -> Self::Output {
         match (self, o) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 - n2)),
-            _ => Err(InterpretError::ValueError("Can only subtract 2 number values")),
+            (Value::Int(i1), Value::Int(i2)) => Some(i1.checked_sub(i2).map_or(Value::Number(i1 as f64 - i2 as f64), Value::Int)),
+            _ => floats(self, o).map(|(n1, n2)| Value::Number(n1 - n2)),
         }
     }
 }
 
 impl Mul<Value> for Value {
-    type Output = Result<Self, InterpretError>;
+    type Output = Option<Self>;
 
     fn mul(self, o: Value) -> Self::Output {
         match (self, o) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 * n2)),
-            _ => Err(InterpretError::ValueError("Can only multiply 2 number values")),
+            (Value::Int(i1), Value::Int(i2)) => Some(i1.checked_mul(i2).map_or(Value::Number(i1 as f64 * i2 as f64), Value::Int)),
+            _ => floats(self, o).map(|(n1, n2)| Value::Number(n1 * n2)),
         }
     }
 }
 
+// Always a Number, so 7 / 2 is 3.5
 impl Div<Value> for Value {
-    type Output = Result<Self, InterpretError>;
+    type Output = Option<Self>;
 
     fn div(self, o: Value) -> Self::Output {
+        floats(self, o).map(|(n1, n2)| Value::Number(n1 / n2))
+    }
+}
+
+// Truncated like f64's own remainder, so the result takes the sign of the dividend
+impl Rem<Value> for Value {
+    type Output = Option<Self>;
+
+    fn rem(self, o: Value) -> Self::Output {
         match (self, o) {
-            (Value::Number(n1), Value::Number(n2)) => Ok(Value::Number(n1 / n2)),
-            _ => Err(InterpretError::ValueError("Can only divide 2 number values")),
+            // Only None for a zero divisor (or MIN % -1), which float remainder handles
+            (Value::Int(i1), Value::Int(i2)) => Some(i1.checked_rem(i2).map_or(Value::Number(i1 as f64 % i2 as f64), Value::Int)),
+            _ => floats(self, o).map(|(n1, n2)| Value::Number(n1 % n2)),
         }
     }
 }
 
 impl Neg for Value {
-    type Output = Result<Self, InterpretError>;
+    type Output = Option<Self>;
 
     fn neg(self) -> Self::Output {
         match self {
-            Value::Number(n) => Ok(Value::Number(-n)),
-            _ => Err(InterpretError::ValueError("Can only negate number values")),
+            Value::Number(n) => Some(Value::Number(-n)),
+            Value::Int(i) => Some(i.checked_neg().map_or(Value::Number(-(i as f64)), Value::Int)),
+            _ => None,
         }
     }
 }
@@ -90,8 +370,143 @@ impl Neg for Value {
 impl FromStr for Value {
     type Err = ParseFloatError;
 
-    // NOTE: Right now we only try to parse numeric strings into Values
+    // NOTE: Right now we only try to parse numeric strings into Values. Without
+    // a decimal point or exponent it's an Int, unless it's too big for one
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let radix = match s.get(..2) {
+            Some("0x") | Some("0X") => Some(16),
+            Some("0b") | Some("0B") => Some(2),
+            _ => None,
+        };
+
+        if let Some(radix) = radix {
+            let digits = &s[2..];
+            if let Ok(i) = i64::from_str_radix(digits, radix) {
+                return Ok(Value::Int(i));
+            }
+            if let Some(n) = digits.chars().try_fold(0.0, |n, c| c.to_digit(radix).map(|d| n * radix as f64 + d as f64)) {
+                return Ok(Value::Number(n));
+            }
+        }
+
+        if !s.contains(['.', 'e', 'E']) {
+            if let Ok(i) = s.parse::<i64>() {
+                return Ok(Value::Int(i));
+            }
+        }
         Ok(Value::Number(s.parse::<f64>()?))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_hash_keys() {
+        let keys: HashSet<Value> = [
+            Value::Number(0.0),
+            Value::Number(-0.0),
+            Value::Number(f64::NAN),
+            Value::Number(-f64::NAN),
+            Value::Number(1.0),
+            Value::Bool(true),
+            Value::Bool(true),
+            Value::Nil,
+            Value::Object(ObjHandle(0)),
+            Value::Object(ObjHandle(0)),
+            Value::Object(ObjHandle(1)),
+        ].into_iter().collect();
+
+        assert_eq!(keys.len(), 7);
+        assert!(keys.contains(&Value::Int(1)));
+        assert!(keys.contains(&Value::Int(0)));
+        assert!(keys.contains(&Value::Number(f64::NAN)));
+        assert!(!keys.contains(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn test_int_arithmetic() {
+        assert!(matches!(Value::Int(2) + Value::Int(3), Some(Value::Int(5))));
+        assert!(matches!(Value::Int(2) * Value::Number(1.5), Some(Value::Number(n)) if n == 3.0));
+        assert!(matches!(Value::Int(7) / Value::Int(2), Some(Value::Number(n)) if n == 3.5));
+        assert!(matches!(Value::Int(-7) % Value::Int(3), Some(Value::Int(-1))));
+        assert!(matches!(-Value::Int(4), Some(Value::Int(-4))));
+
+        // Overflow falls back to floating point
+        assert!(matches!(Value::Int(i64::MAX) + Value::Int(1), Some(Value::Number(_))));
+        assert!(matches!(-Value::Int(i64::MIN), Some(Value::Number(_))));
+        assert!(matches!(Value::Int(1) % Value::Int(0), Some(Value::Number(n)) if n.is_nan()));
+
+        assert!(Value::Int(1) < Value::Number(1.5));
+        assert!(Value::Int(i64::MAX) > Value::Int(i64::MAX - 1));
+        assert_eq!(Value::Int(3), Value::Number(3.0));
+        assert_ne!(Value::Int(i64::MAX), Value::Number(i64::MAX as f64));
+        assert_eq!(Value::Int(-12).to_string(), "-12");
+
+        assert!(matches!("12".parse(), Ok(Value::Int(12))));
+        assert!(matches!("12.0".parse(), Ok(Value::Number(_))));
+        assert!(matches!("99999999999999999999".parse(), Ok(Value::Number(_))));
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::default();
+        table.insert("a".to_string(), Value::Int(1));
+        table.insert("b".to_string(), Value::Int(2));
+        table.insert("a".to_string(), Value::Int(3));
+
+        assert_eq!(table.get("a"), Some(&Value::Int(3)));
+        assert_eq!(table.index_of("b"), Some(1));
+        assert_eq!(table.get_at(1, "b"), Some(Value::Int(2)));
+        assert_eq!(table.get_at(1, "a"), None);
+        assert_eq!(table.get_at(2, "a"), None);
+        assert_eq!(table.iter().collect::<Vec<_>>(), vec![("a", Value::Int(3)), ("b", Value::Int(2))]);
+    }
+
+    #[test]
+    fn test_range_object() {
+        let up = RangeObject { start: 1, end: 4, step: 1 };
+        assert_eq!(up.len(), 3);
+        assert_eq!((0..4).map(|i| up.get(i)).collect::<Vec<_>>(), vec![Some(1), Some(2), Some(3), None]);
+
+        let down = RangeObject { start: 3, end: 0, step: -1 };
+        assert_eq!(down.get(2), Some(1));
+        assert_eq!(down.len(), 3);
+
+        assert!(RangeObject { start: 2, end: 2, step: 1 }.is_empty());
+        assert_eq!(RangeObject { start: i64::MIN, end: i64::MAX, step: 1 }.len(), usize::MAX);
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert!(matches!("0xFF".parse(), Ok(Value::Int(255))));
+        assert!(matches!("0Xff".parse(), Ok(Value::Int(255))));
+        assert!(matches!("0b1010".parse(), Ok(Value::Int(10))));
+        assert!(matches!("0x10000000000000000".parse(), Ok(Value::Number(n)) if n == 18446744073709551616.0));
+        assert!(matches!("1.5e-3".parse(), Ok(Value::Number(n)) if n == 0.0015));
+        assert!(matches!("3e8".parse(), Ok(Value::Number(n)) if n == 3e8));
+        assert!("0xZZ".parse::<Value>().is_err());
+    }
+
+    #[test]
+    fn test_remainder() {
+        assert_eq!(Value::Number(7.0) % Value::Number(3.0), Some(Value::Number(1.0)));
+        assert_eq!(Value::Number(-7.0) % Value::Number(3.0), Some(Value::Number(-1.0)));
+        assert_eq!(Value::Number(5.5) % Value::Number(2.0), Some(Value::Number(1.5)));
+        assert_eq!(Value::Number(1.0) % Value::Nil, None);
+    }
+
+    #[test]
+    fn test_number_display() {
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(-0.0).to_string(), "-0");
+        assert_eq!(Value::Number(1e20).to_string(), "100000000000000000000");
+        assert_eq!(Value::Number(1.5).to_string(), "1.5");
+        assert_eq!(Value::Number(0.1 + 0.2).to_string(), "0.3");
+        assert_eq!(Value::Number(3.0000000000000004).to_string(), "3");
+        assert_eq!(format!("{:.3}", Value::Number(2.0 / 3.0)), "0.667");
+        assert_eq!(format!("{:.3}", Value::Number(2.0)), "2");
+    }
+}
@@ -1,136 +1,2761 @@
-use crate::value::Value;
-use crate::chunk::{Chunk, OpCode};
-use crate::compiler::compile;
-use crate::error::{InterpretError};
+use crate::value::{Value, ObjHandle, ObjectType, Function, Class, Instance, BoundMethod, Module, RangeObject, Table, DEFAULT_PRECISION};
+use crate::chunk::{Chunk, OpCode, OP_TABLE};
+use crate::compiler::{self, DiagnosticSink, Stderr};
+use crate::lower;
+use crate::natives;
+use crate::stdlib;
+use crate::heap::ObjHeap;
+use crate::stack::Stack;
+use crate::error::{CompileError, Diagnostic, InterpretError, RuntimeError, TraceFrame};
+use crate::scanner::Scanner;
+use crate::token::TokenType;
+#[cfg(feature = "stats")]
+use crate::stats::ExecutionStats;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cmp;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FRAMES: usize = 64;
+// Room for every frame to use all 256 of its slots, as in clox
+const DEFAULT_STACK_SIZE: usize = DEFAULT_MAX_FRAMES * 256;
 
 #[derive(Default)]
 pub struct VM {
-    chunk: Option<Chunk>,
+    frames: Vec<CallFrame>,
+    stack: Stack,
+    // Innermost last; one for each try block being executed
+    handlers: Vec<Handler>,
+    heap: ObjHeap,
+    // Survive across interpret calls, so a REPL session keeps its variables
+    globals: Table,
+    // Imported modules by canonical path, so each file only runs once
+    modules: HashMap<PathBuf, ObjHandle>,
+    // Files compiled ahead of their import by prefetch_imports, by canonical path
+    prefetched: HashMap<PathBuf, PrefetchedModule>,
+    // Each native is allocated the first time a script names it
+    natives: HashMap<&'static str, ObjHandle>,
+    interrupt: Option<&'static AtomicBool>,
+    cancellation: CancellationHandle,
+    // Instructions left to run, when the host has set a budget
+    fuel: Option<u64>,
+    metrics: InterpretResult,
+    // Calls to each function this run, named once it finishes
+    calls: HashMap<ObjHandle, u64>,
+    #[cfg(feature = "stats")]
+    stats: ExecutionStats,
+    options: VMOptions,
+    // Where compile errors and warnings go; stderr when unset
+    diagnostics: Option<Box<dyn DiagnosticSink + Send>>,
+}
+
+// A module compiled on another thread, in its own heap, so it comes back as
+// bytecode along with what the compiler had to say about it
+#[derive(Debug)]
+struct PrefetchedModule {
+    bytes: Vec<u8>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Lets another thread stop a running VM. Cancelling takes effect before the
+// next instruction, and is used up by the run it stops
+#[derive(Debug, Clone, Default)]
+pub struct CancellationHandle(Arc<AtomicBool>);
+
+impl CancellationHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VMOptions {
+    // Raise an error on division by zero rather than producing inf or NaN
+    pub strict_division: bool,
+    // Significant digits printed for numbers with a fractional part
+    pub number_precision: usize,
+    // Let `+` stringify a number added to a string instead of raising an error
+    pub coerce_strings: bool,
+    // How deep calls can nest, counting the script, before a stack overflow
+    pub max_frames: usize,
+    // How many values the stack holds before a stack overflow
+    pub stack_size: usize,
+    // Print the stack and each instruction before it runs, like clox's
+    // DEBUG_TRACE_EXECUTION
+    pub trace_execution: bool,
+    // Compile by building an ast and lowering it, rather than in a single pass
+    pub two_phase: bool,
+}
+
+impl Default for VMOptions {
+    fn default() -> Self {
+        VMOptions {
+            strict_division: true,
+            number_precision: DEFAULT_PRECISION,
+            coerce_strings: false,
+            max_frames: DEFAULT_MAX_FRAMES,
+            stack_size: DEFAULT_STACK_SIZE,
+            trace_execution: false,
+            two_phase: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterpretResult {
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub peak_stack: usize,
+    pub allocations: u64,
+    // Indexed by opcode byte
+    pub op_counts: [u64; OP_TABLE.len()],
+    // By function name, so methods of different classes with the same name
+    // share a count. Imported modules count as "script"
+    pub calls: HashMap<String, u64>,
+    // Objects on the heap once the run finished
+    pub live_objects: usize,
+}
+
+// Arrays only derive Default up to 32 elements, and the opcode table has outgrown that
+impl Default for InterpretResult {
+    fn default() -> Self {
+        InterpretResult {
+            instructions: 0,
+            elapsed: Duration::default(),
+            peak_stack: 0,
+            allocations: 0,
+            op_counts: [0; OP_TABLE.len()],
+            calls: HashMap::new(),
+            live_objects: 0,
+        }
+    }
+}
+
+impl InterpretResult {
+    pub fn op_count(&self, op: OpCode) -> u64 {
+        self.op_counts[op as usize]
+    }
+
+    // Only opcodes that actually ran are listed in the histogram. Nothing is
+    // ever freed, so the heap's collected count is always zero for now
+    pub fn to_json(&self) -> String {
+        let histogram = OP_TABLE.iter()
+                                .zip(self.op_counts)
+                                .filter(|&(_, count)| count > 0)
+                                .map(|(info, count)| format!("\"{}\": {}", info.name, count))
+                                .collect::<Vec<_>>()
+                                .join(", ");
+        let mut calls: Vec<_> = self.calls.iter().collect();
+        calls.sort();
+        let calls = calls.iter()
+                         .map(|(name, count)| format!("\"{}\": {}", name, count))
+                         .collect::<Vec<_>>()
+                         .join(", ");
+
+        format!(
+            "{{\"instructions\": {}, \"elapsed_ns\": {}, \"peak_stack\": {}, \"allocations\": {}, \
+             \"heap\": {{\"live\": {}, \"collected\": 0}}, \"calls\": {{{}}}, \"opcodes\": {{{}}}}}",
+            self.instructions, self.elapsed.as_nanos(), self.peak_stack, self.allocations,
+            self.live_objects, calls, histogram
+        )
+    }
+}
+
+// A function invocation. Its locals start at `slots` in the VM stack, where
+// slot zero holds the function itself
+#[derive(Debug, Clone, Copy)]
+struct CallFrame {
+    function: ObjHandle,
     ip: usize,
-    stack: Vec<Value>,
+    slots: usize,
+    // Set when the frame runs `__eq__` for an OP_COMPARE_JUMP, which still has
+    // to jump this far if the result is falsey
+    compare_jump: Option<u16>,
 }
 
-pub struct InterpretResult;
+// Where to resume when a value is thrown: the catch clause's ip in the frame
+// that ran OP_TRY_BEGIN, with the stack as it was then
+#[derive(Debug, Clone, Copy)]
+struct Handler {
+    frames: usize,
+    stack: usize,
+    ip: usize,
+}
+
+// The stack is a copy taken after the instruction ran, bottom first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepResult {
+    pub op: OpCode,
+    pub halted: bool,
+    pub stack: Vec<Value>,
+}
 
 impl VM {
-    pub fn interpret(&mut self, source: &str) -> Result<InterpretResult, InterpretError> {
-        self.chunk = Some(Chunk::default());
+    pub fn with_options(options: VMOptions) -> Self {
+        VM { options, ..Default::default() }
+    }
 
-        if compile(source, self.chunk.as_mut().unwrap()).is_err() {
-            return Err(InterpretError::CompileError);
-        }
+    // Stops with InterpretError::FuelExhausted after `fuel` instructions, so a host
+    // can run untrusted scripts without them looping forever
+    pub fn with_fuel(fuel: u64) -> Self {
+        VM { fuel: Some(fuel), ..Default::default() }
+    }
+
+    // The budget isn't reset between runs. Topping it up after it ran out lets
+    // step() carry on from the instruction that was refused
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn options_mut(&mut self) -> &mut VMOptions {
+        &mut self.options
+    }
+
+    // Also used for the modules the program imports
+    pub fn set_diagnostic_sink(&mut self, sink: Box<dyn DiagnosticSink + Send>) {
+        self.diagnostics = Some(sink);
+    }
 
-        self.ip = 0;
+    pub fn interpret(&mut self, source: &str) -> Result<InterpretResult, InterpretError> {
+        self.load(source)?;
         self.run()
     }
 
     pub fn instruct(&mut self, chunk: Chunk) -> Result<InterpretResult, InterpretError> {
-        self.chunk = Some(chunk);
-        self.ip = 0;
+        self.load_chunk(chunk)?;
         self.run()
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    pub fn load(&mut self, source: &str) -> Result<(), InterpretError> {
+        let chunk = self.compile(source)?;
+        self.start(chunk)
+    }
+
+    // Compiles against this VM's heap without loading, e.g. to serialize the chunk first
+    pub fn compile(&mut self, source: &str) -> Result<Chunk, InterpretError> {
+        self.compile_source(source).map_err(InterpretError::CompileError)
+    }
+
+    fn compile_source(&mut self, source: &str) -> Result<Chunk, Vec<CompileError>> {
+        let mut chunk = Chunk::default();
+        let sink: &mut dyn DiagnosticSink = match &mut self.diagnostics {
+            Some(sink) => sink.as_mut(),
+            None => &mut Stderr,
+        };
+        if self.options.two_phase {
+            lower::compile_with_sink(source, &mut chunk, &mut self.heap, sink)?;
+        } else {
+            compiler::compile_with_sink(source, &mut chunk, &mut self.heap, sink)?;
+        }
+        Ok(chunk)
+    }
+
+    pub fn load_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        // The script's own slot is already on the stack when it starts
+        chunk.verify_with_depth(1)?;
+        self.start(chunk)
+    }
+
+    // Wraps the chunk as the top-level script function and calls it. The stack
+    // is only allocated here, so a changed stack_size applies from the next run
+    fn start(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        let script = self.heap.alloc(ObjectType::Function(Function { chunk, ..Default::default() }));
+
+        self.reset_stack();
+        if self.stack.capacity() != self.options.stack_size {
+            self.stack = Stack::new(self.options.stack_size);
+        }
+        self.metrics = InterpretResult::default();
+        self.calls.clear();
+        self.push(Value::Object(script))?;
+        self.frames.push(CallFrame { function: script, ip: 0, slots: 0, compare_jump: None });
+        Ok(())
+    }
+
+    // Checked before every instruction, so tripping the flag (e.g. from a SIGINT
+    // handler) aborts the running program
+    pub fn set_interrupt_flag(&mut self, flag: &'static AtomicBool) {
+        self.interrupt = Some(flag);
+    }
+
+    // Kept across interpret calls until reset_stats
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &ExecutionStats {
+        &self.stats
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&mut self) {
+        self.stats = ExecutionStats::default();
+    }
+
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.cancellation.clone()
+    }
+
+    // The main script's globals, e.g. for a host to pass values in and out
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).copied()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    pub fn heap(&self) -> &ObjHeap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut ObjHeap {
+        &mut self.heap
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        self.stack.push(value)?;
+        self.metrics.peak_stack = self.metrics.peak_stack.max(self.stack.len());
+        Ok(())
     }
 
     fn pop(&mut self) -> Result<Value, InterpretError> {
-        self.stack.pop().ok_or(InterpretError::RuntimeError)
+        self.stack.pop().ok_or_else(|| InterpretError::ValueError("Stack underflow.".to_string()))
     }
 
     fn peek(&mut self, distance: usize) -> Result<Value, InterpretError> {
         self.stack.get(self.stack.len() - distance - 1)
                   .cloned()
-                  .ok_or(InterpretError::RuntimeError)
+                  .ok_or_else(|| InterpretError::ValueError("Stack underflow.".to_string()))
+    }
+
+    // The cursor is a list or range index, or a byte offset into a string so that each
+    // step lands on the next character boundary
+    fn iter_next(&mut self) -> Result<Option<Value>, InterpretError> {
+        let cursor = match self.peek(0)? {
+            Value::Int(i) => i as usize,
+            _ => return Err(InterpretError::ValueError("Bad bytecode (iterator cursor is not an Int).".to_string())),
+        };
+        let collection = self.peek(1)?;
+
+        let (item, next) = if let Some(items) = self.heap.as_list(&collection) {
+            match items.get(cursor) {
+                Some(&item) => (item, cursor + 1),
+                None => return Ok(None),
+            }
+        } else if let Some(range) = self.heap.as_range(&collection) {
+            match range.get(cursor) {
+                Some(n) => (Value::Int(n), cursor + 1),
+                None => return Ok(None),
+            }
+        } else {
+            let s = self.heap.as_str(&collection)
+                             .ok_or_else(|| InterpretError::ValueError("Bad bytecode (not an iterable).".to_string()))?;
+            match s.get(cursor..).and_then(|rest| rest.chars().next()) {
+                Some(c) => {
+                    self.metrics.allocations += 1;
+                    (self.heap.alloc_str(c.to_string()), cursor + c.len_utf8())
+                },
+                None => return Ok(None),
+            }
+        };
+
+        let top = self.stack.len() - 1;
+        self.stack[top] = Value::Int(next as i64);
+        Ok(Some(item))
     }
 
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        self.handlers.clear();
     }
 
-    fn runtime_error(&mut self, msg: &'static str) {
-        println!("{}", msg);
+    fn locate(&mut self, error: InterpretError, ip: usize) -> InterpretError {
+        match error {
+            InterpretError::ValueError(message) => {
+                let chunk = self.chunk().ok();
+                let line = chunk.and_then(|c| c.get_line(ip));
+                let span = chunk.and_then(|c| c.get_span(ip));
+                let op = chunk.and_then(|c| c.read_op(ip).ok());
+                let trace = self.stack_trace(ip);
 
-        let instruction = self.ip - self.chunk().expect("Expected chunk").code.len() - 1;
-        let line = self.chunk().expect("Expected chunk").get_line(instruction);
-        println!("[line {}] in script", line.expect("Expected line"));
-        self.reset_stack();
+                self.reset_stack();
+                InterpretError::RuntimeError(RuntimeError { message, line, span, op, trace })
+            },
+            e => e,
+        }
+    }
+
+    // Innermost call first
+    fn stack_trace(&self, ip: usize) -> Vec<TraceFrame> {
+        let source = self.frames.first()
+                                .and_then(|f| self.heap.function(f.function))
+                                .and_then(|f| f.chunk.source.clone());
+
+        self.frames.iter().enumerate().rev().filter_map(|(depth, frame)| {
+            let function = self.heap.function(frame.function)?;
+            // Frames below the innermost have already moved past their call
+            let at = if depth == self.frames.len() - 1 { ip } else { frame.ip.saturating_sub(1) };
+            Some(TraceFrame {
+                function: function.name.clone(),
+                line: function.chunk.get_line(at)?,
+                source: source.clone(),
+            })
+        }).collect()
+    }
+
+    // The module the running function was loaded from, or None for the main script
+    fn module(&self) -> Option<ObjHandle> {
+        self.frames.last()
+                   .and_then(|f| self.heap.function(f.function))
+                   .and_then(|f| f.module)
+    }
+
+    fn globals(&self) -> &Table {
+        match self.module().map(|m| self.heap.get(m)) {
+            Some(ObjectType::Module(module)) => &module.globals,
+            _ => &self.globals,
+        }
+    }
+
+    fn globals_mut(&mut self) -> &mut Table {
+        match self.module().map(|m| self.heap.get_mut(m)) {
+            Some(ObjectType::Module(module)) => &mut module.globals,
+            _ => &mut self.globals,
+        }
+    }
+
+    fn frame(&self) -> Result<&CallFrame, InterpretError> {
+        self.frames.last().ok_or_else(no_chunk)
+    }
+
+    fn frame_mut(&mut self) -> Result<&mut CallFrame, InterpretError> {
+        self.frames.last_mut().ok_or_else(no_chunk)
     }
 
     fn chunk(&self) -> Result<&Chunk, InterpretError> {
-        self.chunk.as_ref().ok_or(InterpretError::RuntimeError)
+        let frame = self.frame()?;
+        self.heap.function(frame.function)
+                 .map(|f| &f.chunk)
+                 .ok_or_else(bad_frame)
+    }
+
+    // The running frame alongside its chunk. They borrow separate fields, so
+    // decoding an operand finds both with one lookup and bumps the ip in place
+    fn cursor(&mut self) -> Result<(&mut CallFrame, &Chunk), InterpretError> {
+        let frame = self.frames.last_mut().ok_or_else(no_chunk)?;
+        let chunk = self.heap.function(frame.function)
+                             .map(|f| &f.chunk)
+                             .ok_or_else(bad_frame)?;
+        Ok((frame, chunk))
+    }
+
+    fn ip(&self) -> usize {
+        self.frames.last().map_or(0, |f| f.ip)
+    }
+
+    // Caches where the name lookup at `site` in the running chunk was found
+    fn remember(&mut self, site: usize, index: usize) {
+        if let Some(frame) = self.frames.last() {
+            if let ObjectType::Function(function) = self.heap.get_mut(frame.function) {
+                function.chunk.cache(site, index);
+            }
+        }
     }
 
     fn read_op(&mut self) -> Result<OpCode, InterpretError> {
-        let op = self.chunk()?.read_op(self.ip)?;
-        self.ip += 1;
+        let (frame, chunk) = self.cursor()?;
+        let op = chunk.read_op(frame.ip)?;
+        frame.ip += 1;
         Ok(op)
     }
 
     fn read_byte(&mut self) -> Result<u8, InterpretError> {
-        let op = self.chunk()?.read(self.ip)?;
-        self.ip += 1;
-        Ok(op)
+        let (frame, chunk) = self.cursor()?;
+        let byte = chunk.read(frame.ip)?;
+        frame.ip += 1;
+        Ok(byte)
+    }
+
+    fn read_short(&mut self) -> Result<u16, InterpretError> {
+        let (frame, chunk) = self.cursor()?;
+        let jump = chunk.read_short(frame.ip)?;
+        frame.ip += 2;
+        Ok(jump)
+    }
+
+    // The operand of OP_CONSTANT, or the wider one of OP_CONSTANT_LONG
+    fn read_constant(&mut self, long: bool) -> Result<Value, InterpretError> {
+        let (frame, chunk) = self.cursor()?;
+        let (idx, width) = match long {
+            true => (chunk.read_long(frame.ip)?, 3),
+            false => (chunk.read(frame.ip)?.into(), 1),
+        };
+        let constant = *chunk.constant_ref(idx)?;
+        frame.ip += width;
+        Ok(constant)
+    }
+
+    // Global, class and property names are string constants in the chunk
+    fn read_name(&mut self, long: bool) -> Result<String, InterpretError> {
+        let name = self.read_constant(long)?;
+        self.heap.as_str(&name)
+                 .map(str::to_string)
+                 .ok_or_else(bad_name)
     }
 
     fn binary_op<F>(&mut self, op: F) -> Result<(), InterpretError>
     where
-        F: Fn(Value, Value) -> Result<Value, InterpretError>
+        F: Fn(&ObjHeap, Value, Value) -> Result<Value, InterpretError>
+    {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let result = op(&self.heap, a, b)?;
+        self.push(result)?;
+        Ok(())
+    }
+
+    fn arithmetic_op<F>(&mut self, verb: &str, op: F) -> Result<(), InterpretError>
+    where
+        F: Fn(Value, Value) -> Option<Value>
     {
+        self.binary_op(|heap, a, b| op(a, b).ok_or_else(|| binary_error(heap, verb, &a, &b)))
+    }
+
+    fn add(&mut self) -> Result<(), InterpretError> {
         let b = self.pop()?;
         let a = self.pop()?;
-        self.push(op(a, b)?);
+        let value = self.add_values(a, b)?;
+        self.push(value)?;
+        Ok(())
+    }
+
+    fn add_values(&mut self, a: Value, b: Value) -> Result<Value, InterpretError> {
+        if self.heap.as_str(&a).is_some() || self.heap.as_str(&b).is_some() {
+            if let (Some(s1), Some(s2)) = (self.concat_operand(&a), self.concat_operand(&b)) {
+                let result = [s1, s2].concat();
+                self.metrics.allocations += 1;
+                return Ok(self.heap.alloc_str(result));
+            }
+        }
+        (a + b).ok_or_else(|| binary_error(&self.heap, "add", &a, &b))
+    }
+
+    // The text a value contributes when it's added to a string
+    fn concat_operand(&self, value: &Value) -> Option<Cow<'_, str>> {
+        match value {
+            Value::Number(_) | Value::Int(_) if self.options.coerce_strings => {
+                Some(Cow::Owned(format!("{:.*}", self.options.number_precision, value)))
+            },
+            _ => self.heap.as_str(value).map(Cow::Borrowed),
+        }
+    }
+
+    fn concat(&mut self, count: usize) -> Result<(), InterpretError> {
+        let start = self.stack.len()
+                        .checked_sub(count)
+                        .ok_or_else(|| InterpretError::ValueError("Stack underflow.".to_string()))?;
+        let values = self.stack.split_off(start);
+
+        let strings: Option<Vec<&str>> = values.iter().map(|v| self.heap.as_str(v)).collect();
+        if let Some(result) = strings.map(|s| s.concat()) {
+            self.metrics.allocations += 1;
+            let value = self.heap.alloc_str(result);
+            self.push(value)?;
+        } else {
+            // Fold pairwise so errors name the same operands a chain of Adds would
+            let mut result = values[0];
+            for &v in &values[1..] {
+                result = self.add_values(result, v)?;
+            }
+            self.push(result)?;
+        }
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        let handle = match callee {
+            Value::Object(handle) => handle,
+            _ => return Err(InterpretError::ValueError("Can only call functions and classes.".to_string())),
+        };
+
+        match self.heap.get(handle) {
+            ObjectType::Function(_) => self.call(handle, arg_count),
+            // Calling a class constructs a new instance in the callee's slot, which
+            // init then receives as `this`
+            ObjectType::Class(class) => {
+                let init = class.methods.get("init").copied();
+                let instance = self.heap.alloc(ObjectType::Instance(Instance { class: handle, fields: Table::default() }));
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = Value::Object(instance);
+
+                match init {
+                    Some(init) => self.call(init, arg_count),
+                    None => check_arity(0, arg_count),
+                }
+            },
+            ObjectType::BoundMethod(bound) => {
+                let BoundMethod { receiver, method } = *bound;
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = receiver;
+                self.call(method, arg_count)
+            },
+            // Runs to completion right here, leaving its result in the callee's slot
+            ObjectType::Native(native) => {
+                let native = *native;
+                check_arity(native.arity, arg_count)?;
+                let slot = self.stack.len() - arg_count - 1;
+                let args = self.stack.split_off(slot + 1);
+                let result = (native.function)(&mut self.heap, &args).map_err(InterpretError::ValueError)?;
+                self.stack.truncate(slot);
+                self.push(result)
+            },
+            _ => Err(InterpretError::ValueError("Can only call functions and classes.".to_string())),
+        }
+    }
+
+    fn native(&mut self, name: &str) -> Option<Value> {
+        let native = natives::lookup(name)?;
+        let handle = match self.natives.get(native.name) {
+            Some(&handle) => handle,
+            None => {
+                let handle = self.heap.alloc(ObjectType::Native(native));
+                self.natives.insert(native.name, handle);
+                handle
+            },
+        };
+        Some(Value::Object(handle))
+    }
+
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver = self.peek(arg_count)?;
+        if let Some(module) = self.heap.as_module(&receiver) {
+            let function = module_export(module, name)?;
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = function;
+            return self.call_value(function, arg_count);
+        }
+
+        let instance = self.heap.as_instance(&receiver).ok_or_else(|| {
+            InterpretError::ValueError("Only instances have methods.".to_string())
+        })?;
+
+        // A field holding a function gets called like any other value
+        if let Some(&field) = instance.fields.get(name) {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = field;
+            return self.call_value(field, arg_count);
+        }
+
+        let class = self.heap.class(instance.class);
+        if class.is_some_and(|c| c.getters.contains_key(name)) {
+            return Err(InterpretError::ValueError(format!("'{}' is a getter, so it's read without parentheses.", name)));
+        }
+        let method = class.and_then(|c| c.methods.get(name).copied())
+                          .ok_or_else(|| InterpretError::ValueError(format!("Undefined property '{}'.", name)))?;
+        self.call(method, arg_count)
+    }
+
+    fn import(&mut self, path: &str) -> Result<(), InterpretError> {
+        let import_error = |e: std::io::Error| InterpretError::ValueError(format!("Could not import '{}': {}.", path, e));
+        let builtin = stdlib::source(path);
+
+        // A built-in module goes by its name. A file is relative to the
+        // importing file, when that has one
+        let canonical = match builtin {
+            Some(_) => PathBuf::from(path),
+            None => {
+                let importer = match self.module() {
+                    Some(module) => self.heap.as_module(&Value::Object(module)).map(|m| m.path.clone()),
+                    None => self.frames.first()
+                                       .and_then(|f| self.heap.function(f.function))
+                                       .and_then(|f| f.chunk.source.clone()),
+                };
+                let resolved = match importer.as_deref().and_then(|p| Path::new(p).parent()) {
+                    Some(dir) => dir.join(path),
+                    None => PathBuf::from(path),
+                };
+                fs::canonicalize(resolved).map_err(import_error)?
+            },
+        };
+
+        if let Some(&module) = self.modules.get(&canonical) {
+            self.push(Value::Object(module))?;
+            self.push(Value::Nil)?;
+            return Ok(());
+        }
+
+        let mut chunk = match self.take_prefetched(&canonical) {
+            Some(chunk) => chunk,
+            None => {
+                let source = match builtin {
+                    Some(source) => Cow::Borrowed(source),
+                    None => Cow::Owned(fs::read_to_string(&canonical).map_err(import_error)?),
+                };
+                self.compile_source(&source).map_err(|errors| {
+                    InterpretError::ValueError(format!("Could not compile '{}': {}", path, errors[0]))
+                })?
+            },
+        };
+        chunk.source = Some(canonical.display().to_string());
+
+        // Cached before it runs, so an import cycle sees the partly run module
+        // instead of running it again
+        let module = self.heap.alloc(ObjectType::Module(Module { path: canonical.display().to_string(), globals: Table::default() }));
+        self.modules.insert(canonical, module);
+        let script = self.heap.alloc(ObjectType::Function(Function { chunk, ..Default::default() }));
+        self.adopt(script, module);
+
+        self.push(Value::Object(module))?;
+        self.push(Value::Object(script))?;
+        self.call(script, 0)
+    }
+
+    // Compiles every file the program at `path` imports, directly or through
+    // other imports, before it runs. Compiling a file only needs its source, so
+    // each round of newly found files is compiled in parallel, each into a heap
+    // of its own. A file that can't be read or compiled is left for its import
+    // to deal with, so errors are reported just as they would be without this
+    pub fn prefetch_imports(&mut self, path: &Path, source: &str) {
+        let two_phase = self.options.two_phase;
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+        let mut seen: HashSet<PathBuf> = self.modules.keys().chain(self.prefetched.keys()).cloned().collect();
+        let mut pending: Vec<PathBuf> = import_paths(source, path).into_iter().filter(|p| seen.insert(p.clone())).collect();
+        while !pending.is_empty() {
+            let mut found = Vec::new();
+            for batch in pending.chunks(workers) {
+                let results: Vec<_> = thread::scope(|scope| {
+                    let handles: Vec<_> = batch.iter().map(|path| scope.spawn(move || prefetch(path, two_phase))).collect();
+                    handles.into_iter().map(|handle| handle.join().ok().flatten()).collect()
+                });
+
+                // Taken in import order, whichever thread finished first
+                for (path, result) in batch.iter().zip(results) {
+                    let Some((module, imports)) = result else { continue };
+                    if let Some(module) = module {
+                        self.prefetched.insert(path.clone(), module);
+                    }
+                    found.extend(imports.into_iter().filter(|p| seen.insert(p.clone())));
+                }
+            }
+            pending = found;
+        }
+    }
+
+    // The file's diagnostics are reported now, where compiling it here would have
+    fn take_prefetched(&mut self, path: &Path) -> Option<Chunk> {
+        let module = self.prefetched.remove(path)?;
+        let chunk = Chunk::deserialize(&module.bytes, &mut self.heap).ok()?;
+
+        let sink: &mut dyn DiagnosticSink = match &mut self.diagnostics {
+            Some(sink) => sink.as_mut(),
+            None => &mut Stderr,
+        };
+        for diagnostic in module.diagnostics {
+            sink.report(diagnostic);
+        }
+        Some(chunk)
+    }
+
+    // Ties a function, and every function nested in it, to the module's globals
+    fn adopt(&mut self, function: ObjHandle, module: ObjHandle) {
+        let nested: Vec<ObjHandle> = match self.heap.get_mut(function) {
+            ObjectType::Function(f) => {
+                f.module = Some(module);
+                f.chunk.constants().iter().filter_map(|v| match v {
+                    Value::Object(h) => Some(*h),
+                    _ => None,
+                }).collect()
+            },
+            _ => return,
+        };
+        for handle in nested {
+            if self.heap.function(handle).is_some() {
+                self.adopt(handle, module);
+            }
+        }
+    }
+
+    fn bind_method(&mut self, class: ObjHandle, receiver: Value, name: &str) -> Result<Value, InterpretError> {
+        let method = self.heap.class(class)
+                              .and_then(|c| c.methods.get(name).copied())
+                              .ok_or_else(|| InterpretError::ValueError(format!("Undefined property '{}'.", name)))?;
+
+        let bound = self.heap.alloc(ObjectType::BoundMethod(BoundMethod { receiver, method }));
+        Ok(Value::Object(bound))
+    }
+
+    fn call(&mut self, function: ObjHandle, arg_count: usize) -> Result<(), InterpretError> {
+        let (arity, variadic) = self.heap.function(function).map_or((0, false), |f| (f.arity, f.variadic));
+        let arg_count = if variadic {
+            self.collect_rest(arity - 1, arg_count)?
+        } else {
+            check_arity(arity, arg_count)?;
+            arg_count
+        };
+
+        if self.frames.len() >= self.options.max_frames {
+            return Err(InterpretError::ValueError("Stack overflow.".to_string()));
+        }
+
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { function, ip: 0, slots, compare_jump: None });
+        *self.calls.entry(function).or_default() += 1;
+        Ok(())
+    }
+
+    // Calls in place of the running frame: the callee and its arguments slide
+    // down over the caller's slots, and the caller's frame is dropped, so the
+    // callee returns straight to whoever called the caller
+    fn tail_call(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        let caller = *self.frame()?;
+        let slot = self.stack.len() - arg_count - 1;
+
+        // Popped first so the frame limit counts the call as a replacement, and
+        // put back on failure so the error is reported from the caller
+        self.frames.pop();
+        if let Err(e) = self.call_value(callee, arg_count) {
+            self.frames.push(caller);
+            return Err(e);
+        }
+
+        let len = self.stack.len();
+        self.stack.copy_within(slot..len, caller.slots);
+        self.stack.truncate(caller.slots + len - slot);
+        match self.frames.last_mut().filter(|f| f.slots == slot) {
+            Some(frame) => {
+                frame.slots = caller.slots;
+                frame.compare_jump = caller.compare_jump;
+            },
+            // Nothing was called (a class without init), so the caller has
+            // already returned
+            None => if let Some(offset) = caller.compare_jump {
+                self.finish_compare_jump(offset)?;
+            },
+        }
+        Ok(())
+    }
+
+    // A user-defined `__eq__`, used when both operands of OP_EQUAL are instances
+    fn eq_method(&self, a: &Value, b: &Value) -> Option<ObjHandle> {
+        let instance = self.heap.as_instance(a)?;
+        self.heap.as_instance(b)?;
+        self.heap.class(instance.class)?.methods.get("__eq__").copied()
+    }
+
+    fn finish_compare_jump(&mut self, offset: u16) -> Result<(), InterpretError> {
+        if self.peek(0)?.is_falsey() {
+            self.frame_mut()?.ip += offset as usize;
+        }
         Ok(())
     }
 
+    // Replaces the arguments past the fixed parameters with a single list for
+    // the rest parameter, returning the new argument count
+    fn collect_rest(&mut self, fixed: usize, arg_count: usize) -> Result<usize, InterpretError> {
+        if arg_count < fixed {
+            let msg = format!("Expected at least {} arguments but got {}.", fixed, arg_count);
+            return Err(InterpretError::ValueError(msg));
+        }
+
+        let rest = self.stack.split_off(self.stack.len() - (arg_count - fixed));
+        self.metrics.allocations += 1;
+        let list = self.heap.alloc(ObjectType::List(rest));
+        self.push(Value::Object(list))?;
+        Ok(fixed + 1)
+    }
+
+    // The metrics are filled in however the run ends, so a failed run can still
+    // be measured through metrics()
     fn run(&mut self) -> Result<InterpretResult, InterpretError> {
-        loop {
-            match self.read_op()? {
-                OpCode::Return => {
-                    println!("{}", self.pop()?);
-                    self.chunk()?.disassemble_chunk("ASSEMBLY");
-                    break;
-                },
-                OpCode::Constant => {
-                    let b = self.read_byte()?.into();
-                    let constant = self.chunk()?.read_constant(b)?;
-                    // TODO Figure out how to avoid this clone
-                    self.push(constant.clone());
-                },
-                OpCode::ConstantLong => {
-                    let mut idx: usize = 0;
-                    for _ in 0..=2 {
-                        let b: usize = self.read_byte()?.into();
-                        idx = (idx << 2) + b;
+        let start = Instant::now();
+        let result = self.run_to_end();
+        self.metrics.elapsed += start.elapsed();
+
+        for (&function, &count) in &self.calls {
+            let name = self.heap.function(function).and_then(|f| f.name.as_deref()).unwrap_or("script");
+            *self.metrics.calls.entry(name.to_string()).or_default() += count;
+        }
+        self.metrics.live_objects = self.heap.len();
+        result.map(|_| self.metrics.clone())
+    }
+
+    fn run_to_end(&mut self) -> Result<(), InterpretError> {
+        while !self.advance()?.1 {}
+        Ok(())
+    }
+
+    // What the latest run measured, up to wherever it stopped
+    pub fn metrics(&self) -> &InterpretResult {
+        &self.metrics
+    }
+
+    pub fn step(&mut self) -> Result<StepResult, InterpretError> {
+        let (op, halted) = self.advance()?;
+        Ok(StepResult { op, halted, stack: self.stack.to_vec() })
+    }
+
+    // One instruction without the stack snapshot, which run() has no use for
+    fn advance(&mut self) -> Result<(OpCode, bool), InterpretError> {
+        let ip = self.ip();
+        if self.options.trace_execution {
+            self.trace_instruction(ip)?;
+        }
+        self.execute_next().map_err(|e| self.locate(e, ip))
+    }
+
+    fn trace_instruction(&self, ip: usize) -> Result<(), InterpretError> {
+        print!("          ");
+        for value in self.stack.iter() {
+            print!("[ {} ]", self.heap.display(value));
+        }
+        println!();
+        self.chunk()?.disassemble_instruction(ip, &self.heap);
+        Ok(())
+    }
+
+    fn execute_next(&mut self) -> Result<(OpCode, bool), InterpretError> {
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.checked_sub(1).ok_or(InterpretError::FuelExhausted)?;
+        }
+        if self.cancellation.take() {
+            return Err(InterpretError::Cancelled);
+        }
+
+        #[cfg(feature = "stats")]
+        let line = self.chunk().ok().and_then(|c| c.get_line(self.ip()));
+        let op = self.read_op()?;
+        self.metrics.instructions += 1;
+        self.metrics.op_counts[op as usize] += 1;
+        #[cfg(feature = "stats")]
+        self.stats.record(op, line);
+
+        // A relaxed load keeps the common case cheap; only a raised flag pays for the swap
+        if self.interrupt.is_some_and(|f| f.load(Ordering::Relaxed) && f.swap(false, Ordering::SeqCst)) {
+            return Err(InterpretError::ValueError("Interrupted.".to_string()));
+        }
+
+        // Faults inside a try block are caught like thrown values, with the
+        // message as the value
+        let halted = match self.execute(op) {
+            Err(InterpretError::ValueError(message)) if !self.handlers.is_empty() => {
+                let value = self.heap.alloc_str(message);
+                self.throw(value)?;
+                false
+            },
+            result => result?,
+        };
+        Ok((op, halted))
+    }
+
+    fn throw(&mut self, value: Value) -> Result<(), InterpretError> {
+        let Some(handler) = self.handlers.pop() else {
+            let text = match self.heap.as_str(&value) {
+                Some(s) => s.to_string(),
+                None => self.heap.display(&value).to_string(),
+            };
+            return Err(InterpretError::ValueError(format!("Uncaught exception: {}", text)));
+        };
+
+        self.frames.truncate(handler.frames);
+        self.stack.truncate(handler.stack);
+        self.push(value)?;
+        self.frame_mut()?.ip = handler.ip;
+        Ok(())
+    }
+
+    fn execute(&mut self, op: OpCode) -> Result<bool, InterpretError> {
+        match op {
+            OpCode::Call => {
+                let arg_count = self.read_byte()?.into();
+                let callee = self.peek(arg_count)?;
+                self.call_value(callee, arg_count)?;
+            },
+            OpCode::TailCall => {
+                let arg_count = self.read_byte()?.into();
+                let callee = self.peek(arg_count)?;
+                self.tail_call(callee, arg_count)?;
+            },
+            OpCode::Class | OpCode::ClassLong => {
+                let name = self.read_name(op.is_long())?;
+                let class = self.heap.alloc(ObjectType::Class(Class { name, methods: HashMap::new(), getters: HashMap::new() }));
+                self.push(Value::Object(class))?;
+            },
+            OpCode::GetProperty | OpCode::GetPropertyLong => {
+                let site = self.ip() - 1;
+                let constant = self.read_constant(op.is_long())?;
+                let receiver = self.peek(0)?;
+
+                // Fields shadow methods
+                if let Some(instance) = self.heap.as_instance(&receiver) {
+                    let name = self.heap.as_str(&constant).ok_or_else(bad_name)?;
+                    if let (Some(value), miss) = cached_lookup(&instance.fields, self.chunk()?.cached(site), name) {
+                        if let Some(index) = miss {
+                            self.remember(site, index);
+                        }
+                        self.pop()?;
+                        self.push(value)?;
+                        return Ok(false);
                     }
+                }
 
-                    let constant = self.chunk()?.read_constant(idx)?;
-                    // TODO Figure out how to avoid this clone
-                    self.push(constant.clone());
-                },
-                OpCode::Nil => self.push(Value::Nil),
-                OpCode::True => self.push(Value::Bool(true)),
-                OpCode::False => self.push(Value::Bool(false)),
-                OpCode::Equal => self.binary_op(|a, b| Ok(Value::Bool(a == b)))?,
-                OpCode::Greater => self.binary_op(|a, b| Ok(Value::Bool(a > b)))?,
-                OpCode::Less => self.binary_op(|a, b| Ok(Value::Bool(a < b)))?,
-                OpCode::Add => self.binary_op(|a, b| a + b)?,
-                OpCode::Subtract => self.binary_op(|a, b| a - b)?,
-                OpCode::Multiply => self.binary_op(|a, b| a * b)?,
-                OpCode::Divide => self.binary_op(|a, b| a / b)?,
-                OpCode::Not => {
-                    match self.pop()? {
-                        Value::Bool(b) => self.push(Value::Bool(!b)),
-                        Value::Nil => self.push(Value::Bool(true)),
-                        _ => return Err(InterpretError::ValueError("Expected falsable type")),
+                let name = self.heap.as_str(&constant).map(str::to_string).ok_or_else(bad_name)?;
+                if let Some(module) = self.heap.as_module(&receiver) {
+                    let value = module_export(module, &name)?;
+                    self.pop()?;
+                    self.push(value)?;
+                    return Ok(false);
+                }
+
+                let instance = self.heap.as_instance(&receiver).ok_or_else(|| {
+                    InterpretError::ValueError("Only instances have properties.".to_string())
+                })?;
+
+                // The receiver is already where a getter's `this` goes, and its
+                // return value takes the receiver's place
+                if let Some(&getter) = self.heap.class(instance.class).and_then(|c| c.getters.get(&name)) {
+                    self.call(getter, 0)?;
+                    return Ok(false);
+                }
+
+                let value = self.bind_method(instance.class, receiver, &name)?;
+                self.pop()?;
+                self.push(value)?;
+            },
+            OpCode::SetProperty | OpCode::SetPropertyLong => {
+                let name = self.read_name(op.is_long())?;
+                let value = self.peek(0)?;
+                let receiver = self.peek(1)?;
+                self.heap.check_mutable(&receiver).map_err(InterpretError::ValueError)?;
+                let instance = self.heap.as_instance_mut(&receiver).ok_or_else(|| {
+                    InterpretError::ValueError("Only instances have fields.".to_string())
+                })?;
+                instance.fields.insert(name, value);
+
+                // The assignment's value replaces the instance as the result
+                self.pop()?;
+                self.pop()?;
+                self.push(value)?;
+            },
+            OpCode::BuildList => {
+                let count: usize = self.read_byte()?.into();
+                let start = self.stack.len()
+                                .checked_sub(count)
+                                .ok_or_else(|| InterpretError::ValueError("Stack underflow.".to_string()))?;
+                let items = self.stack.split_off(start);
+                self.metrics.allocations += 1;
+                let list = self.heap.alloc(ObjectType::List(items));
+                self.push(Value::Object(list))?;
+            },
+            OpCode::Unpack => {
+                let count: usize = self.read_byte()?.into();
+                let value = self.pop()?;
+                let Some(items) = self.heap.as_list(&value) else {
+                    let msg = format!("Can only unpack lists, not {}.", self.heap.describe(&value));
+                    return Err(InterpretError::ValueError(msg));
+                };
+                if items.len() != count {
+                    let msg = format!("Expected {} values to unpack but got {}.", count, items.len());
+                    return Err(InterpretError::ValueError(msg));
+                }
+                self.stack.extend_from_slice(items)?;
+                self.metrics.peak_stack = self.metrics.peak_stack.max(self.stack.len());
+            },
+            OpCode::IndexGet => {
+                let index = self.pop()?;
+                let target = self.pop()?;
+
+                // `obj[name]` reads the property the string names, as `obj.name` would
+                if let Some(instance) = self.heap.as_instance(&target) {
+                    let name = self.heap.as_str(&index).map(str::to_string).ok_or_else(not_a_name)?;
+                    if let Some(&value) = instance.fields.get(&name) {
+                        self.push(value)?;
+                        return Ok(false);
                     }
-                },
-                OpCode::Negate => {
-                    let v = self.pop()?;
-                    self.push((-v)?);
-                },
-            };
+                    if let Some(&getter) = self.heap.class(instance.class).and_then(|c| c.getters.get(&name)) {
+                        self.push(target)?;
+                        self.call(getter, 0)?;
+                        return Ok(false);
+                    }
+                    let value = self.bind_method(instance.class, target, &name)?;
+                    self.push(value)?;
+                    return Ok(false);
+                }
+
+                let value = match self.heap.as_str(&target) {
+                    Some(s) => {
+                        let piece = string_index(s, self.heap.as_range(&index), index)?;
+                        self.metrics.allocations += 1;
+                        self.heap.alloc_str(piece)
+                    },
+                    None => {
+                        let items = self.heap.as_list(&target).ok_or_else(not_a_list)?;
+                        items[sequence_index("List", items.len(), index)?]
+                    },
+                };
+                self.push(value)?;
+            },
+            OpCode::IndexSet => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let list = self.pop()?;
+                if self.heap.as_str(&list).is_some() {
+                    return Err(InterpretError::ValueError("Strings can't be modified.".to_string()));
+                }
+                self.heap.check_mutable(&list).map_err(InterpretError::ValueError)?;
+                if self.heap.as_instance(&list).is_some() {
+                    let name = self.heap.as_str(&index).map(str::to_string).ok_or_else(not_a_name)?;
+                    if let Some(instance) = self.heap.as_instance_mut(&list) {
+                        instance.fields.insert(name, value);
+                    }
+                    self.push(value)?;
+                    return Ok(false);
+                }
+                let items = self.heap.as_list_mut(&list).ok_or_else(not_a_list)?;
+                let i = sequence_index("List", items.len(), index)?;
+                items[i] = value;
+                self.push(value)?;
+            },
+            OpCode::Range | OpCode::RangeInclusive => {
+                let end = range_bound(self.pop()?)?;
+                let start = range_bound(self.pop()?)?;
+                let step = if end < start { -1 } else { 1 };
+                let end = if op == OpCode::RangeInclusive {
+                    end.checked_add(step)
+                       .ok_or_else(|| InterpretError::ValueError("Range end is out of bounds.".to_string()))?
+                } else {
+                    end
+                };
+                self.metrics.allocations += 1;
+                let range = self.heap.alloc(ObjectType::Range(RangeObject { start, end, step }));
+                self.push(Value::Object(range))?;
+            },
+            OpCode::IterNew => {
+                let collection = self.peek(0)?;
+                let iterable = self.heap.as_list(&collection).is_some()
+                    || self.heap.as_str(&collection).is_some()
+                    || self.heap.as_range(&collection).is_some();
+                if !iterable {
+                    let msg = format!("Can only iterate over lists, strings and ranges, not {}.", self.heap.describe(&collection));
+                    return Err(InterpretError::ValueError(msg));
+                }
+                self.push(Value::Int(0))?;
+            },
+            OpCode::IterNext => {
+                let offset = self.read_short()?;
+                match self.iter_next()? {
+                    Some(item) => self.push(item)?,
+                    None => self.frame_mut()?.ip += offset as usize,
+                }
+            },
+            OpCode::Method | OpCode::MethodLong | OpCode::Getter | OpCode::GetterLong => {
+                let name = self.read_name(op.is_long())?;
+                let method = match self.peek(0)? {
+                    Value::Object(handle) if self.heap.function(handle).is_some() => handle,
+                    _ => return Err(InterpretError::ValueError("Bad bytecode (method is not a function).".to_string())),
+                };
+                match self.peek(1)? {
+                    Value::Object(class) => match self.heap.get_mut(class) {
+                        ObjectType::Class(class) if matches!(op, OpCode::Getter | OpCode::GetterLong) => class.getters.insert(name, method),
+                        ObjectType::Class(class) => class.methods.insert(name, method),
+                        _ => return Err(InterpretError::ValueError("Bad bytecode (method outside a class).".to_string())),
+                    },
+                    _ => return Err(InterpretError::ValueError("Bad bytecode (method outside a class).".to_string())),
+                };
+                self.pop()?;
+            },
+            OpCode::Invoke => {
+                let name = self.read_name(false)?;
+                let arg_count = self.read_byte()?.into();
+                self.invoke(&name, arg_count)?;
+            },
+            OpCode::Return => {
+                let result = self.pop()?;
+                let frame = self.frames.pop().expect("Expected a call frame");
+                self.stack.truncate(frame.slots);
+                // Returning from inside a try block leaves it
+                let depth = self.frames.len();
+                self.handlers.retain(|h| h.frames <= depth);
+                if self.frames.is_empty() {
+                    return Ok(true);
+                }
+                self.push(result)?;
+                if let Some(offset) = frame.compare_jump {
+                    self.finish_compare_jump(offset)?;
+                }
+            },
+            OpCode::Print => {
+                let value = self.pop()?;
+                match self.heap.as_str(&value) {
+                    Some(s) => println!("{}", s),
+                    None => println!("{:.*}", self.options.number_precision, self.heap.display(&value)),
+                }
+            },
+            OpCode::Pop => {
+                self.pop()?;
+            },
+            OpCode::Dup => {
+                let value = self.peek(0)?;
+                self.push(value)?;
+            },
+            OpCode::Swap => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(InterpretError::ValueError("Stack underflow.".to_string()));
+                }
+                self.stack.swap(len - 1, len - 2);
+            },
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                let name = self.read_name(op.is_long())?;
+                let value = self.pop()?;
+                self.globals_mut().insert(name, value);
+            },
+            // Looked up through the interned name rather than a copy of it, since
+            // every call to a global function lands here
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let site = self.ip() - 1;
+                let name = self.read_constant(op.is_long())?;
+                let name = self.heap.as_str(&name).ok_or_else(bad_name)?;
+                let (value, miss) = cached_lookup(self.globals(), self.chunk()?.cached(site), name);
+                let value = match value {
+                    Some(value) => value,
+                    None => {
+                        let name = name.to_string();
+                        self.native(&name).ok_or_else(|| undefined_variable(&name))?
+                    },
+                };
+                if let Some(index) = miss {
+                    self.remember(site, index);
+                }
+                self.push(value)?;
+            },
+            OpCode::GetLocal | OpCode::GetLocalLong | OpCode::GetLocal0 => {
+                let slot = match op {
+                    OpCode::GetLocal => self.frame()?.slots + self.read_byte()? as usize,
+                    OpCode::GetLocalLong => self.frame()?.slots + self.read_short()? as usize,
+                    _ => self.frame()?.slots,
+                };
+                let value = self.stack.get(slot).copied().ok_or_else(bad_slot)?;
+                self.push(value)?;
+            },
+            OpCode::SetLocal | OpCode::SetLocalLong => {
+                let offset = match op {
+                    OpCode::SetLocal => self.read_byte()? as usize,
+                    _ => self.read_short()? as usize,
+                };
+                let slot = self.frame()?.slots + offset;
+                let value = self.peek(0)?;
+                *self.stack.get_mut(slot).ok_or_else(bad_slot)? = value;
+            },
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let name = self.read_name(op.is_long())?;
+                let value = self.peek(0)?;
+                match self.globals_mut().get_mut(&name) {
+                    Some(slot) => *slot = value,
+                    None => return Err(undefined_variable(&name)),
+                }
+            },
+            OpCode::Constant | OpCode::ConstantLong => {
+                let constant = self.read_constant(op == OpCode::ConstantLong)?;
+                self.push(constant)?;
+            },
+            OpCode::Nil => self.push(Value::Nil)?,
+            OpCode::True => self.push(Value::Bool(true))?,
+            OpCode::False => self.push(Value::Bool(false))?,
+            OpCode::Equal => {
+                // The receiver and argument are already in place, and the
+                // result takes the receiver's place
+                let (a, b) = (self.peek(1)?, self.peek(0)?);
+                match self.eq_method(&a, &b) {
+                    Some(method) => self.call(method, 1)?,
+                    None => self.binary_op(|heap, a, b| Ok(Value::Bool(heap.equal(&a, &b))))?,
+                }
+            },
+            OpCode::Greater => {
+                self.binary_op(|heap, a, b| Ok(Value::Bool(heap.compare(&a, &b) == Some(cmp::Ordering::Greater))))?
+            },
+            OpCode::Less => {
+                self.binary_op(|heap, a, b| Ok(Value::Bool(heap.compare(&a, &b) == Some(cmp::Ordering::Less))))?
+            },
+            OpCode::Add => self.add()?,
+            OpCode::AddConstant => {
+                let b = self.read_constant(false)?;
+                let a = self.pop()?;
+                let value = self.add_values(a, b)?;
+                self.push(value)?;
+            },
+            OpCode::Subtract => self.arithmetic_op("subtract", |a, b| a - b)?,
+            OpCode::Multiply => self.arithmetic_op("multiply", |a, b| a * b)?,
+            OpCode::Divide => {
+                if self.options.strict_division && self.peek(0)? == Value::Number(0.0) {
+                    return Err(InterpretError::ValueError("Division by zero.".to_string()));
+                }
+                self.arithmetic_op("divide", |a, b| a / b)?
+            },
+            OpCode::Modulo => {
+                if self.options.strict_division && self.peek(0)? == Value::Number(0.0) {
+                    return Err(InterpretError::ValueError("Division by zero.".to_string()));
+                }
+                self.arithmetic_op("take the remainder of", |a, b| a % b)?
+            },
+            OpCode::Power => self.arithmetic_op("exponentiate", Value::pow)?,
+            OpCode::Not => {
+                match self.pop()? {
+                    Value::Bool(b) => self.push(Value::Bool(!b))?,
+                    Value::Nil => self.push(Value::Bool(true))?,
+                    v => {
+                        let msg = format!("cannot apply '!' to {}", self.heap.describe(&v));
+                        return Err(InterpretError::ValueError(msg));
+                    },
+                }
+            },
+            OpCode::Negate => {
+                let v = self.pop()?;
+                match -v {
+                    Some(result) => self.push(result)?,
+                    None => {
+                        let msg = format!("cannot negate {}", self.heap.describe(&v));
+                        return Err(InterpretError::ValueError(msg));
+                    },
+                }
+            },
+            OpCode::ConcatN => {
+                let count = self.read_byte()?.into();
+                self.concat(count)?;
+            },
+            OpCode::Jump => {
+                let offset = self.read_short()?;
+                self.frame_mut()?.ip += offset as usize;
+            },
+            OpCode::JumpIfFalse => {
+                let offset = self.read_short()?;
+                if self.peek(0)?.is_falsey() {
+                    self.frame_mut()?.ip += offset as usize;
+                }
+            },
+            OpCode::CompareJump => {
+                let compare = OpCode::try_from(self.read_byte()?)?;
+                if !compare.is_comparison() {
+                    return Err(InterpretError::ValueError("Bad bytecode (OP_COMPARE_JUMP without a comparison).".to_string()));
+                }
+                let offset = self.read_short()?;
+                let frames = self.frames.len();
+                self.execute(compare)?;
+                // An `__eq__` call jumps once it returns
+                if self.frames.len() > frames {
+                    self.frame_mut()?.compare_jump = Some(offset);
+                } else {
+                    self.finish_compare_jump(offset)?;
+                }
+            },
+            OpCode::JumpIfNotNil => {
+                let offset = self.read_short()?;
+                if self.peek(0)? != Value::Nil {
+                    self.frame_mut()?.ip += offset as usize;
+                }
+            },
+            OpCode::Import | OpCode::ImportLong => {
+                let path = self.read_name(op.is_long())?;
+                self.import(&path)?;
+            },
+            OpCode::ImportAll => {
+                let module = self.pop()?;
+                let exports: Vec<(String, Value)> = match self.heap.as_module(&module) {
+                    Some(module) => module.globals.iter().map(|(k, v)| (k.to_string(), v)).collect(),
+                    None => return Err(InterpretError::ValueError("Bad bytecode (import of a non-module).".to_string())),
+                };
+                self.globals_mut().extend(exports);
+            },
+            OpCode::TryBegin => {
+                let offset = self.read_short()?;
+                let handler = Handler { frames: self.frames.len(), stack: self.stack.len(), ip: self.ip() + offset as usize };
+                self.handlers.push(handler);
+            },
+            OpCode::TryEnd => {
+                self.handlers.pop();
+            },
+            OpCode::Throw => {
+                let value = self.pop()?;
+                self.throw(value)?;
+            },
+            OpCode::JumpIfNil => {
+                let offset = self.read_short()?;
+                if self.peek(0)? == Value::Nil {
+                    self.frame_mut()?.ip += offset as usize;
+                }
+            },
+            OpCode::Loop => {
+                let offset = self.read_short()?;
+                self.frame_mut()?.ip -= offset as usize;
+            },
+        };
+        Ok(false)
+    }
+}
+
+fn binary_error(heap: &ObjHeap, verb: &str, a: &Value, b: &Value) -> InterpretError {
+    InterpretError::ValueError(format!("cannot {} {} and {}", verb, heap.describe(a), heap.describe(b)))
+}
+
+fn check_arity(arity: usize, arg_count: usize) -> Result<(), InterpretError> {
+    if arg_count != arity {
+        let msg = format!("Expected {} arguments but got {}.", arity, arg_count);
+        return Err(InterpretError::ValueError(msg));
+    }
+    Ok(())
+}
+
+// Tries the index cached for a lookup site before hashing the name. The second
+// value is the index to cache when the cached one missed
+fn cached_lookup(table: &Table, cached: Option<usize>, name: &str) -> (Option<Value>, Option<usize>) {
+    if let Some(value) = cached.and_then(|i| table.get_at(i, name)) {
+        return (Some(value), None);
+    }
+    match table.index_of(name) {
+        Some(index) => (table.get_at(index, name), Some(index)),
+        None => (None, None),
+    }
+}
+
+fn module_export(module: &Module, name: &str) -> Result<Value, InterpretError> {
+    module.globals.get(name).copied().ok_or_else(|| {
+        InterpretError::ValueError(format!("Undefined variable '{}' in module '{}'.", name, module.path))
+    })
+}
+
+fn not_a_list() -> InterpretError {
+    InterpretError::ValueError("Only lists, strings and instances can be indexed.".to_string())
+}
+
+fn not_a_name() -> InterpretError {
+    InterpretError::ValueError("Property name must be a string.".to_string())
+}
+
+// Negative indices count back from the end, so -1 is the last item
+fn sequence_index(kind: &str, len: usize, index: Value) -> Result<usize, InterpretError> {
+    let i = match index {
+        Value::Int(i) => i as f64,
+        Value::Number(n) if n.fract() == 0.0 => n,
+        _ => return Err(InterpretError::ValueError(format!("{} index must be an integer.", kind))),
+    };
+    let resolved = if i < 0.0 { i + len as f64 } else { i };
+    if resolved < 0.0 || resolved >= len as f64 {
+        let msg = format!("{} index {} out of range for length {}.", kind, i, len);
+        return Err(InterpretError::ValueError(msg));
+    }
+    Ok(resolved as usize)
+}
+
+// Strings are indexed by character rather than by byte. A range slices out
+// the characters it covers, and has to run forwards within the string
+fn string_index(s: &str, range: Option<&RangeObject>, index: Value) -> Result<String, InterpretError> {
+    let len = s.chars().count();
+    match range {
+        Some(range) => {
+            if range.start < 0 || range.end < range.start || range.end as usize > len {
+                let msg = format!("String slice {}..{} out of range for length {}.", range.start, range.end, len);
+                return Err(InterpretError::ValueError(msg));
+            }
+            let start = range.start as usize;
+            Ok(s.chars().skip(start).take(range.end as usize - start).collect())
+        },
+        None => {
+            let i = sequence_index("String", len, index)?;
+            Ok(s.chars().nth(i).map(String::from).unwrap_or_default())
+        },
+    }
+}
+
+fn range_bound(value: Value) -> Result<i64, InterpretError> {
+    match value {
+        Value::Int(i) => Ok(i),
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Ok(n as i64),
+        _ => Err(InterpretError::ValueError("Range bounds must be integers.".to_string())),
+    }
+}
+
+fn no_chunk() -> InterpretError {
+    InterpretError::ValueError("No chunk loaded.".to_string())
+}
+
+fn bad_frame() -> InterpretError {
+    InterpretError::ValueError("Bad call frame.".to_string())
+}
+
+fn bad_name() -> InterpretError {
+    InterpretError::ValueError("Bad bytecode (name is not a string).".to_string())
+}
+
+fn bad_slot() -> InterpretError {
+    InterpretError::ValueError("Bad bytecode (local slot out of range).".to_string())
+}
+
+fn undefined_variable(name: &str) -> InterpretError {
+    InterpretError::ValueError(format!("Undefined variable '{}'.", name))
+}
+
+// The files a source imports with a literal path, resolved as VM::import would
+// from the importing file at `path`
+fn import_paths(source: &str, path: &Path) -> Vec<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut scanner = Scanner::new(source);
+    let mut paths = Vec::new();
+    let mut after_import = false;
+    loop {
+        let token = match scanner.scan_token() {
+            Ok(token) => token,
+            Err(_) => continue,
+        };
+        match token.token_type {
+            TokenType::EOF => return paths,
+            TokenType::String if after_import => {
+                let literal = &token.literal[1..token.literal.len() - 1];
+                paths.extend(fs::canonicalize(dir.join(literal)).ok());
+            },
+            _ => {},
+        }
+        after_import = token.token_type == TokenType::Import;
+    }
+}
+
+// Run on a worker thread: the file's compiled module, if it compiled, and the
+// files it imports in turn
+fn prefetch(path: &Path, two_phase: bool) -> Option<(Option<PrefetchedModule>, Vec<PathBuf>)> {
+    let source = fs::read_to_string(path).ok()?;
+    let imports = import_paths(&source, path);
+
+    let mut heap = ObjHeap::default();
+    let mut chunk = Chunk::default();
+    let mut diagnostics = Vec::new();
+    let compiled = if two_phase {
+        lower::compile_with_sink(&source, &mut chunk, &mut heap, &mut diagnostics)
+    } else {
+        compiler::compile_with_sink(&source, &mut chunk, &mut heap, &mut diagnostics)
+    };
+    let module = compiled.ok().and_then(|_| chunk.serialize(&heap).ok()).map(|bytes| PrefetchedModule { bytes, diagnostics });
+    Some((module, imports))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::ChunkBuilder;
+    use crate::token::Span;
+    use crate::error::{Diagnostic, Severity};
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::default();
+        vm.load("var x = 1;\n-x + 2;").unwrap();
+
+        let script = Value::Object(vm.frames[0].function);
+        let ops: Vec<StepResult> = (0..8).map(|_| vm.step().unwrap()).collect();
+        assert_eq!(ops, vec![
+            StepResult { op: OpCode::Constant, halted: false, stack: vec![script, Value::Int(1)] },
+            StepResult { op: OpCode::DefineGlobal, halted: false, stack: vec![script] },
+            StepResult { op: OpCode::GetGlobal, halted: false, stack: vec![script, Value::Int(1)] },
+            StepResult { op: OpCode::Negate, halted: false, stack: vec![script, Value::Int(-1)] },
+            StepResult { op: OpCode::AddConstant, halted: false, stack: vec![script, Value::Int(1)] },
+            StepResult { op: OpCode::Pop, halted: false, stack: vec![script] },
+            StepResult { op: OpCode::Nil, halted: false, stack: vec![script, Value::Nil] },
+            StepResult { op: OpCode::Return, halted: true, stack: vec![] },
+        ]);
+    }
+
+    #[test]
+    fn test_trace_execution() {
+        assert!(!VMOptions::default().trace_execution);
+
+        let mut vm = VM::with_options(VMOptions { trace_execution: true, ..Default::default() });
+        assert_eq!(evaluate(&mut vm, "1 + 2"), Value::Int(3));
+    }
+
+    #[test]
+    fn test_interpret_result() {
+        let mut vm = VM::default();
+        // A variable in the chain keeps the strings from being joined at compile time
+        vm.interpret("var a = \"a\";").unwrap();
+        let result = vm.interpret("a + \"b\" + (a + \"d\");").unwrap();
+        assert_eq!(result.instructions, 9);
+        // The script's own slot, plus the four strings
+        assert_eq!(result.peak_stack, 5);
+        assert_eq!(result.allocations, 2);
+        assert_eq!(result.op_count(OpCode::Constant), 2);
+        assert_eq!(result.op_count(OpCode::GetGlobal), 2);
+        assert_eq!(result.op_count(OpCode::Return), 1);
+        assert_eq!(result.op_count(OpCode::Negate), 0);
+
+        let result = vm.interpret("a + \"b\" + \"c\" + \"d\";").unwrap();
+        assert_eq!(result.allocations, 1);
+    }
+
+    #[test]
+    fn test_metrics_json() {
+        let mut vm = VM::default();
+        let result = vm.interpret("
+            fun count(n) { if (n == 0) return 0; return count(n - 1); }
+            class P { init() {} get() { return 1; } }
+            count(3);
+            P().get();
+        ").unwrap();
+
+        // Tail calls are counted like any other
+        assert_eq!(result.calls.get("count"), Some(&4));
+        assert_eq!(result.calls.get("init"), Some(&1));
+        assert_eq!(result.calls.get("get"), Some(&1));
+        assert_eq!(result.live_objects, vm.heap().len());
+
+        let json = result.to_json();
+        assert!(json.contains("\"calls\": {\"count\": 4, \"get\": 1, \"init\": 1}"), "{}", json);
+        assert!(json.contains(&format!("\"heap\": {{\"live\": {}, \"collected\": 0}}", vm.heap().len())), "{}", json);
+
+        // Counts start over with each run
+        let result = vm.interpret("count(0);").unwrap();
+        assert_eq!(result.calls.get("count"), Some(&1));
+        assert_eq!(result.calls.len(), 1);
+
+        // A failed run is measured up to the error
+        assert!(vm.interpret("count(2); nil();").is_err());
+        assert_eq!(vm.metrics().calls.get("count"), Some(&3));
+        assert_eq!(vm.metrics().live_objects, vm.heap().len());
+        assert!(vm.metrics().instructions > 0);
+    }
+
+    #[test]
+    fn test_stack_ops() {
+        let mut b = ChunkBuilder::default();
+        b.constant(Value::Int(1))
+         .constant(Value::Int(2))
+         .op(OpCode::Swap)
+         .op(OpCode::Subtract)
+         .op(OpCode::Dup)
+         .op(OpCode::Add)
+         .op(OpCode::Return);
+
+        let mut vm = VM::default();
+        vm.load_chunk(b.build().unwrap()).unwrap();
+        let stacks: Vec<Vec<Value>> = (0..6).map(|_| vm.step().unwrap().stack[1..].to_vec()).collect();
+        assert_eq!(stacks[2], vec![Value::Int(2), Value::Int(1)]);
+        assert_eq!(stacks[5], vec![Value::Int(2)]);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+        let mut b = ChunkBuilder::default();
+        let top = b.label();
+        b.bind(top).jump(top);
+
+        let mut vm = VM::default();
+        vm.set_interrupt_flag(&INTERRUPT);
+        vm.load_chunk(b.build().unwrap()).unwrap();
+        for _ in 0..100 {
+            assert!(vm.step().is_ok());
+        }
+
+        INTERRUPT.store(true, Ordering::SeqCst);
+        match vm.step() {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Interrupted."),
+            _ => panic!("Expected runtime error"),
+        }
+        assert!(!INTERRUPT.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats() {
+        let mut vm = VM::default();
+        vm.interpret("var a = 0;\nwhile (a < 10)\n  a = a + 1;").unwrap();
+        assert_eq!(vm.stats().op_count(OpCode::SetGlobal), 10);
+        // Each pass through the body runs GetGlobal, AddConstant, SetGlobal, Pop and Loop
+        assert!(vm.stats().line_count(3) >= 50);
+        assert_eq!(vm.stats().hottest_lines()[0].0, 3);
+
+        // Counts add up across runs until they're reset
+        vm.interpret("a = 1;").unwrap();
+        assert_eq!(vm.stats().op_count(OpCode::SetGlobal), 11);
+        vm.reset_stats();
+        assert_eq!(vm.stats().op_count(OpCode::SetGlobal), 0);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut vm = VM::default();
+        let handle = vm.cancellation_handle();
+        assert!(!handle.is_cancelled());
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            handle.cancel();
+        });
+        match vm.interpret("while (true) {}") {
+            Err(InterpretError::Cancelled) => {},
+            _ => panic!("Expected cancellation"),
+        }
+        canceller.join().unwrap();
+
+        // The cancellation was used up by the run it stopped
+        assert!(!vm.cancellation_handle().is_cancelled());
+        assert_eq!(evaluate(&mut vm, "1 + 2"), Value::Int(3));
+    }
+
+    #[test]
+    fn test_fuel() {
+        let mut vm = VM::with_fuel(1000);
+        match vm.interpret("while (true) {}") {
+            Err(InterpretError::FuelExhausted) => {},
+            _ => panic!("Expected fuel to run out"),
+        }
+        assert_eq!(vm.fuel(), Some(0));
+
+        let mut b = ChunkBuilder::default();
+        b.constant(Value::Int(1))
+         .constant(Value::Int(2))
+         .op(OpCode::Add)
+         .op(OpCode::Return);
+
+        let mut vm = VM::with_fuel(2);
+        vm.load_chunk(b.build().unwrap()).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert!(matches!(vm.step(), Err(InterpretError::FuelExhausted)));
+
+        vm.set_fuel(None);
+        assert_eq!(vm.step().unwrap().stack[1..], [Value::Int(3)]);
+
+        let mut vm = VM::with_fuel(100);
+        vm.interpret("var a = 1 + 2;").unwrap();
+        assert!(vm.fuel().unwrap() < 100);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut vm = VM::default();
+        match vm.interpret("1 / (2 - 2);") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Division by zero."),
+            _ => panic!("Expected runtime error"),
+        }
+
+        match vm.interpret("5 % 0;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Division by zero."),
+            _ => panic!("Expected runtime error"),
+        }
+        assert_eq!(evaluate(&mut vm, "-7 % 3"), Value::Number(-1.0));
+
+        let mut vm = VM::with_options(VMOptions { strict_division: false, ..Default::default() });
+        assert!(vm.interpret("1 / 0;").is_ok());
+    }
+
+    #[test]
+    fn test_exponentiation() {
+        let mut vm = VM::default();
+        assert_eq!(evaluate(&mut vm, "2 ** 3 ** 2"), Value::Number(512.0));
+        assert_eq!(evaluate(&mut vm, "-2 ^ 2"), Value::Number(-4.0));
+        assert_eq!(evaluate(&mut vm, "4 ** -0.5"), Value::Number(0.5));
+
+        match vm.interpret("2 ** nil;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "cannot exponentiate Int(2) and Nil"),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut vm = VM::default();
+        vm.interpret("var n = 0; for (var i = 0; i < 1000; i = i + 1) n = n + 3;").unwrap();
+        assert!(matches!(evaluate(&mut vm, "n"), Value::Int(3000)));
+        assert!(matches!(evaluate(&mut vm, "n / 3"), Value::Number(_)));
+        assert!(matches!(evaluate(&mut vm, "2 ** 62"), Value::Int(4611686018427387904)));
+        assert!(matches!(evaluate(&mut vm, "2 ** 64"), Value::Number(_)));
+        assert_eq!(evaluate(&mut vm, "1 == 1.0"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "2 < 2.5"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "[1, 2][1.0]"), Value::Int(2));
+    }
+
+    #[test]
+    fn test_string_coercion() {
+        let mut vm = VM::default();
+        assert!(vm.interpret("\"count: \" + 3;").is_err());
+
+        vm.options_mut().coerce_strings = true;
+        assert_eq!(evaluate(&mut vm, "\"count: \" + 3 == \"count: 3\""), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "1 + 2 + \"a\" + 0.5 == \"3a0.5\""), Value::Bool(true));
+        assert!(vm.interpret("nil + \"a\";").is_err());
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut vm = VM::default();
+        let source = (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+        assert_eq!(evaluate(&mut vm, &source), Value::Int(44850));
+        assert!(vm.chunk().unwrap().verify_with_depth(1).is_ok());
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let mut vm = VM::default();
+        vm.interpret("var total = 0;
+                      for (var i = 0; i < 5; i = i + 1) if (i == 2 or i < 1) total = total + 10;").unwrap();
+        assert_eq!(evaluate(&mut vm, "total"), Value::Int(20));
+
+        vm.interpret("class A { init(n) { this.n = n; } next() { return this.n + 1; } }").unwrap();
+        assert_eq!(evaluate(&mut vm, "A(4).next()"), Value::Int(5));
+        assert_eq!(evaluate(&mut vm, "match 3 { 2 => \"two\", 3 => \"three\" } + \"!\" == \"three!\""), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_inline_caches() {
+        let mut vm = VM::default();
+        vm.interpret("var a = 1; var b = 2; fun get() { return b; }
+                      class P {} fun y(o) { return o.y; }
+                      var p = P(); p.x = 1; p.y = 2;
+                      var q = P(); q.y = 3;").unwrap();
+        assert_eq!(evaluate(&mut vm, "get() + get()"), Value::Int(4));
+
+        let get = vm.globals.get("get").copied().unwrap();
+        assert_eq!(vm.heap.as_function(&get).unwrap().chunk.cached(0), Some(1));
+
+        // A cached index still reads the current value
+        vm.interpret("b = 5;").unwrap();
+        assert_eq!(evaluate(&mut vm, "get()"), Value::Int(5));
+
+        // The instances keep y at different indices, so each misses the other's cache
+        assert_eq!(evaluate(&mut vm, "y(p) + y(q) + y(p)"), Value::Int(7));
+
+        // Globals the script defined first are looked up by index before it ever runs
+        let mut vm = VM::default();
+        let chunk = vm.compile("var a = 1; var b = 2; fun get() { return b; } print get();").unwrap();
+        let get = chunk.constants().iter().find(|c| vm.heap.as_function(c).is_some()).copied().unwrap();
+        assert_eq!(vm.heap.as_function(&get).unwrap().chunk.cached(0), Some(1));
+        vm.instruct(chunk).unwrap();
+
+        // Which a table that isn't fresh gets wrong, and the lookup falls back to the name
+        vm.interpret("var c = 3; var d = 4; fun sum() { return c + d; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "sum()"), Value::Int(7));
+    }
+
+    #[test]
+    fn test_interned_strings() {
+        let mut vm = VM::default();
+        vm.interpret("var a = \"ab\"; var b = \"a\"; var c = b + \"b\";").unwrap();
+
+        // Built at runtime or written as a literal, equal strings are one object
+        assert_eq!(evaluate(&mut vm, "a"), evaluate(&mut vm, "c"));
+        assert_eq!(evaluate(&mut vm, "\"ab\""), evaluate(&mut vm, "a"));
+    }
+
+    // Runs a single expression statement up to its OP_POP, leaving the result on the stack
+    fn evaluate(vm: &mut VM, source: &str) -> Value {
+        vm.load(&format!("{};", source)).unwrap();
+        // The statement's OP_POP sits just before the closing OP_NIL, OP_RETURN,
+        // and only counts once any calls have returned to the script
+        let end = vm.chunk().unwrap().code.len() - 3;
+        while vm.frames.len() > 1 || vm.ip() != end {
+            vm.step().unwrap();
+        }
+        vm.peek(0).unwrap()
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut vm = VM::default();
+        vm.interpret("var a = 1; var b; a = a + 2;").unwrap();
+        assert_eq!(evaluate(&mut vm, "a"), Value::Number(3.0));
+        assert_eq!(evaluate(&mut vm, "b"), Value::Nil);
+
+        // Redefining a global just replaces it
+        vm.interpret("var a = \"x\";").unwrap();
+        assert_eq!(evaluate(&mut vm, "a == \"x\""), Value::Bool(true));
+
+        match vm.interpret("c;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Undefined variable 'c'."),
+            _ => panic!("Expected runtime error"),
+        }
+
+        match vm.interpret("c = 1;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Undefined variable 'c'."),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let mut vm = VM::default();
+        vm.interpret("var total = 0; for (var i = 0; i < 5; i = i + 1) total = total + i;").unwrap();
+        assert_eq!(evaluate(&mut vm, "total"), Value::Number(10.0));
+
+        vm.interpret("var n = 0; while (n < 3) { var step = 1; n = n + step; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "n"), Value::Number(3.0));
+
+        vm.interpret("var r; if (n > 5) r = \"big\"; else r = \"small\";").unwrap();
+        assert_eq!(evaluate(&mut vm, "r == \"small\""), Value::Bool(true));
+
+        // The dropped branch's literal isn't negated in place of x
+        vm.interpret("var x = 5; if (false) { 2; } var y = -x;").unwrap();
+        assert_eq!(evaluate(&mut vm, "y"), Value::Int(-5));
+
+        vm.interpret("var s = 0; { var a = 1; { var a = 2; s = s + a; } s = s + a; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "s"), Value::Number(3.0));
+
+        // The right operand is never evaluated when the left decides the result
+        assert_eq!(evaluate(&mut vm, "false and undefined"), Value::Bool(false));
+        assert_eq!(evaluate(&mut vm, "(\"yes\" or undefined) == \"yes\""), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "nil or 2"), Value::Number(2.0));
+        assert_eq!(evaluate(&mut vm, "1 and 2"), Value::Number(2.0));
+        assert_eq!(evaluate(&mut vm, "nil ?? 2"), Value::Number(2.0));
+        assert_eq!(evaluate(&mut vm, "false ?? undefined"), Value::Bool(false));
+        assert_eq!(evaluate(&mut vm, "nil ?? nil ?? 3"), Value::Number(3.0));
+
+        // The loop variable is scoped to the loop
+        vm.interpret("var i = \"outer\"; for (var i = 0; i < 2; i = i + 1) {}").unwrap();
+        assert_eq!(evaluate(&mut vm, "i == \"outer\""), Value::Bool(true));
+
+        // continue still runs the increment, and unwinds the body's locals
+        vm.interpret("var big = 0; for (var i = 0; i < 6; i = i + 1) { var small = i < 3; if (small) continue; big = big + i; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "big"), Value::Number(12.0));
+
+        vm.interpret("var m = 0; var skipped = 0; while (m < 4) { m = m + 1; { var x = m; if (x == 2) { skipped = x; continue; } } }").unwrap();
+        assert_eq!(evaluate(&mut vm, "m + skipped"), Value::Number(6.0));
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut vm = VM::default();
+        vm.interpret("fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } var f = fib(10);").unwrap();
+        assert_eq!(evaluate(&mut vm, "f"), Value::Number(55.0));
+
+        vm.interpret("fun add(a, b) { var sum = a + b; return sum; } fun none() {}").unwrap();
+        assert_eq!(evaluate(&mut vm, "add(1, 2) * 2"), Value::Number(6.0));
+        assert_eq!(evaluate(&mut vm, "none()"), Value::Nil);
+
+        vm.interpret("fun twice(f, x) { return f(f(x)); } var sq = twice(fun (n) { return n * n; }, 3);").unwrap();
+        assert_eq!(evaluate(&mut vm, "sq"), Value::Number(81.0));
+        assert_eq!(evaluate(&mut vm, "(fun () { return 1; })()"), Value::Number(1.0));
+
+        vm.interpret("fun count(first, ...rest) { return first + rest[-1]; } fun all(...xs) { return xs; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "count(1, 2, 3)"), Value::Number(4.0));
+        assert_eq!(evaluate(&mut vm, "count(1, 2)"), Value::Number(3.0));
+        let none = evaluate(&mut vm, "all()");
+        assert_eq!(vm.heap().display(&none).to_string(), "[]");
+        let three = evaluate(&mut vm, "all(1, nil, \"x\")");
+        assert_eq!(vm.heap().display(&three).to_string(), "[1, nil, \"x\"]");
+
+        let message = |vm: &mut VM, source| match vm.interpret(source) {
+            Err(InterpretError::RuntimeError(e)) => (e.message, e.trace.iter().map(|f| f.to_string()).collect::<Vec<_>>()),
+            _ => panic!("Expected runtime error"),
+        };
+
+        assert_eq!(message(&mut vm, "add(1);").0, "Expected 2 arguments but got 1.");
+        assert_eq!(message(&mut vm, "count();").0, "Expected at least 1 arguments but got 0.");
+        assert_eq!(message(&mut vm, "\"add\"();").0, "Can only call functions and classes.");
+        assert_eq!(
+            message(&mut vm, "fun g() {\n return -nil;\n}\ng();"),
+            ("cannot negate Nil".to_string(), vec!["[line 2] in g()".to_string(), "[line 4] in script".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut vm = VM::default();
+        vm.interpret("fun count(n, acc) { if (n == 0) return acc; return count(n - 1, acc + 1); }").unwrap();
+        assert_eq!(evaluate(&mut vm, "count(10000, 0)"), Value::Int(10000));
+        assert_eq!(evaluate(&mut vm, "count(3, 0) + 1"), Value::Int(4));
+
+        // Classes and bound methods are tail called like functions
+        vm.interpret("class P { init(x) { this.x = x; } get() { return this.x; } }").unwrap();
+        vm.interpret("fun make(x) { var unused = 1; return P(x); }").unwrap();
+        assert_eq!(evaluate(&mut vm, "make(5).x"), Value::Int(5));
+        vm.interpret("fun bound(p) { var m = p.get; return m(); }").unwrap();
+        assert_eq!(evaluate(&mut vm, "bound(P(6))"), Value::Int(6));
+
+        // A failed tail call is reported from the caller
+        vm.interpret("fun bad() {\n  return nil();\n}").unwrap();
+        match vm.interpret("bad();") {
+            Err(InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "Can only call functions and classes.");
+                assert_eq!(e.trace[0], TraceFrame { function: Some("bad".to_string()), line: 2, source: None });
+            },
+            _ => panic!("Expected runtime error"),
+        }
+
+        // The ast front end emits them too
+        for two_phase in [false, true] {
+            let mut vm = VM::with_options(VMOptions { two_phase, ..Default::default() });
+            vm.interpret("fun r(n) { if (n == 0) return 0; return r(n - 1); }").unwrap();
+            assert_eq!(evaluate(&mut vm, "r(1000)"), Value::Int(0));
+        }
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut vm = VM::default();
+        match vm.interpret("fun f() {\n f();\n}\nf();") {
+            Err(InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "Stack overflow.");
+                // The script plus 63 calls to f, the last of which failed to call again
+                assert_eq!(e.trace.len(), 64);
+                assert_eq!(e.trace[0], TraceFrame { function: Some("f".to_string()), line: 2, source: None });
+                assert_eq!(e.trace[63], TraceFrame { function: None, line: 4, source: None });
+                assert_eq!(e.to_string(), [
+                    "Stack overflow.",
+                    "[line 2] in f()",
+                    "[line 2] in f()",
+                    "[line 2] in f()",
+                    "[previous frame repeated 60 more times]",
+                    "[line 4] in script",
+                ].join("\n"));
+            },
+            _ => panic!("Expected runtime error"),
+        }
+
+        // The limit is configurable, and counts the script's own frame
+        vm.options_mut().max_frames = 4;
+        vm.interpret("fun depth(n) { if (n == 0) return 0; return depth(n - 1) + 1; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "depth(2)"), Value::Int(2));
+        assert!(vm.interpret("depth(3);").is_err());
+
+        vm.options_mut().max_frames = 1000;
+        assert_eq!(evaluate(&mut vm, "depth(500)"), Value::Int(500));
+
+        // The value stack has a fixed size of its own, which the script's slot counts toward
+        vm.options_mut().stack_size = 8;
+        assert!(vm.interpret("var l = [1, 2, 3, 4, 5, 6, 7];").is_ok());
+        match vm.interpret("var l = [1, 2, 3, 4, 5, 6, 7, 8];") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Stack overflow."),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut vm = VM::default();
+        vm.interpret("class Point {} var p = Point(); p.x = 1; p.y = p.x + 1;").unwrap();
+        assert_eq!(evaluate(&mut vm, "p.x + p.y"), Value::Number(3.0));
+        assert_eq!(evaluate(&mut vm, "p.x = 5"), Value::Number(5.0));
+
+        let point = evaluate(&mut vm, "Point");
+        assert_eq!(vm.heap().display(&point).to_string(), "Point");
+        let p = evaluate(&mut vm, "p");
+        assert_eq!(vm.heap().display(&p).to_string(), "Point instance");
+
+        let message = |vm: &mut VM, source| match vm.interpret(source) {
+            Err(InterpretError::RuntimeError(e)) => e.message,
+            _ => panic!("Expected runtime error"),
+        };
+
+        assert_eq!(message(&mut vm, "p.z;"), "Undefined property 'z'.");
+        assert_eq!(message(&mut vm, "1.x;"), "Only instances have properties.");
+        assert_eq!(message(&mut vm, "Point.x = 1;"), "Only instances have fields.");
+        assert_eq!(message(&mut vm, "Point(1);"), "Expected 0 arguments but got 1.");
+    }
+
+    #[test]
+    fn test_instance_equality() {
+        let program = "
+            class Plain {}
+            class Point {
+                init(x) { this.x = x; }
+                __eq__(other) { return this.x == other.x; }
+            }
+            class Always {
+                __eq__(other) { return same(this, other); }
+            }
+            fun same(a, b) { return true; }
+            var p = Plain();
+            var branch;
+            if (Point(1) == Point(1)) branch = \"equal\"; else branch = \"different\";
+            var missed;
+            if (Point(1) == Point(2)) missed = true; else missed = false;
+            var always = false;
+            if (Always() == p) always = true;
+        ";
+        for two_phase in [false, true] {
+            let mut vm = VM::with_options(VMOptions { two_phase, ..Default::default() });
+            vm.interpret(program).unwrap();
+
+            // Without __eq__ instances are only equal to themselves
+            assert_eq!(evaluate(&mut vm, "p == p"), Value::Bool(true));
+            assert_eq!(evaluate(&mut vm, "p == Plain()"), Value::Bool(false));
+
+            assert_eq!(evaluate(&mut vm, "Point(1) == Point(1)"), Value::Bool(true));
+            assert_eq!(evaluate(&mut vm, "Point(1) != Point(2)"), Value::Bool(true));
+            assert_eq!(evaluate(&mut vm, "Point(1) == 1"), Value::Bool(false));
+            assert_eq!(evaluate(&mut vm, "Always() == p"), Value::Bool(true));
+            assert_eq!(evaluate(&mut vm, "missed"), Value::Bool(false));
+            assert_eq!(evaluate(&mut vm, "always"), Value::Bool(true));
+            let branch = evaluate(&mut vm, "branch");
+            assert_eq!(vm.heap().as_str(&branch), Some("equal"));
+        }
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut vm = VM::default();
+        vm.interpret("var l = [1, 2, 3]; l[0] = l[-1] + l[1]; var c = 0; l[2] = c = 7;").unwrap();
+        assert_eq!(evaluate(&mut vm, "l[0]"), Value::Number(5.0));
+        assert_eq!(evaluate(&mut vm, "l[-3]"), Value::Number(5.0));
+        assert_eq!(evaluate(&mut vm, "l[2] + c"), Value::Number(14.0));
+
+        // Lists are shared, not copied
+        vm.interpret("var m = [l, []]; m[0][1] = 9;").unwrap();
+        assert_eq!(evaluate(&mut vm, "l[1]"), Value::Number(9.0));
+        let m = evaluate(&mut vm, "m");
+        assert_eq!(vm.heap().display(&m).to_string(), "[[5, 9, 7], []]");
+
+        for (source, message) in [
+            ("l[3];", "List index 3 out of range for length 3."),
+            ("l[-4] = 0;", "List index -4 out of range for length 3."),
+            ("l[0.5];", "List index must be an integer."),
+            ("l[nil];", "List index must be an integer."),
+            ("var n = 1; n[0];", "Only lists, strings and instances can be indexed."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dynamic_properties() {
+        let mut vm = VM::default();
+        vm.interpret("
+            class P { init() { this.x1 = 1; } sum() { return this.x1 + this.x2; } twice { return this.x1 * 2; } }
+            var p = P();
+            var n = \"2\";
+            p[\"x\" + n] = 10;
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "p.x2"), Value::Int(10));
+        assert_eq!(evaluate(&mut vm, "p[\"x\" + \"1\"]"), Value::Int(1));
+        assert_eq!(evaluate(&mut vm, "p[\"sum\"]()"), Value::Int(11));
+        assert_eq!(evaluate(&mut vm, "p[\"twice\"]"), Value::Int(2));
+        assert_eq!(evaluate(&mut vm, "p[\"x1\"] = 5"), Value::Int(5));
+
+        for (source, message) in [
+            ("p[1];", "Property name must be a string."),
+            ("p[nil] = 1;", "Property name must be a string."),
+            ("p[\"y\"];", "Undefined property 'y'."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reflection() {
+        let mut vm = VM::default();
+        vm.interpret("
+            class P { init() { this.b = 1; this.a = 2; } sum() { return this.a + this.b; } size { return 2; } }
+            var p = P();
+            setField(p, \"c\", 3);
+            var copy = P();
+            for (name in fields(p)) setField(copy, name, getField(p, name) * 10);
+        ").unwrap();
+
+        let show = |vm: &mut VM, source: &str| {
+            let value = evaluate(vm, source);
+            vm.heap().display(&value).to_string()
+        };
+        assert_eq!(show(&mut vm, "fields(p)"), "[\"b\", \"a\", \"c\"]");
+        assert_eq!(show(&mut vm, "methods(P)"), "[\"init\", \"size\", \"sum\"]");
+        assert_eq!(evaluate(&mut vm, "copy.c"), Value::Int(30));
+        assert_eq!(evaluate(&mut vm, "hasField(p, \"a\")"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "hasField(p, \"sum\")"), Value::Bool(false));
+        assert_eq!(show(&mut vm, "getField"), "<native fn getField>");
+
+        // A script's own global hides a native of the same name
+        vm.interpret("fun fields(x) { return x; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "fields(1)"), Value::Int(1));
+
+        for (source, message) in [
+            ("getField(p, \"z\");", "Undefined field 'z'."),
+            ("getField(1, \"a\");", "getField() expects an instance, not Int(1)."),
+            ("setField(p, 1, 2);", "setField() expects a field name, not Int(1)."),
+            ("methods(p);", "methods() expects a class, not Instance(P instance)."),
+            ("hasField(p);", "Expected 2 arguments but got 1."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut vm = VM::default();
+        vm.interpret("
+            class Config { init() { this.port = 80; } }
+            var config = freeze(Config());
+            var list = [1, 2];
+            var same = freeze(list);
+            var open = Config();
+            open.port = 81;
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "config.port + list[1]"), Value::Int(82));
+        assert_eq!(evaluate(&mut vm, "same == list"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "open.port"), Value::Int(81));
+
+        for (source, message) in [
+            ("config.port = 1;", "Can't modify frozen Instance(Config instance)."),
+            ("config[\"host\"] = 1;", "Can't modify frozen Instance(Config instance)."),
+            ("setField(config, \"port\", 1);", "Can't modify frozen Instance(Config instance)."),
+            ("list[0] = 3;", "Can't modify frozen List([1, 2])."),
+            ("freeze(1);", "freeze() expects an instance or a list, not Int(1)."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_for_in() {
+        let mut vm = VM::default();
+        vm.interpret("
+            var total = 0;
+            for (x in [1, 2, 3, 4]) { if (x == 2) continue; total = total + x; }
+            var chars = [];
+            var n = 0;
+            for (c in \"hello\") { if (n == 1) chars = [c]; n = n + 1; }
+            fun first(xs) { for (x in xs) return x; return nil; }
+            var f = first([7, 8]);
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "total"), Value::Int(8));
+        assert_eq!(evaluate(&mut vm, "n"), Value::Int(5));
+        assert_eq!(evaluate(&mut vm, "chars[0] == \"e\""), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "f"), Value::Int(7));
+
+        vm.interpret("for (x in []) print x; for (x in \"\") print x;").unwrap();
+
+        match vm.interpret("for (x in 3) print x;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Can only iterate over lists, strings and ranges, not Int(3)."),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut vm = VM::default();
+        vm.interpret("
+            var up = [];
+            for (i in 0..4) up = [up, i];
+            var sum = 0;
+            for (i in 3..=1) sum = sum * 10 + i;
+            var n = 2;
+            var r = 1..n + 1;
+        ").unwrap();
+        let up = evaluate(&mut vm, "up");
+        assert_eq!(vm.heap().display(&up).to_string(), "[[[[[], 0], 1], 2], 3]");
+        assert_eq!(evaluate(&mut vm, "sum"), Value::Int(321));
+
+        // Ranges are values: inclusive ones are kept as the equivalent exclusive range
+        let r = evaluate(&mut vm, "r");
+        assert_eq!(vm.heap().describe(&r), "Range(1..3)");
+        assert_eq!(evaluate(&mut vm, "r == 1..=2"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "r == 1..2"), Value::Bool(false));
+
+        for (source, message) in [
+            ("1..nil;", "Range bounds must be integers."),
+            ("0.5..2;", "Range bounds must be integers."),
+            ("0..=9223372036854775807;", "Range end is out of bounds."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_string_indexing() {
+        let mut vm = VM::default();
+        let s = vm.heap_mut().alloc_str("héllo".to_string());
+        vm.globals.insert("s".to_string(), s);
+
+        for (source, expected) in [
+            ("s[1]", "é"),
+            ("s[-1]", "o"),
+            ("s[1..3]", "él"),
+            ("s[1..=3]", "éll"),
+            ("s[2..2]", ""),
+            ("s[0..5]", "héllo"),
+            ("\"abc\"[1.0]", "b"),
+            ("\"héllo\"[1]", "é"),
+            ("\"héllo\"[2..5]", "llo"),
+            ("\"日本\" + \"語\"", "日本語"),
+        ] {
+            let value = evaluate(&mut vm, source);
+            assert_eq!(vm.heap().as_str(&value), Some(expected), "{}", source);
+        }
+
+        for (source, message) in [
+            ("s[5];", "String index 5 out of range for length 5."),
+            ("s[-6];", "String index -6 out of range for length 5."),
+            ("s[nil];", "String index must be an integer."),
+            ("s[3..6];", "String slice 3..6 out of range for length 5."),
+            ("s[3..1];", "String slice 3..1 out of range for length 5."),
+            ("s[0] = \"j\";", "Strings can't be modified."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_match() {
+        let mut vm = VM::default();
+        vm.interpret("
+            fun describe(v) {
+                return match v {
+                    1 => \"one\",
+                    -1 => \"minus one\",
+                    \"x\" => \"ex\",
+                    nil => \"nothing\",
+                    _ => \"other\",
+                };
+            }
+            var partial = match 3 { 1 => 1 };
+        ").unwrap();
+        for (source, expected) in [
+            ("describe(1)", "one"),
+            ("describe(1.0)", "one"),
+            ("describe(-1)", "minus one"),
+            ("describe(\"x\")", "ex"),
+            ("describe(nil)", "nothing"),
+            ("describe([])", "other"),
+        ] {
+            let value = evaluate(&mut vm, source);
+            assert_eq!(vm.heap().as_str(&value), Some(expected), "{}", source);
+        }
+        assert_eq!(evaluate(&mut vm, "partial"), Value::Nil);
+        assert_eq!(evaluate(&mut vm, "match 2 { 2 => 3 } + 1"), Value::Int(4));
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let mut vm = VM::default();
+        vm.interpret("
+            var (a, b, c) = [1, 2, 3];
+            fun swap(pair) { var (x, y) = pair; return [y, x]; }
+            var (d, e) = swap([a, b]);
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "a + b * 10 + c * 100"), Value::Int(321));
+        assert_eq!(evaluate(&mut vm, "d * 10 + e"), Value::Int(21));
+
+        for (source, message) in [
+            ("var (f, g) = [1];", "Expected 2 values to unpack but got 1."),
+            ("var (f, g) = [1, 2, 3];", "Expected 2 values to unpack but got 3."),
+            ("var (f) = \"ab\";", "Can only unpack lists, not Str(\"ab\")."),
+        ] {
+            match vm.interpret(source) {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, message),
+                _ => panic!("Expected runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut vm = VM::default();
+        vm.interpret("var caught; try { var a = 1; throw \"boom\"; } catch (e) { caught = e; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "caught == \"boom\""), Value::Bool(true));
+
+        // Thrown values unwind through call frames, and runtime errors are caught
+        // with their message
+        vm.interpret("
+            fun fail(n) { if (n == 0) return -nil; return fail(n - 1); }
+            var result = 0;
+            try { fail(3); } catch (e) { result = e; }
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "result == \"cannot negate Nil\""), Value::Bool(true));
+
+        // A handler only covers its own try block
+        vm.interpret("
+            fun safe() { try { return 1; } catch (e) { return 2; } }
+            var after;
+            try { safe(); throw 3; } catch (e) { after = e; }
+            var n = 0;
+            while (n < 3) { n = n + 1; try { continue; } catch (e) {} }
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "after"), Value::Number(3.0));
+
+        // The nearest handler wins, and a rethrow reaches the next one out
+        vm.interpret("var log = 0; try { try { throw 1; } catch (e) { log = e; throw e + 1; } } catch (e) { log = log * 10 + e; }").unwrap();
+        assert_eq!(evaluate(&mut vm, "log"), Value::Number(12.0));
+
+        match vm.interpret("throw [1];") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Uncaught exception: [1]"),
+            _ => panic!("Expected runtime error"),
+        }
+        match vm.interpret("try { } catch (e) { } -nil;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "cannot negate Nil"),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = std::env::temp_dir().join(format!("roxl-imports-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/counter.lox"), "
+            import \"shared.lox\" as shared;
+            var count = 0;
+            fun bump() { count = count + 1; return count; }
+            class Box { init(v) { this.v = v; } get() { return this.v + shared.offset; } }
+        ").unwrap();
+        fs::write(dir.join("lib/shared.lox"), "var offset = 100; var runs = 0; runs = runs + 1;").unwrap();
+        fs::write(dir.join("broken.lox"), "var = 1;").unwrap();
+
+        let mut vm = VM::default();
+        let mut chunk = vm.compile("
+            var count = 10;
+            import \"lib/counter.lox\" as counter;
+            import \"lib/counter.lox\" as again;
+            counter.bump();
+            again.bump();
+        ").unwrap();
+        chunk.source = Some(dir.join("main.lox").display().to_string());
+        vm.instruct(chunk).unwrap();
+
+        // Module functions use the module's globals, and each file runs once
+        assert_eq!(evaluate(&mut vm, "count"), Value::Number(10.0));
+        assert_eq!(evaluate(&mut vm, "counter.count"), Value::Number(2.0));
+        assert_eq!(evaluate(&mut vm, "counter == again"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "counter.Box(1).get()"), Value::Number(101.0));
+
+        // Without an alias the module's globals are copied in
+        let mut chunk = vm.compile("import \"lib/shared.lox\";").unwrap();
+        chunk.source = Some(dir.join("main.lox").display().to_string());
+        vm.instruct(chunk).unwrap();
+        assert_eq!(evaluate(&mut vm, "offset + runs"), Value::Number(101.0));
+
+        let mut error = |source: &str| {
+            let mut chunk = vm.compile(source).unwrap();
+            chunk.source = Some(dir.join("main.lox").display().to_string());
+            match vm.instruct(chunk) {
+                Err(InterpretError::RuntimeError(e)) => e.message,
+                _ => panic!("Expected runtime error"),
+            }
+        };
+        assert!(error("import \"missing.lox\";").starts_with("Could not import 'missing.lox': "));
+        assert_eq!(error("import \"broken.lox\";"), "Could not compile 'broken.lox': [line 1] Error at '=': Expect variable name.");
+        assert!(error("import \"lib/counter.lox\" as c; c.nothing;").starts_with("Undefined variable 'nothing' in module '"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prefetch_imports() {
+        let dir = std::env::temp_dir().join(format!("roxl-prefetch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/a.lox"), "import \"b.lox\" as b;\nfun twice() { return b.value * 2; }").unwrap();
+        fs::write(dir.join("lib/b.lox"), "var value = 21;\n{ var unused = 1; }").unwrap();
+        fs::write(dir.join("c.lox"), "var c = 3;").unwrap();
+        fs::write(dir.join("broken.lox"), "var = 1;").unwrap();
+
+        for two_phase in [false, true] {
+            let sink = SharedSink::default();
+            let mut vm = VM::with_options(VMOptions { two_phase, ..Default::default() });
+            vm.set_diagnostic_sink(Box::new(sink.clone()));
+
+            let main = dir.join("main.lox");
+            let source = "import \"lib/a.lox\" as a;\nimport \"c.lox\";\nimport \"missing.lox\";";
+            vm.prefetch_imports(&main, source);
+
+            // b.lox is only found once a.lox has been scanned; a missing file is left alone
+            let mut prefetched: Vec<&Path> = vm.prefetched.keys().map(|p| p.strip_prefix(dir.canonicalize().unwrap()).unwrap()).collect();
+            prefetched.sort();
+            assert_eq!(prefetched, vec![Path::new("c.lox"), Path::new("lib/a.lox"), Path::new("lib/b.lox")]);
+            // Nothing is reported until the import runs
+            assert!(sink.0.lock().unwrap().is_empty());
+
+            let mut chunk = vm.compile("import \"lib/a.lox\" as a; import \"c.lox\";").unwrap();
+            chunk.source = Some(main.display().to_string());
+            vm.instruct(chunk).unwrap();
+            assert!(vm.prefetched.is_empty());
+            assert_eq!(evaluate(&mut vm, "a.twice() + c"), Value::Int(45));
+
+            let diagnostics = sink.0.lock().unwrap();
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            assert_eq!(messages, vec!["[line 2] Warning: Unused variable 'unused'."]);
+            drop(diagnostics);
+
+            // A file that doesn't compile is still reported by its import
+            vm.prefetch_imports(&main, "import \"broken.lox\";");
+            assert!(vm.prefetched.is_empty());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_std_test() {
+        let mut vm = VM::default();
+        vm.interpret("
+            import \"std:test\";
+            test(\"passes\", fun () { expect(1 + 1).toEqual(2); expect(nil).toBeFalsey(); });
+            test(\"fails\", fun () { expect(str(12)).toEqual(\"13\"); });
+            test(\"errors\", fun () { nil(); });
+            test(\"throws\", fun () { expect(fun () { throw 1; }).toThrow(); });
+            test(\"doesn't throw\", fun () { expect(fun () {}).toThrow(); });
+            import \"std:test\" as tests;
+        ").unwrap();
+
+        let tests = evaluate(&mut vm, "tests");
+        let module = vm.heap().as_module(&tests).unwrap();
+        assert_eq!(module.path, "std:test");
+        assert_eq!(module.globals.get("passed"), Some(&Value::Int(2)));
+        assert_eq!(module.globals.get("failed"), Some(&Value::Int(3)));
+    }
+
+    #[test]
+    fn test_getters() {
+        let mut vm = VM::default();
+        vm.interpret("
+            class Circle {
+                init(r) { this.r = r; }
+                area { return 3 * this.r * this.r; }
+                diameter { return this.r * 2; }
+            }
+            var c = Circle(2);
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "c.area + c.diameter"), Value::Number(16.0));
+
+        vm.interpret("c.r = 1;").unwrap();
+        assert_eq!(evaluate(&mut vm, "c.area"), Value::Number(3.0));
+
+        match vm.interpret("c.area();") {
+            Err(InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "'area' is a getter, so it's read without parentheses.")
+            },
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut vm = VM::default();
+        vm.interpret("
+            class Counter {
+                init(start) { this.count = start; }
+                bump(by) { this.count = this.count + by; return this; }
+            }
+            var c = Counter(10);
+            c.bump(1).bump(2);
+            var bump = c.bump;
+            bump(3);
+        ").unwrap();
+        assert_eq!(evaluate(&mut vm, "c.count"), Value::Number(16.0));
+        assert_eq!(evaluate(&mut vm, "c.bump == c.bump"), Value::Bool(true));
+
+        // Initializers hand back the instance, even when called directly
+        assert_eq!(evaluate(&mut vm, "c.init(0) == c"), Value::Bool(true));
+        assert_eq!(evaluate(&mut vm, "c.count"), Value::Number(0.0));
+
+        // Method calls go through OP_INVOKE rather than allocating a bound method
+        let bound_methods = |vm: &VM| (0..vm.heap().len())
+            .filter(|&i| matches!(vm.heap().get(ObjHandle(i)), ObjectType::BoundMethod(_)))
+            .count();
+        let before = bound_methods(&vm);
+        let result = vm.interpret("c.bump(1); c.bump(2);").unwrap();
+        assert_eq!(result.op_count(OpCode::Invoke), 2);
+        assert_eq!(bound_methods(&vm), before);
+
+        // Optional chains stop at a nil receiver instead of failing
+        vm.interpret("var none; var maybe = c;").unwrap();
+        assert_eq!(evaluate(&mut vm, "none?.count"), Value::Nil);
+        assert_eq!(evaluate(&mut vm, "none?.bump(1).count"), Value::Nil);
+        assert_eq!(evaluate(&mut vm, "maybe?.bump(1).count"), Value::Number(4.0));
+        match vm.interpret("1?.count;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Only instances have properties."),
+            _ => panic!("Expected runtime error"),
+        }
+
+        // A field of the same name hides the method, even when called
+        vm.interpret("fun one() { return 1; } c.bump = one;").unwrap();
+        assert_eq!(evaluate(&mut vm, "c.bump()"), Value::Number(1.0));
+
+        match vm.interpret("var n = 1; n.bump();") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Only instances have methods."),
+            _ => panic!("Expected runtime error"),
+        }
+
+        match vm.interpret("Counter();") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Expected 1 arguments but got 0."),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<std::sync::Mutex<Vec<Diagnostic>>>);
+
+    impl DiagnosticSink for SharedSink {
+        fn report(&mut self, diagnostic: Diagnostic) {
+            self.0.lock().unwrap().push(diagnostic);
+        }
+    }
+
+    #[test]
+    fn test_diagnostic_sink() {
+        let sink = SharedSink::default();
+        let mut vm = VM::default();
+        vm.set_diagnostic_sink(Box::new(sink.clone()));
+
+        assert!(matches!(vm.interpret("print ;"), Err(InterpretError::CompileError(_))));
+        assert!(vm.interpret("{ var a = 1; }").is_ok());
+
+        let diagnostics = sink.0.lock().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[1].to_string(), "[line 1] Warning: Unused variable 'a'.");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let mut vm = VM::default();
+        match vm.interpret("3 +\n nil;") {
+            Err(InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "cannot add Int(3) and Nil");
+                assert_eq!(e.line, Some(2));
+                assert_eq!(e.span, Some(Span { start: 5, end: 8, line: 2, column: 2 }));
+                assert_eq!(e.op, Some(OpCode::Add));
+                assert_eq!(e.to_string(), "cannot add Int(3) and Nil\n[line 2] in script");
+            },
+            _ => panic!("Expected runtime error"),
+        }
+
+        match vm.interpret("1 + 2 + \"a\";") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "cannot add Int(3) and Str(\"a\")"),
+            _ => panic!("Expected runtime error"),
+        }
+
+        let mut chunk = vm.compile("1 +\n true;").unwrap();
+        chunk.source = Some("main.lox".to_string());
+        match vm.instruct(chunk) {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.trace[0].to_string(), "[main.lox:2] in script"),
+            _ => panic!("Expected runtime error"),
+        }
+
+        match vm.interpret("-\"a\";") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "cannot negate Str(\"a\")"),
+            _ => panic!("Expected runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_long_names() {
+        // Every name after the first 256 constants in the script takes a long operand
+        let program = (0..300).map(|i| format!("var v{} = {};", i, i)).collect::<String>() + "
+            v299 = v299 + 1;
+            class Box {
+                total() { return this.v299 + 1; }
+                size { return 2; }
+            }
+            var b = Box();
+            b.v299 = v299;
+            var result = b.total() + b.v299 + b.size;
+        ";
+        for two_phase in [false, true] {
+            let mut vm = VM::with_options(VMOptions { two_phase, ..Default::default() });
+            vm.interpret(&program).unwrap();
+            assert!(matches!(evaluate(&mut vm, "result"), Value::Int(603)));
+        }
+    }
+
+    #[test]
+    fn test_long_locals() {
+        let program = format!(
+            "fun f() {{ {} v299 = v299 + v1; return v299 + v0; }}",
+            (0..300).map(|i| format!("var v{} = {};", i, i)).collect::<String>()
+        );
+        for two_phase in [false, true] {
+            let mut vm = VM::with_options(VMOptions { two_phase, ..Default::default() });
+            vm.interpret(&program).unwrap();
+            assert!(matches!(evaluate(&mut vm, "f()"), Value::Int(300)));
+        }
+    }
+
+    #[test]
+    fn test_two_phase() {
+        assert!(!VMOptions::default().two_phase);
+
+        let program = "
+            var total = 0;
+            for (var i = 0; i < 5; i = i + 1) { if (i == 3) continue; total = total + i; }
+            for (x in [1, 2, 3]) total = total + x;
+            class Point {
+                init(x, y) { this.x = x; this.y = y; }
+                sum() { return this.x + this.y; }
+                size { return 2; }
+            }
+            fun make(x) { return Point(x, x * 2); }
+            var (a, b) = [10, 20];
+            const add = fun (x, y) { return x + y; };
+            var caught;
+            try { throw \"oops\"; } catch (e) { caught = e; }
+            var none;
+            fun countdown(n) { fun down(k) { if (k == 0) return \"done\"; return down(k - 1); } return down(n); }
+        ";
+        let checks = [
+            "total",
+            "make(3).sum() + make(1).size",
+            "add(a, b)",
+            "caught",
+            "none?.x.y ?? \"empty\"",
+            "match (total) { 13 => \"yes\", _ => \"no\" }",
+            "countdown(100)",
+            "[a, b, total][2] - b",
+        ];
+
+        let mut single = VM::default();
+        let mut two_phase = VM::with_options(VMOptions { two_phase: true, ..Default::default() });
+        single.interpret(program).unwrap();
+        two_phase.interpret(program).unwrap();
+        for source in checks {
+            let expected = evaluate(&mut single, source);
+            let actual = evaluate(&mut two_phase, source);
+            match (single.heap.as_str(&expected), two_phase.heap.as_str(&actual)) {
+                (Some(expected), Some(actual)) => assert_eq!(actual, expected, "{}", source),
+                _ => assert_eq!(actual, expected, "{}", source),
+            }
+        }
+
+        // Scoping errors come from the lowering
+        match two_phase.interpret("return 1;") {
+            Err(InterpretError::CompileError(errors)) => assert_eq!(errors[0].message, "Can't return from top-level code."),
+            _ => panic!("Expected compile error"),
+        }
+
+        // Anonymous functions are named the same way by both compilers
+        for vm in [&mut single, &mut two_phase] {
+            match vm.interpret("var f = fun (x) { return x + nil; };\nf(1);") {
+                Err(InterpretError::RuntimeError(e)) => assert_eq!(e.trace[0].to_string(), "[line 1] in lambda()"),
+                _ => panic!("Expected runtime error"),
+            }
         }
-        Ok(InterpretResult)
     }
 }